@@ -0,0 +1,202 @@
+// Keyboard configuration exported to and imported from a file.
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::transport::{Side, Size, Transport};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    // None to read the side from the handedness pin.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub side: Option<Side>,
+    pub debounce: u8,
+    // [tx][rx] of the half connected to the host.
+    pub thresholds: Vec<Vec<u16>>,
+    // [layer][row][col] QMK keycodes.
+    pub keymap: Vec<Vec<Vec<u16>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Ok(Format::Toml),
+            Some("json") => Ok(Format::Json),
+            _ => bail!("{}: use .toml or .json", path.display()),
+        }
+    }
+}
+
+impl Config {
+    pub fn read(device: &mut dyn Transport) -> Result<Self> {
+        let size = device.size()?;
+        let mut keymap = vec![vec![vec![0; size.cols]; size.rows]; size.layers];
+        for (l, layer) in keymap.iter_mut().enumerate() {
+            for (r, row) in layer.iter_mut().enumerate() {
+                for (c, keycode) in row.iter_mut().enumerate() {
+                    *keycode = device.keycode(l, r, c)?;
+                }
+            }
+        }
+
+        Ok(Self {
+            side: device.side()?,
+            debounce: device.debounce()?,
+            thresholds: device.thresholds()?,
+            keymap,
+        })
+    }
+
+    // Write values which differ from the keyboard. Returns the number of changed values.
+    pub fn write(&self, device: &mut dyn Transport) -> Result<usize> {
+        self.check_size(&device.size()?)?;
+        let current = Self::read(device)?;
+        let mut changes = 0;
+
+        for (l, layer) in self.keymap.iter().enumerate() {
+            for (r, row) in layer.iter().enumerate() {
+                for (c, keycode) in row.iter().enumerate() {
+                    if *keycode != current.keymap[l][r][c] {
+                        device.set_keycode(l, r, c, *keycode)?;
+                        changes += 1;
+                    }
+                }
+            }
+        }
+        for (tx, row) in self.thresholds.iter().enumerate() {
+            for (rx, threshold) in row.iter().enumerate() {
+                if *threshold != current.thresholds[tx][rx] {
+                    device.set_threshold(tx, rx, *threshold)?;
+                    changes += 1;
+                }
+            }
+        }
+        if self.debounce != current.debounce {
+            device.set_debounce(self.debounce)?;
+            changes += 1;
+        }
+        if self.side != current.side {
+            device.set_side(self.side)?;
+            changes += 1;
+        }
+        Ok(changes)
+    }
+
+    fn check_size(&self, size: &Size) -> Result<()> {
+        ensure!(
+            self.keymap.len() == size.layers
+                && self.keymap.iter().flatten().count() == size.layers * size.rows
+                && self
+                    .keymap
+                    .iter()
+                    .flatten()
+                    .all(|row| row.len() == size.cols),
+            "keymap must be {} layers of {}x{} keys",
+            size.layers,
+            size.rows,
+            size.cols
+        );
+        ensure!(
+            self.thresholds.len() == size.tx
+                && self.thresholds.iter().all(|row| row.len() == size.rx),
+            "thresholds must be {}x{}",
+            size.tx,
+            size.rx
+        );
+        ensure!(self.debounce > 0, "debounce must be 1 or more");
+        Ok(())
+    }
+
+    pub fn to_text(&self, format: Format) -> Result<String> {
+        Ok(match format {
+            Format::Toml => toml::to_string(self)?,
+            Format::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    pub fn from_text(text: &str, format: Format) -> Result<Self> {
+        Ok(match format {
+            Format::Toml => toml::from_str(text)?,
+            Format::Json => serde_json::from_str(text)?,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| path.display().to_string())?;
+        Self::from_text(&text, Format::from_path(path)?)
+            .with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = self.to_text(Format::from_path(path)?)?;
+        fs::write(path, text).with_context(|| path.display().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::Console;
+    use crate::hid::RawHid;
+    use crate::mock::MockDevice;
+
+    fn edited(device: &mut dyn Transport) -> Config {
+        let mut config = Config::read(device).unwrap();
+        config.keymap[0][1][2] = 0x0029;
+        config.keymap[1][4][11] = 0x5221;
+        config.thresholds[2][3] = 1700;
+        config.debounce = 3;
+        config.side = Some(Side::Left);
+        config
+    }
+
+    #[test]
+    fn import_export() {
+        let devices: [Box<dyn Transport>; 2] = [
+            Box::new(Console::new(MockDevice::new())),
+            Box::new(RawHid::new(MockDevice::new())),
+        ];
+        for mut device in devices {
+            let config = edited(device.as_mut());
+            assert_eq!(config.write(device.as_mut()).unwrap(), 5);
+            assert_eq!(Config::read(device.as_mut()).unwrap(), config);
+            assert_eq!(config.write(device.as_mut()).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn file_formats() {
+        let config = edited(&mut Console::new(MockDevice::new()));
+        for format in [Format::Toml, Format::Json] {
+            let text = config.to_text(format).unwrap();
+            assert_eq!(Config::from_text(&text, format).unwrap(), config);
+        }
+
+        let toml = config.to_text(Format::Toml).unwrap();
+        assert!(toml.starts_with("side = \"left\"\ndebounce = 3\n"));
+        assert_eq!(
+            Format::from_path(Path::new("corne.json")).unwrap(),
+            Format::Json
+        );
+        assert!(Format::from_path(Path::new("corne.yaml")).is_err());
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let mut device = Console::new(MockDevice::new());
+        let mut config = Config::read(&mut device).unwrap();
+        config.keymap.pop();
+        assert!(config.write(&mut device).is_err());
+
+        let mut config = Config::read(&mut device).unwrap();
+        config.thresholds[0].push(2000);
+        assert!(config.write(&mut device).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+// Transport over the CDC-ACM console. Commands are listed in eck_rs::console::HELP.
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::transport::{HalfSnapshot, Side, Size, Transport};
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct Console<P> {
+    port: BufReader<P>,
+}
+
+pub fn open(path: &str) -> Result<Console<Box<dyn serialport::SerialPort>>> {
+    let mut port = serialport::new(path, 115_200)
+        .timeout(TIMEOUT)
+        .open()
+        .with_context(|| format!("failed to open {}", path))?;
+
+    // Stop a running heatmap and drop its output.
+    port.write_all(b"\n")?;
+    std::thread::sleep(Duration::from_millis(200));
+    port.clear(serialport::ClearBuffer::Input)?;
+    Ok(Console::new(port))
+}
+
+impl<P: Read + Write> Console<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port: BufReader::new(port),
+        }
+    }
+
+    // Response lines before "ok".
+    fn request(&mut self, request: &str) -> Result<Vec<String>> {
+        let port = self.port.get_mut();
+        port.write_all(request.as_bytes())?;
+        port.write_all(b"\n")?;
+        port.flush()?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.port.read_line(&mut line)? == 0 {
+                bail!("console closed");
+            }
+
+            let line = line.trim_end();
+            if line == "ok" {
+                return Ok(lines);
+            }
+            if let Some(reason) = line.strip_prefix("error: ") {
+                bail!("'{}' failed: {}", request, reason);
+            }
+            lines.push(line.to_string());
+        }
+    }
+
+    fn value<T: FromStr>(&mut self, request: &str) -> Result<T> {
+        let lines = self.request(request)?;
+        match lines.as_slice() {
+            [line] => line
+                .parse()
+                .map_err(|_| anyhow!("'{}': invalid response {}", request, line)),
+            _ => bail!("'{}': expected a line, got {}", request, lines.len()),
+        }
+    }
+}
+
+impl<P: Read + Write> Transport for Console<P> {
+    fn size(&mut self) -> Result<Size> {
+        let mut size = Size {
+            layers: 0,
+            rows: 0,
+            cols: 0,
+            tx: 0,
+            rx: 0,
+        };
+        for line in self.request("size")? {
+            let (name, value) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("size: invalid response {}", line))?;
+            let field = match name {
+                "layers" => &mut size.layers,
+                "rows" => &mut size.rows,
+                "cols" => &mut size.cols,
+                "tx" => &mut size.tx,
+                "rx" => &mut size.rx,
+                _ => continue,
+            };
+            *field = value.parse()?;
+        }
+        Ok(size)
+    }
+
+    fn keycode(&mut self, layer: usize, row: usize, col: usize) -> Result<u16> {
+        self.value(&format!("keycode {} {} {}", layer, row, col))
+    }
+
+    fn set_keycode(&mut self, layer: usize, row: usize, col: usize, keycode: u16) -> Result<()> {
+        self.request(&format!("keycode {} {} {} {}", layer, row, col, keycode))?;
+        Ok(())
+    }
+
+    fn thresholds(&mut self) -> Result<Vec<Vec<u16>>> {
+        self.request("thresholds")?
+            .iter()
+            .map(|line| {
+                line.split_ascii_whitespace()
+                    .map(|value| Ok(value.parse()?))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn set_threshold(&mut self, tx: usize, rx: usize, threshold: u16) -> Result<()> {
+        self.request(&format!("threshold {} {} {}", tx, rx, threshold))?;
+        Ok(())
+    }
+
+    fn debounce(&mut self) -> Result<u8> {
+        self.value("debounce")
+    }
+
+    fn set_debounce(&mut self, debounce: u8) -> Result<()> {
+        self.request(&format!("debounce {}", debounce))?;
+        Ok(())
+    }
+
+    fn side(&mut self) -> Result<Option<Side>> {
+        match self.value::<String>("side")?.as_str() {
+            "left" => Ok(Some(Side::Left)),
+            "right" => Ok(Some(Side::Right)),
+            "pin" => Ok(None),
+            side => bail!("side: invalid response {}", side),
+        }
+    }
+
+    fn set_side(&mut self, side: Option<Side>) -> Result<()> {
+        let arg = match side {
+            Some(Side::Left) => "left",
+            Some(Side::Right) => "right",
+            None => "clear",
+        };
+        self.request(&format!("side {}", arg))?;
+        Ok(())
+    }
+
+    // Lines of "<side> values|thresholds <tx> <rx values>".
+    fn snapshot(&mut self) -> Result<Vec<HalfSnapshot>> {
+        let mut halves: Vec<HalfSnapshot> = Vec::new();
+        for line in self.request("snapshot")? {
+            let invalid = || anyhow!("snapshot: invalid response {}", line);
+            let mut words = line.split_ascii_whitespace();
+            let (Some(side), Some(name), Some(tx)) = (words.next(), words.next(), words.next())
+            else {
+                return Err(invalid());
+            };
+            let side = match side {
+                "left" => Side::Left,
+                "right" => Side::Right,
+                _ => return Err(invalid()),
+            };
+            let row = words
+                .map(|v| Ok(v.parse()?))
+                .collect::<Result<Vec<u16>>>()?;
+
+            if halves.last().map(|h| h.side) != Some(side) {
+                halves.push(HalfSnapshot {
+                    side,
+                    values: Vec::new(),
+                    thresholds: Vec::new(),
+                });
+            }
+            let half = halves.last_mut().unwrap();
+            let matrix = match name {
+                "values" => &mut half.values,
+                "thresholds" => &mut half.thresholds,
+                _ => return Err(invalid()),
+            };
+            if tx.parse::<usize>()? != matrix.len() {
+                return Err(invalid());
+            }
+            matrix.push(row);
+        }
+        Ok(halves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    #[test]
+    fn console_requests() {
+        let mut console = Console::new(MockDevice::new());
+        assert_eq!(console.size().unwrap(), MockDevice::SIZE);
+        assert_eq!(console.thresholds().unwrap()[3], [2000; 7]);
+
+        console.set_keycode(1, 2, 3, 0x0004).unwrap();
+        assert_eq!(console.keycode(1, 2, 3).unwrap(), 0x0004);
+        console.set_side(Some(Side::Right)).unwrap();
+        assert_eq!(console.side().unwrap(), Some(Side::Right));
+
+        let err = console.set_threshold(4, 0, 1000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'threshold 4 0 1000' failed: key out of range"
+        );
+        // Still in sync after an error.
+        assert_eq!(console.debounce().unwrap(), 5);
+    }
+
+    #[test]
+    fn console_snapshot() {
+        let mut device = MockDevice::new();
+        device.values[1][2] = 2500;
+        device.remote_values = Some(vec![vec![300; 7]; 4]);
+        let mut console = Console::new(device);
+
+        let halves = console.snapshot().unwrap();
+        assert_eq!(halves.len(), 2);
+        assert_eq!(halves[0].side, Side::Left);
+        assert_eq!(halves[0].values[1][2], 2500);
+        assert_eq!(halves[0].thresholds[3], [2000; 7]);
+        assert_eq!(halves[1].side, Side::Right);
+        assert_eq!(halves[1].values[3], [300; 7]);
+    }
+}
@@ -0,0 +1,209 @@
+// Live view of raw values of both halves. A key is drawn as a bar with the threshold marker
+// and the held peak, above its idle noise.
+use anyhow::Result;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::{cursor, queue, terminal};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::stats::{KeyStats, Stats};
+use crate::trace::{self, Frame};
+use crate::transport::{Side, Transport};
+
+// Same as the slave relay period, stm32g0 config::SPLIT_ANALOG_PERIOD.
+const FRAME_PERIOD: Duration = Duration::from_millis(100);
+const ADC_MAX: u16 = 4095;
+const BAR_WIDTH: usize = 9;
+// Value, space and bar.
+const CELL_WIDTH: usize = 5 + BAR_WIDTH;
+
+pub fn live(device: &mut dyn Transport, record: Option<&Path>) -> Result<()> {
+    let mut recorder = record.map(trace::create).transpose()?;
+    let start = Instant::now();
+    run("live", |_| {
+        let frame = Frame {
+            ms: start.elapsed().as_millis() as u64,
+            halves: device.snapshot()?,
+        };
+        if let Some(recorder) = &mut recorder {
+            recorder.record(&frame)?;
+        }
+        Ok(vec![frame])
+    })
+}
+
+// Plays at the recorded pace, pausing stops the clock.
+pub fn replay(path: &Path) -> Result<()> {
+    let frames = trace::load(path)?;
+    let mut clock = 0;
+    let mut next = 0;
+    run(&path.display().to_string(), |step| {
+        clock += step.as_millis() as u64;
+        let end = next + frames[next..].iter().take_while(|f| f.ms <= clock).count();
+        let played = frames[next..end].to_vec();
+        next = end;
+        Ok(played)
+    })
+}
+
+// Restores the terminal on drop, also on errors.
+struct Screen;
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        let screen = Screen;
+        queue!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = queue!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = io::stdout().flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// Frames are taken every period, as many as have passed in the step.
+fn run(title: &str, mut frames: impl FnMut(Duration) -> Result<Vec<Frame>>) -> Result<()> {
+    let _screen = Screen::enter()?;
+    let mut stats = Stats::default();
+    let mut paused = false;
+
+    loop {
+        if !paused {
+            for frame in frames(FRAME_PERIOD)? {
+                stats.update(&frame.halves, Duration::from_millis(frame.ms));
+            }
+        }
+        draw(&mut io::stdout(), title, &stats, paused)?;
+
+        if !event::poll(FRAME_PERIOD)? {
+            continue;
+        }
+        match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('r') => stats.reset(),
+                KeyCode::Char(' ') => paused = !paused,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
+
+fn draw(out: &mut impl Write, title: &str, stats: &Stats, paused: bool) -> Result<()> {
+    queue!(out, cursor::MoveTo(0, 0))?;
+    let state = if paused { "  [paused]" } else { "" };
+    line(
+        out,
+        &format!("{}  {:.1}s{}", title, stats.at.as_secs_f64(), state),
+    )?;
+
+    for half in &stats.halves {
+        let side = match half.side {
+            Side::Left => "left",
+            Side::Right => "right",
+        };
+        let age = stats.at.saturating_sub(half.at);
+        let stale = match age > FRAME_PERIOD * 5 {
+            true => format!("  no values for {:.1}s", age.as_secs_f64()),
+            false => String::new(),
+        };
+        line(out, "")?;
+        line(out, &format!("{}{}", side, stale))?;
+
+        for keys in &half.keys {
+            for key in keys {
+                // Grayscale background of 256 color palette, like the console heatmap.
+                let shade = 232 + key.value.min(ADC_MAX) as u32 * 23 / ADC_MAX as u32;
+                let color = if key.pressed() {
+                    Color::Red
+                } else {
+                    Color::Green
+                };
+                queue!(
+                    out,
+                    SetBackgroundColor(Color::AnsiValue(shade as u8)),
+                    Print(format!("{:>4}", key.value)),
+                    ResetColor,
+                    Print(" "),
+                    SetForegroundColor(color),
+                    Print(bar(key)),
+                    ResetColor,
+                    Print(" "),
+                )?;
+            }
+            line(out, "")?;
+            for key in keys {
+                queue!(out, Print(format!("{:<w$} ", noise(key), w = CELL_WIDTH)))?;
+            }
+            line(out, "")?;
+        }
+    }
+
+    line(out, "")?;
+    line(
+        out,
+        "bar: value, | threshold, ^ peak held 2s. below: peak, idle mean ± std dev",
+    )?;
+    line(out, "q quit  r reset stats  space pause")?;
+    queue!(out, terminal::Clear(terminal::ClearType::FromCursorDown))?;
+    out.flush()?;
+    Ok(())
+}
+
+// Raw mode needs CR.
+fn line(out: &mut impl Write, text: &str) -> Result<()> {
+    queue!(
+        out,
+        Print(text),
+        terminal::Clear(terminal::ClearType::UntilNewLine),
+        Print("\r\n")
+    )?;
+    Ok(())
+}
+
+fn bar(key: &KeyStats) -> String {
+    let position = |v: u16| v.min(ADC_MAX) as usize * (BAR_WIDTH - 1) / ADC_MAX as usize;
+    let (level, threshold, peak) = (
+        position(key.value),
+        position(key.threshold),
+        position(key.peak),
+    );
+    (0..BAR_WIDTH)
+        .map(|i| match i {
+            _ if i == threshold => '|',
+            _ if i == peak && peak > level => '^',
+            _ if i <= level && key.value > 0 => '#',
+            _ => '.',
+        })
+        .collect()
+}
+
+fn noise(key: &KeyStats) -> String {
+    match (key.idle_mean(), key.idle_std_dev()) {
+        (Some(mean), Some(std_dev)) => format!("{:>4} {:>4.0}±{:<4.1}", key.peak, mean, std_dev),
+        _ => format!("{:>4}", key.peak),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bar_markers() {
+        let mut key = KeyStats::default();
+        key.update(4095, 2048, Duration::ZERO);
+        key.update(1024, 2048, Duration::from_millis(100));
+        assert_eq!(bar(&key), "###.|...^");
+        key.update(0, 2048, Duration::from_secs(3));
+        assert_eq!(bar(&key), "....|....");
+    }
+}
@@ -0,0 +1,247 @@
+// Transport over the VIA raw HID interface. Ids are in eck_rs::via.
+use anyhow::{bail, Result};
+use eck_rs::via::*;
+
+use crate::transport::{HalfSnapshot, Side, Size, Transport};
+
+// Same as stm32g0 config::USB_VID, USB_PID.
+#[cfg(feature = "hid")]
+const USB_VID: u16 = 0x16c0;
+#[cfg(feature = "hid")]
+const USB_PID: u16 = 0x27db;
+#[cfg(feature = "hid")]
+const TIMEOUT_MS: i32 = 1000;
+
+pub type Report = [u8; REPORT_SIZE];
+
+// Sends a request report and receives the response.
+pub trait ReportDevice {
+    fn send(&mut self, report: &Report) -> Result<()>;
+    fn receive(&mut self) -> Result<Report>;
+}
+
+pub struct RawHid<D> {
+    device: D,
+}
+
+#[cfg(feature = "hid")]
+pub fn open() -> Result<RawHid<hidapi::HidDevice>> {
+    let api = hidapi::HidApi::new()?;
+    let Some(info) = api.device_list().find(|d| {
+        d.vendor_id() == USB_VID
+            && d.product_id() == USB_PID
+            && d.usage_page() == USAGE_PAGE
+            && d.usage() == USAGE
+    }) else {
+        bail!("keyboard not found");
+    };
+    Ok(RawHid::new(info.open_device(&api)?))
+}
+
+#[cfg(feature = "hid")]
+impl ReportDevice for hidapi::HidDevice {
+    fn send(&mut self, report: &Report) -> Result<()> {
+        // Report id 0 first.
+        let mut buf = [0u8; REPORT_SIZE + 1];
+        buf[1..].copy_from_slice(report);
+        self.write(&buf)?;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Report> {
+        let mut report = [0u8; REPORT_SIZE];
+        if self.read_timeout(&mut report, TIMEOUT_MS)? != REPORT_SIZE {
+            bail!("no response");
+        }
+        Ok(report)
+    }
+}
+
+impl<D: ReportDevice> RawHid<D> {
+    pub fn new(device: D) -> Self {
+        Self { device }
+    }
+
+    fn request(&mut self, request: &[u8]) -> Result<Report> {
+        let mut report = [0u8; REPORT_SIZE];
+        report[..request.len()].copy_from_slice(request);
+        self.device.send(&report)?;
+
+        let response = self.device.receive()?;
+        if response[0] == UNHANDLED {
+            bail!("request {:02x?} failed", request);
+        }
+        if response[0] != request[0] {
+            bail!("unexpected response {:02x?}", &response[..4]);
+        }
+        Ok(response)
+    }
+
+    // Data of a custom value.
+    fn get_value(&mut self, id: u8, args: &[u8]) -> Result<Report> {
+        let mut request = vec![CUSTOM_GET_VALUE, CUSTOM_CHANNEL, id];
+        request.extend_from_slice(args);
+        let response = self.request(&request)?;
+
+        let mut data = [0u8; REPORT_SIZE];
+        data[..REPORT_SIZE - CUSTOM_DATA_START].copy_from_slice(&response[CUSTOM_DATA_START..]);
+        Ok(data)
+    }
+
+    fn set_value(&mut self, id: u8, data: &[u8]) -> Result<()> {
+        let mut request = vec![CUSTOM_SET_VALUE, CUSTOM_CHANNEL, id];
+        request.extend_from_slice(data);
+        self.request(&request)?;
+        Ok(())
+    }
+}
+
+impl<D: ReportDevice> Transport for RawHid<D> {
+    fn size(&mut self) -> Result<Size> {
+        let data = self.get_value(MATRIX_SIZE, &[])?;
+        Ok(Size {
+            layers: data[0] as usize,
+            rows: data[1] as usize,
+            cols: data[2] as usize,
+            tx: data[3] as usize,
+            rx: data[4] as usize,
+        })
+    }
+
+    fn keycode(&mut self, layer: usize, row: usize, col: usize) -> Result<u16> {
+        let response = self.request(&[
+            DYNAMIC_KEYMAP_GET_KEYCODE,
+            layer as u8,
+            row as u8,
+            col as u8,
+        ])?;
+        Ok(u16::from_be_bytes([response[4], response[5]]))
+    }
+
+    fn set_keycode(&mut self, layer: usize, row: usize, col: usize, keycode: u16) -> Result<()> {
+        let [hi, lo] = keycode.to_be_bytes();
+        self.request(&[
+            DYNAMIC_KEYMAP_SET_KEYCODE,
+            layer as u8,
+            row as u8,
+            col as u8,
+            hi,
+            lo,
+        ])?;
+        Ok(())
+    }
+
+    fn thresholds(&mut self) -> Result<Vec<Vec<u16>>> {
+        let size = self.size()?;
+        (0..size.tx)
+            .map(|tx| {
+                (0..size.rx)
+                    .map(|rx| {
+                        let data = self.get_value(THRESHOLD, &[tx as u8, rx as u8])?;
+                        Ok(u16::from_be_bytes([data[2], data[3]]))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn set_threshold(&mut self, tx: usize, rx: usize, threshold: u16) -> Result<()> {
+        let [hi, lo] = threshold.to_be_bytes();
+        self.set_value(THRESHOLD, &[tx as u8, rx as u8, hi, lo])
+    }
+
+    fn debounce(&mut self) -> Result<u8> {
+        Ok(self.get_value(DEBOUNCE, &[])?[0])
+    }
+
+    fn set_debounce(&mut self, debounce: u8) -> Result<()> {
+        self.set_value(DEBOUNCE, &[debounce])
+    }
+
+    fn side(&mut self) -> Result<Option<Side>> {
+        match self.get_value(SIDE, &[])?[0] {
+            SIDE_LEFT => Ok(Some(Side::Left)),
+            SIDE_RIGHT => Ok(Some(Side::Right)),
+            SIDE_PIN => Ok(None),
+            side => bail!("invalid side {}", side),
+        }
+    }
+
+    fn set_side(&mut self, side: Option<Side>) -> Result<()> {
+        let side = match side {
+            Some(Side::Left) => SIDE_LEFT,
+            Some(Side::Right) => SIDE_RIGHT,
+            None => SIDE_PIN,
+        };
+        self.set_value(SIDE, &[side])
+    }
+
+    fn snapshot(&mut self) -> Result<Vec<HalfSnapshot>> {
+        let size = self.size()?;
+        let mut halves = Vec::new();
+        for half in [ANALOG_LOCAL, ANALOG_REMOTE] {
+            let mut snapshot: Option<HalfSnapshot> = None;
+            for tx in 0..size.tx {
+                let data = self.get_value(ANALOG, &[half, tx as u8])?;
+                let side = match data[0] {
+                    SIDE_LEFT => Side::Left,
+                    SIDE_RIGHT => Side::Right,
+                    ANALOG_NONE => break,
+                    side => bail!("invalid side {}", side),
+                };
+                let snapshot = snapshot.get_or_insert_with(|| HalfSnapshot {
+                    side,
+                    values: Vec::new(),
+                    thresholds: Vec::new(),
+                });
+                let keys = data[1..].chunks_exact(4).take(size.rx);
+                snapshot.values.push(
+                    keys.clone()
+                        .map(|k| u16::from_be_bytes([k[0], k[1]]))
+                        .collect(),
+                );
+                snapshot
+                    .thresholds
+                    .push(keys.map(|k| u16::from_be_bytes([k[2], k[3]])).collect());
+            }
+            halves.extend(snapshot);
+        }
+        Ok(halves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockDevice;
+
+    #[test]
+    fn hid_requests() {
+        let mut hid = RawHid::new(MockDevice::new());
+        assert_eq!(hid.size().unwrap(), MockDevice::SIZE);
+
+        hid.set_threshold(3, 6, 1500).unwrap();
+        assert_eq!(hid.thresholds().unwrap()[3][6], 1500);
+        hid.set_keycode(1, 4, 11, 0x5220).unwrap();
+        assert_eq!(hid.keycode(1, 4, 11).unwrap(), 0x5220);
+        assert_eq!(hid.side().unwrap(), None);
+
+        assert!(hid.set_debounce(0).is_err());
+        assert!(hid.set_threshold(0, 7, 1000).is_err());
+    }
+
+    #[test]
+    fn hid_snapshot() {
+        let mut device = MockDevice::new();
+        device.side = Some(Side::Right);
+        device.values[3][6] = 4095;
+        let mut hid = RawHid::new(device);
+
+        // The other half has not sent values.
+        let halves = hid.snapshot().unwrap();
+        assert_eq!(halves.len(), 1);
+        assert_eq!(halves[0].side, Side::Right);
+        assert_eq!(halves[0].values[3][6], 4095);
+        assert_eq!(halves[0].thresholds[0], [2000; 7]);
+    }
+}
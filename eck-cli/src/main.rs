@@ -0,0 +1,180 @@
+// Configure a keyboard running the stm32g0 firmware from the host.
+use anyhow::{bail, ensure, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+mod config;
+mod console;
+mod heatmap;
+// Only the mock uses it without the hid feature.
+#[cfg_attr(not(feature = "hid"), allow(dead_code))]
+mod hid;
+#[cfg(test)]
+mod mock;
+mod stats;
+mod trace;
+mod transport;
+
+use config::Config;
+use transport::{Side, Transport};
+
+#[derive(Parser)]
+#[command(about = "Configure a Corne-eec keyboard over USB")]
+struct Cli {
+    /// Serial port of the CDC-ACM console, e.g. /dev/ttyACM0
+    #[arg(long, conflicts_with = "hid")]
+    port: Option<String>,
+    /// Use the VIA raw HID interface
+    #[arg(long)]
+    hid: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show keymap and matrix size
+    Info,
+    /// Show or set the keycode of a key
+    Keycode {
+        layer: usize,
+        row: usize,
+        col: usize,
+        #[arg(value_parser = parse_keycode)]
+        keycode: Option<u16>,
+    },
+    /// Show thresholds, or set the threshold of a key
+    Threshold {
+        #[arg(requires = "rx")]
+        tx: Option<usize>,
+        #[arg(requires = "value")]
+        rx: Option<usize>,
+        value: Option<u16>,
+    },
+    /// Show or set the debounce count
+    Debounce { value: Option<u8> },
+    /// Show or store the side of the connected half
+    Side { side: Option<SideArg> },
+    /// Save the configuration to a .toml or .json file
+    Export { file: PathBuf },
+    /// Write changed values of a .toml or .json file to the keyboard
+    Import { file: PathBuf },
+    /// Show raw values of both halves live
+    Heatmap {
+        /// Also save the values to a trace file
+        #[arg(long)]
+        record: Option<PathBuf>,
+        /// Play a trace file instead of reading the keyboard
+        #[arg(long, conflicts_with = "record")]
+        replay: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SideArg {
+    Left,
+    Right,
+    /// Clear the stored side and read the handedness pin
+    Pin,
+}
+
+// Decimal or 0x prefixed hex.
+fn parse_keycode(arg: &str) -> Result<u16, String> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => arg.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn open(cli: &Cli) -> Result<Box<dyn Transport>> {
+    if let Some(port) = &cli.port {
+        return Ok(Box::new(console::open(port)?));
+    }
+    if !cli.hid {
+        bail!("give --port or --hid");
+    }
+
+    #[cfg(feature = "hid")]
+    return Ok(Box::new(hid::open()?));
+    #[cfg(not(feature = "hid"))]
+    bail!("built without the hid feature");
+}
+
+fn side_name(side: Option<Side>) -> &'static str {
+    match side {
+        Some(Side::Left) => "left",
+        Some(Side::Right) => "right",
+        None => "pin",
+    }
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Command::Heatmap {
+        replay: Some(file), ..
+    } = &cli.command
+    {
+        return heatmap::replay(file);
+    }
+    let mut device = open(&cli)?;
+    let size = device.size()?;
+
+    match cli.command {
+        Command::Info => {
+            println!("layers {}", size.layers);
+            println!("keymap {}x{}", size.rows, size.cols);
+            println!("matrix {}x{}", size.tx, size.rx);
+        }
+        Command::Keycode {
+            layer,
+            row,
+            col,
+            keycode,
+        } => {
+            ensure!(
+                layer < size.layers && row < size.rows && col < size.cols,
+                "key out of range"
+            );
+            match keycode {
+                Some(keycode) => device.set_keycode(layer, row, col, keycode)?,
+                None => println!("{:#06x}", device.keycode(layer, row, col)?),
+            }
+        }
+        Command::Threshold {
+            tx: Some(tx),
+            rx: Some(rx),
+            value: Some(value),
+        } => {
+            ensure!(tx < size.tx && rx < size.rx, "key out of range");
+            device.set_threshold(tx, rx, value)?;
+        }
+        Command::Threshold { .. } => {
+            for row in device.thresholds()? {
+                let row: Vec<_> = row.iter().map(|v| format!("{:>5}", v)).collect();
+                println!("{}", row.concat());
+            }
+        }
+        Command::Debounce { value: Some(value) } => {
+            ensure!(value > 0, "debounce must be 1 or more");
+            device.set_debounce(value)?;
+        }
+        Command::Debounce { value: None } => println!("{}", device.debounce()?),
+        Command::Side { side: Some(side) } => device.set_side(match side {
+            SideArg::Left => Some(Side::Left),
+            SideArg::Right => Some(Side::Right),
+            SideArg::Pin => None,
+        })?,
+        Command::Side { side: None } => println!("{}", side_name(device.side()?)),
+        Command::Export { file } => {
+            Config::read(device.as_mut())?.save(&file)?;
+            println!("Saved to {}.", file.display());
+        }
+        Command::Import { file } => {
+            let changes = Config::load(&file)?.write(device.as_mut())?;
+            println!("{} values changed.", changes);
+        }
+        Command::Heatmap { record, .. } => heatmap::live(device.as_mut(), record.as_deref())?,
+    }
+    Ok(())
+}
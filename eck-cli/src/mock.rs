@@ -0,0 +1,294 @@
+// Keyboard in memory. Answers console and VIA requests like the firmware.
+use anyhow::{bail, Result};
+use eck_rs::console::{Command, LineBuffer, SideArg};
+use eck_rs::via::*;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+
+use crate::hid::{Report, ReportDevice};
+use crate::transport::{Side, Size};
+
+pub struct MockDevice {
+    pub keymap: Vec<Vec<Vec<u16>>>,
+    pub thresholds: Vec<Vec<u16>>,
+    pub debounce: u8,
+    pub side: Option<Side>,
+    pub values: Vec<Vec<u16>>,
+    // Values of the other half. None if it does not relay them.
+    pub remote_values: Option<Vec<Vec<u16>>>,
+    lines: LineBuffer,
+    output: VecDeque<u8>,
+    response: Option<Report>,
+}
+
+impl MockDevice {
+    pub const SIZE: Size = Size {
+        layers: 2,
+        rows: 5,
+        cols: 12,
+        tx: 4,
+        rx: 7,
+    };
+
+    pub fn new() -> Self {
+        let s = Self::SIZE;
+        Self {
+            keymap: vec![vec![vec![0; s.cols]; s.rows]; s.layers],
+            thresholds: vec![vec![2000; s.rx]; s.tx],
+            debounce: 5,
+            side: None,
+            values: vec![vec![100; s.rx]; s.tx],
+            remote_values: None,
+            lines: LineBuffer::new(),
+            output: VecDeque::new(),
+            response: None,
+        }
+    }
+
+    fn key(&mut self, layer: usize, row: usize, col: usize) -> Option<&mut u16> {
+        self.keymap.get_mut(layer)?.get_mut(row)?.get_mut(col)
+    }
+
+    fn threshold(&mut self, tx: usize, rx: usize) -> Option<&mut u16> {
+        self.thresholds.get_mut(tx)?.get_mut(rx)
+    }
+
+    // Side of this half is left if read from the pin.
+    fn halves(&self) -> Vec<(Side, &Vec<Vec<u16>>)> {
+        let (local, remote) = match self.side {
+            Some(Side::Right) => (Side::Right, Side::Left),
+            _ => (Side::Left, Side::Right),
+        };
+        let mut halves = vec![(local, &self.values)];
+        halves.extend(self.remote_values.as_ref().map(|values| (remote, values)));
+        halves
+    }
+
+    fn execute(&mut self, cmd: Command) -> Result<String, &'static str> {
+        let mut out = String::new();
+        match cmd {
+            Command::Thresholds => {
+                for row in &self.thresholds {
+                    for value in row {
+                        write!(out, "{:>5}", value).unwrap();
+                    }
+                    out.push('\n');
+                }
+            }
+            Command::SetThreshold { tx, rx, value } => {
+                *self.threshold(tx, rx).ok_or("key out of range")? = value
+            }
+            Command::Debounce(None) => writeln!(out, "{}", self.debounce).unwrap(),
+            Command::Debounce(Some(0)) => return Err("debounce must be 1 or more"),
+            Command::Debounce(Some(n)) => self.debounce = n,
+            Command::Side(None) => {
+                let side = match self.side {
+                    Some(Side::Left) => "left",
+                    Some(Side::Right) => "right",
+                    None => "pin",
+                };
+                writeln!(out, "{}", side).unwrap();
+            }
+            Command::Side(Some(arg)) => {
+                self.side = match arg {
+                    SideArg::Left => Some(Side::Left),
+                    SideArg::Right => Some(Side::Right),
+                    SideArg::Clear => None,
+                }
+            }
+            Command::Size => {
+                let s = Self::SIZE;
+                for (name, value) in [
+                    ("layers", s.layers),
+                    ("rows", s.rows),
+                    ("cols", s.cols),
+                    ("tx", s.tx),
+                    ("rx", s.rx),
+                ] {
+                    writeln!(out, "{} {}", name, value).unwrap();
+                }
+            }
+            Command::Keycode {
+                layer,
+                row,
+                col,
+                keycode,
+            } => {
+                let key = self.key(layer, row, col).ok_or("key out of range")?;
+                match keycode {
+                    Some(keycode) => *key = keycode,
+                    None => writeln!(out, "{}", key).unwrap(),
+                }
+            }
+            Command::Snapshot => {
+                for (side, values) in self.halves() {
+                    let side = match side {
+                        Side::Left => "left",
+                        Side::Right => "right",
+                    };
+                    for (name, matrix) in [("values", values), ("thresholds", &self.thresholds)] {
+                        for (tx, row) in matrix.iter().enumerate() {
+                            write!(out, "{} {} {}", side, name, tx).unwrap();
+                            for value in row {
+                                write!(out, " {}", value).unwrap();
+                            }
+                            out.push('\n');
+                        }
+                    }
+                }
+            }
+            _ => return Err("not supported by mock"),
+        }
+        Ok(out)
+    }
+
+    fn handle_line(&mut self, line: &str) {
+        let response = match Command::parse(line) {
+            Ok(cmd) => self.execute(cmd).map(|out| out + "ok\n"),
+            Err(e) => Err(e.as_str()),
+        };
+        let text = response.unwrap_or_else(|reason| format!("error: {}\n", reason));
+        // Same line ending as the firmware.
+        self.output
+            .extend(text.replace('\n', "\r\n").as_bytes().iter().copied());
+    }
+
+    fn handle_report(&mut self, report: &mut Report) {
+        let (layer, row, col) = (report[1] as usize, report[2] as usize, report[3] as usize);
+        match report[0] {
+            DYNAMIC_KEYMAP_GET_KEYCODE => {
+                let keycode = self.key(layer, row, col).map_or(0, |k| *k);
+                report[4..6].copy_from_slice(&keycode.to_be_bytes());
+            }
+            DYNAMIC_KEYMAP_SET_KEYCODE => {
+                if let Some(key) = self.key(layer, row, col) {
+                    *key = u16::from_be_bytes([report[4], report[5]]);
+                }
+            }
+            CUSTOM_GET_VALUE | CUSTOM_SET_VALUE if report[1] == CUSTOM_CHANNEL => {
+                if !self.custom_value(report) {
+                    report[0] = UNHANDLED;
+                }
+            }
+            _ => report[0] = UNHANDLED,
+        }
+    }
+
+    fn custom_value(&mut self, report: &mut Report) -> bool {
+        let set = report[0] == CUSTOM_SET_VALUE;
+        let id = report[2];
+        let data = &mut report[CUSTOM_DATA_START..];
+        match id {
+            MATRIX_SIZE if !set => {
+                let s = Self::SIZE;
+                data[..5].copy_from_slice(&[
+                    s.layers as u8,
+                    s.rows as u8,
+                    s.cols as u8,
+                    s.tx as u8,
+                    s.rx as u8,
+                ]);
+                true
+            }
+            THRESHOLD => {
+                let Some(threshold) = self.threshold(data[0] as usize, data[1] as usize) else {
+                    return false;
+                };
+                match set {
+                    true => *threshold = u16::from_be_bytes([data[2], data[3]]),
+                    false => data[2..4].copy_from_slice(&threshold.to_be_bytes()),
+                }
+                true
+            }
+            DEBOUNCE if set => {
+                if data[0] == 0 {
+                    return false;
+                }
+                self.debounce = data[0];
+                true
+            }
+            DEBOUNCE => {
+                data[0] = self.debounce;
+                true
+            }
+            SIDE if set => {
+                self.side = match data[0] {
+                    SIDE_LEFT => Some(Side::Left),
+                    SIDE_RIGHT => Some(Side::Right),
+                    _ => None,
+                };
+                true
+            }
+            SIDE => {
+                data[0] = match self.side {
+                    Some(Side::Left) => SIDE_LEFT,
+                    Some(Side::Right) => SIDE_RIGHT,
+                    None => SIDE_PIN,
+                };
+                true
+            }
+            ANALOG if !set => {
+                let (half, tx) = (data[0] as usize, data[1] as usize);
+                if tx >= Self::SIZE.tx {
+                    return false;
+                }
+                let halves = self.halves();
+                let Some((side, values)) = halves.get(half) else {
+                    data[0] = ANALOG_NONE;
+                    return true;
+                };
+                data[0] = match side {
+                    Side::Left => SIDE_LEFT,
+                    Side::Right => SIDE_RIGHT,
+                };
+                let keys = values[tx].iter().zip(&self.thresholds[tx]);
+                for (chunk, (value, threshold)) in data[1..].chunks_exact_mut(4).zip(keys) {
+                    chunk[..2].copy_from_slice(&value.to_be_bytes());
+                    chunk[2..].copy_from_slice(&threshold.to_be_bytes());
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for byte in buf {
+            if let Some(line) = self.lines.push(*byte) {
+                if !line.is_empty() {
+                    self.handle_line(&line);
+                }
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.output.read(buf)
+    }
+}
+
+impl ReportDevice for MockDevice {
+    fn send(&mut self, report: &Report) -> Result<()> {
+        let mut response = *report;
+        self.handle_report(&mut response);
+        self.response = Some(response);
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<Report> {
+        match self.response.take() {
+            Some(response) => Ok(response),
+            None => bail!("no response"),
+        }
+    }
+}
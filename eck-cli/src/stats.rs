@@ -0,0 +1,179 @@
+// Per-key statistics of raw values shown by the heatmap.
+use std::time::Duration;
+
+use crate::transport::{HalfSnapshot, Side};
+
+// Peak is held this long before it follows the value down.
+pub const PEAK_HOLD: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyStats {
+    pub value: u16,
+    pub threshold: u16,
+    pub peak: u16,
+    peak_at: Duration,
+    // Noise of idle samples, those not over the threshold. Welford's online algorithm.
+    pub idle_samples: u64,
+    pub idle_min: u16,
+    pub idle_max: u16,
+    idle_mean: f64,
+    idle_m2: f64,
+}
+
+impl KeyStats {
+    pub fn update(&mut self, value: u16, threshold: u16, at: Duration) {
+        self.value = value;
+        self.threshold = threshold;
+        if value >= self.peak || at.saturating_sub(self.peak_at) >= PEAK_HOLD {
+            self.peak = value;
+            self.peak_at = at;
+        }
+        if self.pressed() {
+            return;
+        }
+
+        match self.idle_samples {
+            0 => (self.idle_min, self.idle_max) = (value, value),
+            _ => {
+                self.idle_min = self.idle_min.min(value);
+                self.idle_max = self.idle_max.max(value);
+            }
+        }
+        self.idle_samples += 1;
+        let delta = value as f64 - self.idle_mean;
+        self.idle_mean += delta / self.idle_samples as f64;
+        self.idle_m2 += delta * (value as f64 - self.idle_mean);
+    }
+
+    pub fn pressed(&self) -> bool {
+        self.value > self.threshold
+    }
+
+    pub fn idle_mean(&self) -> Option<f64> {
+        (self.idle_samples > 0).then_some(self.idle_mean)
+    }
+
+    // Sample standard deviation.
+    pub fn idle_std_dev(&self) -> Option<f64> {
+        (self.idle_samples > 1).then(|| (self.idle_m2 / (self.idle_samples - 1) as f64).sqrt())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HalfStats {
+    pub side: Side,
+    // Indexed [tx][rx].
+    pub keys: Vec<Vec<KeyStats>>,
+    // Time of the last snapshot of this half.
+    pub at: Duration,
+}
+
+// Halves seen so far, left first. A half missing from later snapshots keeps its stats.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub halves: Vec<HalfStats>,
+    pub at: Duration,
+}
+
+impl Stats {
+    pub fn update(&mut self, snapshots: &[HalfSnapshot], at: Duration) {
+        self.at = at;
+        for snapshot in snapshots {
+            let index = match self.halves.iter().position(|h| h.side == snapshot.side) {
+                Some(index) => index,
+                None => {
+                    self.halves.push(HalfStats {
+                        side: snapshot.side,
+                        keys: Vec::new(),
+                        at,
+                    });
+                    self.halves.sort_by_key(|h| h.side == Side::Right);
+                    self.halves
+                        .iter()
+                        .position(|h| h.side == snapshot.side)
+                        .unwrap()
+                }
+            };
+
+            let half = &mut self.halves[index];
+            half.at = at;
+            half.keys.resize(snapshot.values.len(), Vec::new());
+            for (tx, keys) in half.keys.iter_mut().enumerate() {
+                let values = &snapshot.values[tx];
+                keys.resize(values.len(), KeyStats::default());
+                for (rx, key) in keys.iter_mut().enumerate() {
+                    let threshold = snapshot.thresholds.get(tx).and_then(|t| t.get(rx));
+                    key.update(values[rx], threshold.copied().unwrap_or(u16::MAX), at);
+                }
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.halves.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn peak_hold() {
+        let mut key = KeyStats::default();
+        key.update(3000, 2000, ms(0));
+        key.update(500, 2000, ms(100));
+        assert_eq!(key.peak, 3000);
+        key.update(600, 2000, ms(1900));
+        assert_eq!(key.peak, 3000);
+        key.update(400, 2000, ms(2000));
+        assert_eq!(key.peak, 400);
+        key.update(450, 2000, ms(2100));
+        assert_eq!(key.peak, 450);
+    }
+
+    #[test]
+    fn idle_noise() {
+        let mut key = KeyStats::default();
+        assert_eq!(key.idle_mean(), None);
+        for (i, value) in [100, 104, 3000, 102, 98].into_iter().enumerate() {
+            key.update(value, 2000, ms(i as u64 * 100));
+        }
+        // The press is not noise.
+        assert_eq!(key.idle_samples, 4);
+        assert_eq!((key.idle_min, key.idle_max), (98, 104));
+        assert_eq!(key.idle_mean(), Some(101.0));
+        let std_dev = key.idle_std_dev().unwrap();
+        assert!((std_dev - (20.0f64 / 3.0).sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn halves() {
+        let snapshot = |side, value| HalfSnapshot {
+            side,
+            values: vec![vec![value; 3]; 2],
+            thresholds: vec![vec![2000; 3]; 2],
+        };
+        let mut stats = Stats::default();
+        stats.update(&[snapshot(Side::Right, 100)], ms(0));
+        stats.update(
+            &[snapshot(Side::Right, 2500), snapshot(Side::Left, 200)],
+            ms(100),
+        );
+        assert_eq!(stats.halves[0].side, Side::Left);
+        assert_eq!(stats.halves[1].keys[1][2].value, 2500);
+        assert!(stats.halves[1].keys[1][2].pressed());
+        assert_eq!(stats.halves[1].keys[1][2].idle_samples, 1);
+
+        stats.update(&[snapshot(Side::Right, 100)], ms(200));
+        assert_eq!(stats.halves[0].at, ms(100));
+        assert_eq!(stats.halves[1].at, ms(200));
+
+        stats.reset();
+        assert!(stats.halves.is_empty());
+    }
+}
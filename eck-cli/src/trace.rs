@@ -0,0 +1,84 @@
+// Snapshots recorded for the heatmap. One JSON frame per line.
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::transport::HalfSnapshot;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    // Since the start of recording.
+    pub ms: u64,
+    pub halves: Vec<HalfSnapshot>,
+}
+
+pub struct Recorder<W> {
+    out: W,
+}
+
+pub fn create(path: &Path) -> Result<Recorder<BufWriter<File>>> {
+    let file = File::create(path).with_context(|| path.display().to_string())?;
+    Ok(Recorder::new(BufWriter::new(file)))
+}
+
+pub fn load(path: &Path) -> Result<Vec<Frame>> {
+    let file = File::open(path).with_context(|| path.display().to_string())?;
+    read(BufReader::new(file)).with_context(|| format!("failed to read {}", path.display()))
+}
+
+pub fn read(input: impl BufRead) -> Result<Vec<Frame>> {
+    let mut frames = Vec::new();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        frames.push(serde_json::from_str(&line).with_context(|| format!("line {}", i + 1))?);
+    }
+    Ok(frames)
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    // Flushed so that an interrupted recording is still usable.
+    pub fn record(&mut self, frame: &Frame) -> Result<()> {
+        serde_json::to_writer(&mut self.out, frame)?;
+        self.out.write_all(b"\n")?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::Side;
+
+    #[test]
+    fn record_and_read() {
+        let frames = [0, 100].map(|ms| Frame {
+            ms,
+            halves: vec![HalfSnapshot {
+                side: Side::Left,
+                values: vec![vec![ms as u16; 7]; 4],
+                thresholds: vec![vec![2000; 7]; 4],
+            }],
+        });
+        let mut recorder = Recorder::new(Vec::new());
+        for frame in &frames {
+            recorder.record(frame).unwrap();
+        }
+
+        let text = String::from_utf8(recorder.out).unwrap();
+        assert!(text.starts_with("{\"ms\":0,\"halves\":[{\"side\":\"left\","));
+        assert_eq!(read(text.as_bytes()).unwrap(), frames);
+
+        let err = read(format!("{}{{\"ms\":", text).as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "line 3");
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Keymap size and matrix size of a half.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Size {
+    pub layers: usize,
+    pub rows: usize,
+    pub cols: usize,
+    pub tx: usize,
+    pub rx: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// Raw values and thresholds of a half, indexed [tx][rx].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HalfSnapshot {
+    pub side: Side,
+    pub values: Vec<Vec<u16>>,
+    pub thresholds: Vec<Vec<u16>>,
+}
+
+// Configuration access to a keyboard. Thresholds, debounce and side are of the master half,
+// the one connected to the host. Setters apply right away and are saved by the keyboard.
+pub trait Transport {
+    fn size(&mut self) -> Result<Size>;
+    fn keycode(&mut self, layer: usize, row: usize, col: usize) -> Result<u16>;
+    fn set_keycode(&mut self, layer: usize, row: usize, col: usize, keycode: u16) -> Result<()>;
+    // Indexed [tx][rx].
+    fn thresholds(&mut self) -> Result<Vec<Vec<u16>>>;
+    fn set_threshold(&mut self, tx: usize, rx: usize, threshold: u16) -> Result<()>;
+    fn debounce(&mut self) -> Result<u8>;
+    fn set_debounce(&mut self, debounce: u8) -> Result<()>;
+    // None if the side is read from the handedness pin.
+    fn side(&mut self) -> Result<Option<Side>>;
+    fn set_side(&mut self, side: Option<Side>) -> Result<()>;
+    // The connected half, then the other half if it has sent values lately. The first call
+    // starts the relay of the other half, so poll it.
+    fn snapshot(&mut self) -> Result<Vec<HalfSnapshot>>;
+}
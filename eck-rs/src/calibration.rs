@@ -0,0 +1,259 @@
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+// Keys of a half stored in one record.
+pub const MAX_CALIBRATED_KEYS: usize = 64;
+
+// ADC values of a key.
+#[derive(defmt::Format, Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyCalibration {
+    pub resting: u16,
+    pub bottom: u16,
+    pub threshold: u16,
+}
+
+impl KeyCalibration {
+    fn is_valid(&self) -> bool {
+        self.resting < self.threshold && self.threshold <= self.bottom
+    }
+}
+
+// Calibration of a matrix half, indexed like ECScanner values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calibration<const TXSIZE: usize, const RXSIZE: usize> {
+    pub keys: [[KeyCalibration; RXSIZE]; TXSIZE],
+}
+
+// Stored form of Calibration. Matrix size is recorded to reject data of other boards.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CalibrationRecord {
+    tx: u8,
+    rx: u8,
+    keys: Vec<KeyCalibration, MAX_CALIBRATED_KEYS>,
+}
+
+impl<const TXSIZE: usize, const RXSIZE: usize> Calibration<TXSIZE, RXSIZE> {
+    // Uncalibrated keys with given thresholds. Resting and bottom are unknown.
+    pub fn from_thresholds(thresholds: &[[u16; RXSIZE]; TXSIZE]) -> Self {
+        let mut calibration = Self {
+            keys: [[KeyCalibration::default(); RXSIZE]; TXSIZE],
+        };
+        for (tx, row) in thresholds.iter().enumerate() {
+            for (rx, threshold) in row.iter().enumerate() {
+                calibration.set_threshold(tx, rx, *threshold);
+            }
+        }
+        calibration
+    }
+
+    // Set by hand. Resting and bottom are widened to keep the key valid.
+    pub fn set_threshold(&mut self, tx: usize, rx: usize, threshold: u16) {
+        let key = &mut self.keys[tx][rx];
+        key.threshold = threshold.max(1);
+        key.resting = key.resting.min(key.threshold - 1);
+        key.bottom = key.bottom.max(key.threshold);
+    }
+
+    pub fn thresholds(&self) -> [[u16; RXSIZE]; TXSIZE] {
+        self.keys.map(|row| row.map(|key| key.threshold))
+    }
+
+    pub fn to_record(&self) -> CalibrationRecord {
+        CalibrationRecord {
+            tx: TXSIZE as u8,
+            rx: RXSIZE as u8,
+            keys: self.keys.iter().flatten().copied().collect(),
+        }
+    }
+
+    // None if recorded for other matrix size or any key is out of order.
+    pub fn from_record(record: &CalibrationRecord) -> Option<Self> {
+        if record.tx as usize != TXSIZE
+            || record.rx as usize != RXSIZE
+            || record.keys.len() != TXSIZE * RXSIZE
+            || !record.keys.iter().all(KeyCalibration::is_valid)
+        {
+            return None;
+        }
+
+        let mut keys = [[KeyCalibration::default(); RXSIZE]; TXSIZE];
+        for (i, key) in record.keys.iter().enumerate() {
+            keys[i / RXSIZE][i % RXSIZE] = *key;
+        }
+        Some(Self { keys })
+    }
+}
+
+// Collects resting and bottom-out values while a half is calibrated.
+// Sample idle first with every key released, then while keys are pressed to the bottom.
+pub struct Calibrator<const TXSIZE: usize, const RXSIZE: usize> {
+    resting: [[u16; RXSIZE]; TXSIZE],
+    bottom: [[u16; RXSIZE]; TXSIZE],
+    // A key deeper than this from resting is pressed.
+    min_travel: u16,
+}
+
+impl<const TXSIZE: usize, const RXSIZE: usize> Calibrator<TXSIZE, RXSIZE> {
+    pub fn new(min_travel: u16) -> Self {
+        Self {
+            resting: [[0; RXSIZE]; TXSIZE],
+            bottom: [[0; RXSIZE]; TXSIZE],
+            min_travel,
+        }
+    }
+
+    // Highest idle value is the noise ceiling of a key.
+    pub fn sample_idle(&mut self, values: &[[u16; RXSIZE]; TXSIZE]) {
+        for (resting, value) in self
+            .resting
+            .iter_mut()
+            .flatten()
+            .zip(values.iter().flatten())
+        {
+            *resting = (*resting).max(*value);
+        }
+    }
+
+    // Returns true if any key is pressed now.
+    pub fn sample_pressed(&mut self, values: &[[u16; RXSIZE]; TXSIZE]) -> bool {
+        let mut pressed = false;
+        for (tx, row) in values.iter().enumerate() {
+            for (rx, value) in row.iter().enumerate() {
+                self.bottom[tx][rx] = self.bottom[tx][rx].max(*value);
+                pressed |= self.travel(*value, tx, rx) >= self.min_travel;
+            }
+        }
+        pressed
+    }
+
+    fn travel(&self, value: u16, tx: usize, rx: usize) -> u16 {
+        value.saturating_sub(self.resting[tx][rx])
+    }
+
+    pub fn is_calibrated(&self, tx: usize, rx: usize) -> bool {
+        self.travel(self.bottom[tx][rx], tx, rx) >= self.min_travel
+    }
+
+    pub fn calibrated_keys(&self) -> usize {
+        (0..TXSIZE)
+            .flat_map(|tx| (0..RXSIZE).map(move |rx| (tx, rx)))
+            .filter(|(tx, rx)| self.is_calibrated(*tx, *rx))
+            .count()
+    }
+
+    // Threshold at `percent` of the travel. Keys never pressed, e.g. unpopulated
+    // positions, keep the default threshold.
+    pub fn finish(
+        &self,
+        percent: u16,
+        defaults: &[[u16; RXSIZE]; TXSIZE],
+    ) -> Calibration<TXSIZE, RXSIZE> {
+        let mut keys = [[KeyCalibration::default(); RXSIZE]; TXSIZE];
+        for tx in 0..TXSIZE {
+            for rx in 0..RXSIZE {
+                let resting = self.resting[tx][rx];
+                let (threshold, bottom) = match self.is_calibrated(tx, rx) {
+                    true => {
+                        let bottom = self.bottom[tx][rx];
+                        let travel = (bottom - resting) as u32 * percent.min(100) as u32 / 100;
+                        ((resting + travel as u16).max(resting + 1), bottom)
+                    }
+                    false => {
+                        let threshold = defaults[tx][rx].max(resting.saturating_add(1));
+                        (threshold, threshold)
+                    }
+                };
+                keys[tx][rx] = KeyCalibration {
+                    resting,
+                    bottom,
+                    threshold,
+                };
+            }
+        }
+        Calibration { keys }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn calibration() -> Calibration<4, 7> {
+        let mut keys = [[KeyCalibration::default(); 7]; 4];
+        for (i, key) in keys.iter_mut().flatten().enumerate() {
+            let resting = 300 + i as u16;
+            *key = KeyCalibration {
+                resting,
+                bottom: 3000 - i as u16,
+                threshold: resting + 1000,
+            };
+        }
+        Calibration { keys }
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let calibration = calibration();
+        let record = calibration.to_record();
+        let mut buf = [0u8; crate::settings::MAX_VALUE_SIZE];
+        let bytes = postcard::to_slice(&record, &mut buf).unwrap();
+        let decoded: CalibrationRecord = postcard::from_bytes(bytes).unwrap();
+
+        assert_eq!(
+            Calibration::from_record(&decoded),
+            Some(calibration.clone())
+        );
+        assert_eq!(calibration.thresholds()[1][2], 300 + 9 + 1000);
+    }
+
+    #[test]
+    fn other_matrix_size() {
+        let record = calibration().to_record();
+        assert_eq!(Calibration::<7, 4>::from_record(&record), None);
+        assert_eq!(Calibration::<4, 6>::from_record(&record), None);
+    }
+
+    #[test]
+    fn set_threshold() {
+        let mut calibration = Calibration::<1, 2>::from_thresholds(&[[2000, 0]]);
+        assert_eq!(calibration.thresholds(), [[2000, 1]]);
+
+        calibration.keys[0][0].resting = 800;
+        calibration.set_threshold(0, 0, 500);
+        assert_eq!(
+            calibration.keys[0][0],
+            KeyCalibration {
+                resting: 499,
+                bottom: 2000,
+                threshold: 500
+            }
+        );
+        assert!(Calibration::<1, 2>::from_record(&calibration.to_record()).is_some());
+    }
+
+    #[test]
+    fn calibrator() {
+        let mut calibrator = Calibrator::<1, 3>::new(300);
+        calibrator.sample_idle(&[[500, 510, 490]]);
+        calibrator.sample_idle(&[[520, 505, 480]]);
+        assert!(!calibrator.sample_pressed(&[[600, 510, 490]]));
+        assert!(calibrator.sample_pressed(&[[2520, 510, 490]]));
+        assert!(calibrator.sample_pressed(&[[500, 1510, 490]]));
+        assert_eq!(calibrator.calibrated_keys(), 2);
+
+        let calibration = calibrator.finish(50, &[[2000; 3]]);
+        assert_eq!(calibration.thresholds(), [[1520, 1010, 2000]]);
+        assert_eq!(calibration.keys[0][2].bottom, 2000);
+        assert!(Calibration::<1, 3>::from_record(&calibration.to_record()).is_some());
+    }
+
+    #[test]
+    fn invalid_key() {
+        let mut calibration = calibration();
+        calibration.keys[3][6].threshold = calibration.keys[3][6].resting;
+        assert_eq!(
+            Calibration::from_record(&calibration.to_record()),
+            None::<Calibration<4, 7>>
+        );
+    }
+}
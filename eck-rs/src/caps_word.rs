@@ -0,0 +1,84 @@
+use keyberon::key_code::KeyCode;
+
+// Caps Lock for one word. Turns Caps Lock of the host on, and back off at the first pressed key
+// which is not part of a word. The host owns Caps Lock, so it is tapped only if the host's state
+// as reported by its LED output report differs.
+#[derive(Debug, Default)]
+pub struct CapsWord {
+    active: bool,
+    // Caps Lock of the host is checked at the next tick.
+    sync: bool,
+}
+
+impl CapsWord {
+    pub fn toggle(&mut self) {
+        self.active = !self.active;
+        self.sync = true;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // Keys held in this tick. Any of them ending a word ends it.
+    pub fn keys(&mut self, mut keys: impl Iterator<Item = KeyCode>) {
+        if self.active && keys.any(ends_word) {
+            self.active = false;
+            self.sync = true;
+        }
+    }
+
+    // Caps Lock to add to the report of this tick, pressed for one tick after a change.
+    pub fn tick(&mut self, caps_lock: bool) -> Option<KeyCode> {
+        if !self.sync {
+            return None;
+        }
+        self.sync = false;
+        (caps_lock != self.active).then_some(KeyCode::CapsLock)
+    }
+}
+
+// Letters, digits, `-`, `_`, backspace, delete and modifiers continue a word.
+fn ends_word(kc: KeyCode) -> bool {
+    let word = (KeyCode::A as u8..=KeyCode::Kb0 as u8).contains(&(kc as u8))
+        || (KeyCode::LCtrl as u8..=KeyCode::RGui as u8).contains(&(kc as u8));
+    !word && !matches!(kc, KeyCode::Minus | KeyCode::BSpace | KeyCode::Delete)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ends_at_space() {
+        let mut caps_word = CapsWord::default();
+        caps_word.toggle();
+        assert_eq!(caps_word.tick(false), Some(KeyCode::CapsLock));
+        assert_eq!(caps_word.tick(true), None, "tapped once");
+
+        let word = [KeyCode::A, KeyCode::Kb1, KeyCode::LShift, KeyCode::Minus];
+        caps_word.keys(word.into_iter());
+        caps_word.keys(core::iter::once(KeyCode::BSpace));
+        assert!(caps_word.is_active());
+        assert_eq!(caps_word.tick(true), None);
+
+        caps_word.keys([KeyCode::B, KeyCode::Space].into_iter());
+        assert!(!caps_word.is_active());
+        assert_eq!(caps_word.tick(true), Some(KeyCode::CapsLock));
+    }
+
+    #[test]
+    fn follows_host() {
+        let mut caps_word = CapsWord::default();
+        // Caps Lock already on.
+        caps_word.toggle();
+        assert_eq!(caps_word.tick(true), None);
+
+        // Toggled off, the host turned Caps Lock off meanwhile.
+        caps_word.toggle();
+        assert_eq!(caps_word.tick(false), None);
+
+        caps_word.keys(core::iter::once(KeyCode::Dot));
+        assert_eq!(caps_word.tick(false), None, "not active");
+    }
+}
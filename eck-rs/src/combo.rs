@@ -0,0 +1,311 @@
+use heapless::Vec;
+
+use crate::event::Event;
+use crate::stage::{Bindings, Stage};
+
+// Combos held at once.
+const MAX_ACTIVE: usize = 8;
+
+// (row, col) of the layout.
+pub type Key = (u8, u8);
+
+// Keys pressed together act as `key` of the layout, usually one without a switch.
+// `layers` limits the combo to those layers, None for every layer.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Combo<'a> {
+    pub keys: &'a [Key],
+    pub key: Key,
+    pub layers: Option<&'a [u8]>,
+}
+
+impl Combo<'_> {
+    fn allowed(&self, layer: u8) -> bool {
+        match self.layers {
+            Some(layers) => layers.contains(&layer),
+            None => true,
+        }
+    }
+
+    fn contains_all(&self, keys: &[Key]) -> bool {
+        keys.iter().all(|k| self.keys.contains(k))
+    }
+}
+
+#[derive(Debug)]
+struct Active<const KEYS: usize> {
+    combo: usize,
+    // Keys of the combo not released yet.
+    held: Vec<Key, KEYS>,
+    pressed: bool,
+}
+
+// Presses which may start a combo of the current layer are held back until the combo is
+// complete, a key not in it is pressed, or `timeout_ms` passes since the first of them.
+// Combos of more than KEYS keys never fire.
+pub struct Combos<'a, const KEYS: usize> {
+    combos: &'a [Combo<'a>],
+    timeout_ms: u64,
+    pending: Vec<Key, KEYS>,
+    // Time and layer of the first pending press.
+    pending_since: u64,
+    layer: u8,
+    active: Vec<Active<KEYS>, MAX_ACTIVE>,
+}
+
+impl<'a, const KEYS: usize> Combos<'a, KEYS> {
+    pub fn new(combos: &'a [Combo<'a>], timeout_ms: u64) -> Self {
+        Self {
+            combos,
+            timeout_ms,
+            pending: Vec::new(),
+            pending_since: 0,
+            layer: 0,
+            active: Vec::new(),
+        }
+    }
+
+    fn press(&mut self, key: Key, now_ms: u64, layer: u8, out: &mut impl FnMut(Event)) {
+        if !self.pending.is_empty() && !self.extends(key) {
+            self.resolve(out);
+        }
+        if self.pending.is_empty() {
+            self.pending_since = now_ms;
+            self.layer = layer;
+        }
+        if !self.extends(key) {
+            return out(Event::KeyPress(key.0, key.1));
+        }
+
+        let _ = self.pending.push(key);
+        if let Some(combo) = self.complete() {
+            self.fire(combo, out);
+        }
+    }
+
+    // The combo key is released with the first of its keys.
+    fn release(&mut self, key: Key, out: &mut impl FnMut(Event)) {
+        if self.pending.contains(&key) {
+            self.resolve(out);
+        }
+
+        let Some(index) = self.active.iter().position(|a| a.held.contains(&key)) else {
+            return out(Event::KeyRelease(key.0, key.1));
+        };
+        let active = &mut self.active[index];
+        if active.pressed {
+            let (i, j) = self.combos[active.combo].key;
+            out(Event::KeyRelease(i, j));
+            active.pressed = false;
+        }
+        active.held.retain(|k| *k != key);
+        if active.held.is_empty() {
+            self.active.swap_remove(index);
+        }
+    }
+
+    // A combo of the layer has every pending key and `key`.
+    fn extends(&self, key: Key) -> bool {
+        !self.pending.is_full()
+            && !self.pending.contains(&key)
+            && self.combos.iter().any(|c| {
+                c.allowed(self.layer) && c.contains_all(&self.pending) && c.keys.contains(&key)
+            })
+    }
+
+    fn exact(&self) -> Option<usize> {
+        self.combos.iter().position(|c| {
+            c.allowed(self.layer)
+                && c.keys.len() == self.pending.len()
+                && c.contains_all(&self.pending)
+        })
+    }
+
+    // Exact match which no longer combo can follow.
+    fn complete(&self) -> Option<usize> {
+        let longer = self.combos.iter().any(|c| {
+            c.allowed(self.layer)
+                && c.keys.len() > self.pending.len()
+                && c.contains_all(&self.pending)
+        });
+        match longer {
+            true => None,
+            false => self.exact(),
+        }
+    }
+
+    // Fire the combo of the pending keys, or let them through as they are.
+    fn resolve(&mut self, out: &mut impl FnMut(Event)) {
+        match self.exact() {
+            Some(combo) if !self.active.is_full() => self.fire(combo, out),
+            _ => {
+                for (i, j) in self.pending.iter() {
+                    out(Event::KeyPress(*i, *j));
+                }
+                self.pending.clear();
+            }
+        }
+    }
+
+    fn fire(&mut self, combo: usize, out: &mut impl FnMut(Event)) {
+        if self.active.is_full() {
+            return self.resolve(out);
+        }
+        let (i, j) = self.combos[combo].key;
+        out(Event::KeyPress(i, j));
+        let _ = self.active.push(Active {
+            combo,
+            held: self.pending.clone(),
+            pressed: true,
+        });
+        self.pending.clear();
+    }
+}
+
+impl<const KEYS: usize> Stage for Combos<'_, KEYS> {
+    fn event(
+        &mut self,
+        event: Event,
+        now_ms: u64,
+        bindings: &impl Bindings,
+        out: &mut impl FnMut(Event),
+    ) {
+        match event {
+            Event::KeyPress(i, j) => self.press((i, j), now_ms, bindings.layer(), out),
+            Event::KeyRelease(i, j) => self.release((i, j), out),
+            Event::None => {}
+        }
+    }
+
+    // Presses held back for the timeout go as they are, or as the combo they complete.
+    fn tick(&mut self, now_ms: u64, _: &impl Bindings, out: &mut impl FnMut(Event)) {
+        if self.deadline().is_some_and(|deadline| now_ms >= deadline) {
+            self.resolve(out);
+        }
+    }
+
+    fn deadline(&self) -> Option<u64> {
+        (!self.pending.is_empty()).then_some(self.pending_since + self.timeout_ms)
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.active.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage::test::{run, tick, TestBindings};
+    use Event::{KeyPress as P, KeyRelease as R};
+
+    const J: Key = (2, 7);
+    const K: Key = (2, 8);
+    const L: Key = (2, 9);
+    const ESC: Key = (4, 11);
+    const TAB: Key = (4, 0);
+    const ENTER: Key = (4, 1);
+
+    static COMBOS: [Combo; 3] = [
+        Combo {
+            keys: &[J, K],
+            key: ESC,
+            layers: Some(&[0]),
+        },
+        Combo {
+            keys: &[J, K, L],
+            key: ENTER,
+            layers: None,
+        },
+        Combo {
+            keys: &[K, L],
+            key: TAB,
+            layers: None,
+        },
+    ];
+
+    fn on_layer(layer: u8) -> TestBindings {
+        TestBindings {
+            layer,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fires_on_release() {
+        let mut combos = Combos::<3>::new(&COMBOS, 50);
+        // Tapped before the timeout. J may still follow, so it fires on release.
+        let out = run(
+            &mut combos,
+            &on_layer(0),
+            &[(P(2, 8), 0), (P(2, 9), 10), (R(2, 9), 20), (R(2, 8), 30)],
+        );
+        assert_eq!(out, [P(4, 0), R(4, 0)]);
+        assert_eq!(combos.deadline(), None);
+    }
+
+    #[test]
+    fn waits_for_longer_combo() {
+        let mut combos = Combos::<3>::new(&COMBOS, 50);
+        let out = run(&mut combos, &on_layer(0), &[(P(2, 7), 0), (P(2, 8), 10)]);
+        assert!(out.is_empty());
+        assert_eq!(combos.deadline(), Some(50));
+
+        // Timeout fires the shorter one.
+        let mut out = tick(&mut combos, &on_layer(0), 50);
+        out.extend(run(
+            &mut combos,
+            &on_layer(0),
+            &[(R(2, 7), 60), (R(2, 8), 70)],
+        ));
+        assert_eq!(out, [P(4, 11), R(4, 11)]);
+
+        let out = run(&mut combos, &on_layer(0), &[(P(2, 7), 100), (P(2, 8), 110)]);
+        assert!(out.is_empty());
+        assert_eq!(
+            run(&mut combos, &on_layer(0), &[(P(2, 9), 120)]),
+            [P(4, 1)],
+            "the longer one fires right away"
+        );
+    }
+
+    #[test]
+    fn lets_through_other_keys() {
+        let mut combos = Combos::<3>::new(&COMBOS, 50);
+        // Tap of a combo key is delayed until release.
+        let out = run(&mut combos, &on_layer(0), &[(P(2, 7), 0), (R(2, 7), 30)]);
+        assert_eq!(out, [P(2, 7), R(2, 7)]);
+
+        // A key not in the combo lets held back presses through first.
+        let out = run(&mut combos, &on_layer(0), &[(P(2, 7), 100), (P(0, 0), 110)]);
+        assert_eq!(out, [P(2, 7), P(0, 0)]);
+        let out = run(&mut combos, &on_layer(0), &[(R(2, 7), 120), (R(0, 0), 130)]);
+        assert_eq!(out, [R(2, 7), R(0, 0)]);
+
+        // Too slow.
+        let out = run(&mut combos, &on_layer(0), &[(P(2, 8), 200), (P(2, 9), 260)]);
+        assert_eq!(out, [P(2, 8)]);
+        assert_eq!(tick(&mut combos, &on_layer(0), 310).as_slice(), [P(2, 9)]);
+    }
+
+    #[test]
+    fn limited_to_layers() {
+        let mut combos = Combos::<3>::new(&COMBOS, 50);
+        let out = run(&mut combos, &on_layer(1), &[(P(2, 7), 0), (P(2, 8), 10)]);
+        assert!(out.is_empty());
+        // Only J+K+L is on layer 1.
+        assert_eq!(
+            tick(&mut combos, &on_layer(1), 50).as_slice(),
+            [P(2, 7), P(2, 8)]
+        );
+    }
+
+    #[test]
+    fn reset_forgets_keys() {
+        let mut combos = Combos::<3>::new(&COMBOS, 50);
+        run(&mut combos, &on_layer(0), &[(P(2, 8), 0), (P(2, 9), 10)]);
+        combos.reset();
+        let out = run(&mut combos, &on_layer(0), &[(R(2, 8), 20), (R(2, 9), 30)]);
+        assert_eq!(out, [R(2, 8), R(2, 9)]);
+    }
+}
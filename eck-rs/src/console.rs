@@ -0,0 +1,238 @@
+// Line based console commands. Every response ends with a line of "ok" or "error: <reason>".
+use heapless::{String, Vec};
+
+pub const MAX_LINE_LEN: usize = 64;
+
+pub const HELP: &str = "\
+help                          this message
+values                        raw ADC values
+thresholds                    key thresholds
+threshold <tx> <rx> <value>   set and save threshold of a key
+debounce [<n>]                show or set and save debounce count
+heatmap                       live values until any input
+stats                         scan statistics
+link                          split link statistics
+side [left|right|clear]       show, store or clear side of this half
+size                          keymap and matrix size
+keycode <l> <r> <c> [<code>]  show or set keycode of a key
+snapshot                      values and thresholds of both halves
+";
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SideArg {
+    Left,
+    Right,
+    Clear,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Values,
+    Thresholds,
+    SetThreshold {
+        tx: usize,
+        rx: usize,
+        value: u16,
+    },
+    Debounce(Option<u8>),
+    Heatmap,
+    Stats,
+    Link,
+    Side(Option<SideArg>),
+    Size,
+    Keycode {
+        layer: usize,
+        row: usize,
+        col: usize,
+        keycode: Option<u16>,
+    },
+    Snapshot,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    UnknownCommand,
+    MissingArgument,
+    InvalidArgument,
+    TooManyArguments,
+}
+
+impl ParseError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParseError::Empty => "empty",
+            ParseError::UnknownCommand => "unknown command",
+            ParseError::MissingArgument => "missing argument",
+            ParseError::InvalidArgument => "invalid argument",
+            ParseError::TooManyArguments => "too many arguments",
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let mut args = line.split_ascii_whitespace();
+        let name = args.next().ok_or(ParseError::Empty)?;
+        let mut arg = || args.next().ok_or(ParseError::MissingArgument);
+
+        let cmd = match name {
+            "help" | "?" => Command::Help,
+            "values" => Command::Values,
+            "thresholds" => Command::Thresholds,
+            "threshold" => Command::SetThreshold {
+                tx: number(arg()?)?,
+                rx: number(arg()?)?,
+                value: number(arg()?)?,
+            },
+            "debounce" => Command::Debounce(arg().ok().map(number).transpose()?),
+            "heatmap" => Command::Heatmap,
+            "stats" => Command::Stats,
+            "link" => Command::Link,
+            "side" => Command::Side(match arg().ok() {
+                None => None,
+                Some("left") => Some(SideArg::Left),
+                Some("right") => Some(SideArg::Right),
+                Some("clear") => Some(SideArg::Clear),
+                Some(_) => return Err(ParseError::InvalidArgument),
+            }),
+            "size" => Command::Size,
+            "keycode" => Command::Keycode {
+                layer: number(arg()?)?,
+                row: number(arg()?)?,
+                col: number(arg()?)?,
+                keycode: arg().ok().map(number).transpose()?,
+            },
+            "snapshot" => Command::Snapshot,
+            _ => return Err(ParseError::UnknownCommand),
+        };
+
+        match args.next() {
+            Some(_) => Err(ParseError::TooManyArguments),
+            None => Ok(cmd),
+        }
+    }
+}
+
+fn number<T: core::str::FromStr>(arg: &str) -> Result<T, ParseError> {
+    arg.parse().map_err(|_| ParseError::InvalidArgument)
+}
+
+// Collects input bytes into lines. Handles backspace, ignores control characters.
+pub struct LineBuffer {
+    buf: Vec<u8, MAX_LINE_LEN>,
+    overflow: bool,
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    // Returns a completed line. Too long lines are returned empty.
+    pub fn push(&mut self, byte: u8) -> Option<String<MAX_LINE_LEN>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let mut line = String::new();
+                if !self.overflow {
+                    // Only printable ASCII is pushed.
+                    let _ = line.push_str(core::str::from_utf8(&self.buf).unwrap_or(""));
+                }
+                self.buf.clear();
+                self.overflow = false;
+                Some(line)
+            }
+            // Backspace, delete
+            0x08 | 0x7f => {
+                self.buf.pop();
+                None
+            }
+            0x20..=0x7e => {
+                if self.buf.push(byte).is_err() {
+                    self.overflow = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("values"), Ok(Command::Values));
+        assert_eq!(
+            Command::parse(" threshold 1  2 1800 "),
+            Ok(Command::SetThreshold {
+                tx: 1,
+                rx: 2,
+                value: 1800
+            })
+        );
+        assert_eq!(Command::parse("debounce"), Ok(Command::Debounce(None)));
+        assert_eq!(Command::parse("debounce 3"), Ok(Command::Debounce(Some(3))));
+        assert_eq!(
+            Command::parse("side clear"),
+            Ok(Command::Side(Some(SideArg::Clear)))
+        );
+
+        assert_eq!(
+            Command::parse("keycode 1 2 3 4"),
+            Ok(Command::Keycode {
+                layer: 1,
+                row: 2,
+                col: 3,
+                keycode: Some(4)
+            })
+        );
+
+        assert_eq!(Command::parse(""), Err(ParseError::Empty));
+        assert_eq!(Command::parse("reboot"), Err(ParseError::UnknownCommand));
+        assert_eq!(
+            Command::parse("threshold 1 2"),
+            Err(ParseError::MissingArgument)
+        );
+        assert_eq!(
+            Command::parse("threshold 1 2 70000"),
+            Err(ParseError::InvalidArgument)
+        );
+        assert_eq!(Command::parse("side up"), Err(ParseError::InvalidArgument));
+        assert_eq!(
+            Command::parse("stats now"),
+            Err(ParseError::TooManyArguments)
+        );
+    }
+
+    #[test]
+    fn line_buffer() {
+        let mut buffer = LineBuffer::new();
+        let mut lines = heapless::Vec::<String<MAX_LINE_LEN>, 4>::new();
+        for byte in b"vak\x7flues\r\nx\x1b\n" {
+            if let Some(line) = buffer.push(*byte) {
+                lines.push(line).unwrap();
+            }
+        }
+        assert_eq!(lines, ["values", "", "x"]);
+
+        for _ in 0..MAX_LINE_LEN + 1 {
+            assert_eq!(buffer.push(b'a'), None);
+        }
+        assert_eq!(buffer.push(b'\n').unwrap(), "");
+        assert_eq!(buffer.push(b'b'), None);
+        assert_eq!(buffer.push(b'\n').unwrap(), "b");
+    }
+}
@@ -0,0 +1,20 @@
+// CRC-16/CCITT-FALSE (poly 0x1021, init 0xffff).
+const CRC16_POLY: u16 = 0x1021;
+
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0xffff, data)
+}
+
+// Continue crc16 over more data.
+pub fn crc16_update(mut crc: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = match crc & 0x8000 != 0 {
+                true => (crc << 1) ^ CRC16_POLY,
+                false => crc << 1,
+            };
+        }
+    }
+    crc
+}
@@ -7,14 +7,25 @@ pub struct Debouncer<const ROWS: usize, const COLS: usize> {
 }
 
 impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> {
+    /// At least one scan, e.g. for a count of 0 from flash or the other half.
     pub const fn new(nb_bounce: u8) -> Self {
         Self {
             state: [[false; COLS]; ROWS],
             hit_cnt: [[0; COLS]; ROWS],
-            nb_bounce,
+            nb_bounce: if nb_bounce == 0 { 1 } else { nb_bounce },
         }
     }
 
+    pub fn nb_bounce(&self) -> u8 {
+        self.nb_bounce
+    }
+
+    /// Changes the count. Key states are kept, pending changes restart.
+    pub fn set_nb_bounce(&mut self, nb_bounce: u8) {
+        self.nb_bounce = nb_bounce.max(1);
+        self.hit_cnt = [[0; COLS]; ROWS];
+    }
+
     /// Updates the key history
     pub fn update(
         &mut self,
@@ -47,3 +58,19 @@ impl<const ROWS: usize, const COLS: usize> Debouncer<ROWS, COLS> {
         Ok(is_changed)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_is_one_scan() {
+        let mut debouncer = Debouncer::<1, 1>::new(0);
+        assert_eq!(debouncer.nb_bounce(), 1);
+        assert!(debouncer.update(0, 0, true).unwrap(), "on the first scan");
+
+        debouncer.set_nb_bounce(0);
+        assert_eq!(debouncer.nb_bounce(), 1);
+        assert!(debouncer.update(0, 0, false).unwrap());
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(defmt::Format, Debug)]
+#[derive(defmt::Format, Debug, Clone)]
 pub enum KeyboardError {
     RowOutOfRange(usize),
     ColOutOfRange(usize),
@@ -7,4 +7,6 @@ pub enum KeyboardError {
 
     InvaildHeader,
     InvailedCRC,
+    BufferOverflow,
+    Serialize,
 }
@@ -1,8 +1,9 @@
 use keyberon;
+use serde::{Deserialize, Serialize};
 
 type KeyberonEvent = keyberon::layout::Event;
 
-#[derive(defmt::Format, Debug, Copy, Clone, Default)]
+#[derive(defmt::Format, Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
     KeyPress(u8, u8),
     KeyRelease(u8, u8),
@@ -1,8 +1,22 @@
 #![no_std]
 #![feature(stmt_expr_attributes)]
 pub mod analog;
+pub mod calibration;
+pub mod caps_word;
+pub mod combo;
+pub mod console;
+pub mod crc;
 pub mod debounce;
 pub mod error;
 pub mod event;
+pub mod macros;
+pub mod mouse;
 pub mod mux;
+pub mod one_shot;
+pub mod report;
 pub mod scanner;
+pub mod settings;
+pub mod split;
+pub mod stage;
+pub mod tap_dance;
+pub mod via;
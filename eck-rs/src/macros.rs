@@ -0,0 +1,344 @@
+// Keyboard macros in the VIA dynamic macro buffer format. Macros are separated by NUL.
+// Text is typed as is and actions follow SS_QMK_PREFIX:
+//   | 1 | 1 | keycode |                 tap
+//   | 1 | 2 | keycode |                 press
+//   | 1 | 3 | keycode |                 release
+//   | 1 | 4 | ms in ASCII digits | '|' |  delay
+use heapless::Vec;
+use keyberon::key_code::KeyCode;
+
+const SS_QMK_PREFIX: u8 = 0x01;
+const SS_TAP_CODE: u8 = 0x01;
+const SS_DOWN_CODE: u8 = 0x02;
+const SS_UP_CODE: u8 = 0x03;
+const SS_DELAY_CODE: u8 = 0x04;
+const SS_DELAY_END: u8 = b'|';
+
+// Keys held by a macro at once.
+const MAX_HELD: usize = 6;
+
+// Step of a macro compiled into the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step<'a> {
+    Tap(KeyCode),
+    Press(KeyCode),
+    Release(KeyCode),
+    Delay(u16),
+    // Printable ASCII, newline and tab. Typed with keycodes of the US layout.
+    Text(&'a str),
+}
+
+// Step read from the buffer. Keycodes are HID usages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Tap(u8),
+    Press(u8),
+    Release(u8),
+    Delay(u16),
+    Char(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    BufferFull,
+    // Text has NUL or SS_QMK_PREFIX.
+    InvalidText,
+}
+
+// Write macros to the buffer, each ended with NUL. The rest of the buffer is cleared.
+pub fn encode(macros: &[&[Step]], buffer: &mut [u8]) -> Result<(), EncodeError> {
+    let mut len = 0;
+    let mut put = |bytes: &[u8]| {
+        let dest = buffer
+            .get_mut(len..len + bytes.len())
+            .ok_or(EncodeError::BufferFull)?;
+        dest.copy_from_slice(bytes);
+        len += bytes.len();
+        Ok(())
+    };
+
+    for steps in macros {
+        for step in steps.iter() {
+            match *step {
+                Step::Tap(kc) => put(&[SS_QMK_PREFIX, SS_TAP_CODE, kc as u8])?,
+                Step::Press(kc) => put(&[SS_QMK_PREFIX, SS_DOWN_CODE, kc as u8])?,
+                Step::Release(kc) => put(&[SS_QMK_PREFIX, SS_UP_CODE, kc as u8])?,
+                Step::Delay(ms) => {
+                    put(&[SS_QMK_PREFIX, SS_DELAY_CODE])?;
+                    let mut digits = [0u8; 5];
+                    let mut n = digits.len();
+                    let mut ms = ms;
+                    loop {
+                        n -= 1;
+                        digits[n] = b'0' + (ms % 10) as u8;
+                        ms /= 10;
+                        if ms == 0 {
+                            break;
+                        }
+                    }
+                    put(&digits[n..])?;
+                    put(&[SS_DELAY_END])?;
+                }
+                Step::Text(text) => {
+                    if text.bytes().any(|b| b == 0 || b == SS_QMK_PREFIX) {
+                        return Err(EncodeError::InvalidText);
+                    }
+                    put(text.as_bytes())?;
+                }
+            }
+        }
+        put(&[0])?;
+    }
+    buffer[len..].fill(0);
+    Ok(())
+}
+
+// Bytes of the nth macro, empty if there are fewer macros.
+pub fn get(buffer: &[u8], index: usize) -> &[u8] {
+    buffer.split(|b| *b == 0).nth(index).unwrap_or(&[])
+}
+
+// Next step of a macro and its size. None at the end or on malformed bytes.
+pub fn decode(bytes: &[u8]) -> Option<(Op, usize)> {
+    let op = match *bytes {
+        [] | [0, ..] => return None,
+        [SS_QMK_PREFIX, SS_TAP_CODE, kc, ..] if kc != 0 => (Op::Tap(kc), 3),
+        [SS_QMK_PREFIX, SS_DOWN_CODE, kc, ..] if kc != 0 => (Op::Press(kc), 3),
+        [SS_QMK_PREFIX, SS_UP_CODE, kc, ..] if kc != 0 => (Op::Release(kc), 3),
+        [SS_QMK_PREFIX, SS_DELAY_CODE, ref rest @ ..] => {
+            let end = rest.iter().position(|b| *b == SS_DELAY_END)?;
+            let mut ms = 0u32;
+            for b in &rest[..end] {
+                if !b.is_ascii_digit() {
+                    return None;
+                }
+                ms = (ms * 10 + (b - b'0') as u32).min(u16::MAX as u32);
+            }
+            (Op::Delay(ms as u16), 3 + end)
+        }
+        [SS_QMK_PREFIX, ..] => return None,
+        [c, ..] => (Op::Char(c), 1),
+    };
+    Some(op)
+}
+
+// Plays a macro one step per tick, so that the host sees every press and release.
+// Keys are QMK keycodes, characters are typed as modded keys, e.g. LSFT(KC_A).
+pub struct Player<const N: usize> {
+    bytes: Vec<u8, N>,
+    pos: usize,
+    held: Vec<u16, MAX_HELD>,
+    // Tapped in the last tick, released in this one.
+    tapped: Option<u16>,
+    wait_until: u64,
+    // Keycode typing an ASCII character.
+    ascii: fn(u8) -> Option<u16>,
+}
+
+impl<const N: usize> Player<N> {
+    pub fn new(ascii: fn(u8) -> Option<u16>) -> Self {
+        Self {
+            bytes: Vec::new(),
+            pos: 0,
+            held: Vec::new(),
+            tapped: None,
+            wait_until: 0,
+            ascii,
+        }
+    }
+
+    // Start a macro, false if another one is running.
+    pub fn start(&mut self, bytes: &[u8]) -> bool {
+        if self.is_running() {
+            return false;
+        }
+        self.stop();
+        // Shorter than the buffer it comes from.
+        let _ = self.bytes.extend_from_slice(&bytes[..bytes.len().min(N)]);
+        true
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.bytes.is_empty()
+    }
+
+    // Release every key and forget the macro.
+    pub fn stop(&mut self) {
+        self.bytes.clear();
+        self.pos = 0;
+        self.held.clear();
+        self.tapped = None;
+        self.wait_until = 0;
+    }
+
+    pub fn pressed(&self) -> impl Iterator<Item = u16> + '_ {
+        self.held.iter().copied()
+    }
+
+    pub fn tick(&mut self, now_ms: u64) {
+        if let Some(kc) = self.tapped.take() {
+            self.held.retain(|k| *k != kc);
+            return;
+        }
+        if !self.is_running() || now_ms < self.wait_until {
+            return;
+        }
+
+        // Keys still held at the end are released.
+        let Some((op, len)) = decode(&self.bytes[self.pos..]) else {
+            return self.stop();
+        };
+        self.pos += len;
+        match op {
+            Op::Tap(kc) => self.tap(kc as u16),
+            Op::Press(kc) => self.press(kc as u16),
+            Op::Release(kc) => self.held.retain(|k| *k != kc as u16),
+            Op::Delay(ms) => self.wait_until = now_ms + ms as u64,
+            // Characters without a key are skipped.
+            Op::Char(c) => {
+                if let Some(kc) = (self.ascii)(c) {
+                    self.tap(kc);
+                }
+            }
+        }
+    }
+
+    fn press(&mut self, kc: u16) {
+        if !self.held.contains(&kc) {
+            let _ = self.held.push(kc);
+        }
+    }
+
+    fn tap(&mut self, kc: u16) {
+        self.press(kc);
+        self.tapped = Some(kc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSFT: u16 = 0x0200;
+
+    fn ascii(c: u8) -> Option<u16> {
+        match c {
+            b'a'..=b'z' => Some(0x04 + (c - b'a') as u16),
+            b'A'..=b'Z' => Some(LSFT | (0x04 + (c - b'A') as u16)),
+            _ => None,
+        }
+    }
+
+    // Keys held after each tick.
+    fn play(player: &mut Player<64>, ticks: u64) -> Vec<Vec<u16, 4>, 32> {
+        (0..ticks)
+            .map(|now| {
+                player.tick(now);
+                player.pressed().collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let macros: [&[Step]; 2] = [
+            &[Step::Press(KeyCode::LCtrl), Step::Tap(KeyCode::C)],
+            &[
+                Step::Text("ab"),
+                Step::Delay(250),
+                Step::Release(KeyCode::LCtrl),
+            ],
+        ];
+        let mut buffer = [0xAAu8; 32];
+        encode(&macros, &mut buffer).unwrap();
+        assert_eq!(
+            buffer[..21],
+            [
+                1, 2, 0xE0, 1, 1, 0x06, 0, b'a', b'b', 1, 4, b'2', b'5', b'0', b'|', 1, 3, 0xE0, 0,
+                0, 0
+            ]
+        );
+
+        let ops = |bytes: &[u8]| {
+            let mut ops: Vec<Op, 8> = Vec::new();
+            let mut rest = bytes;
+            while let Some((op, len)) = decode(rest) {
+                ops.push(op).unwrap();
+                rest = &rest[len..];
+            }
+            ops
+        };
+        assert_eq!(ops(get(&buffer, 0)), [Op::Press(0xE0), Op::Tap(0x06)]);
+        assert_eq!(
+            ops(get(&buffer, 1)),
+            [
+                Op::Char(b'a'),
+                Op::Char(b'b'),
+                Op::Delay(250),
+                Op::Release(0xE0)
+            ]
+        );
+        assert!(get(&buffer, 5).is_empty());
+
+        assert_eq!(
+            encode(&macros, &mut [0u8; 16]),
+            Err(EncodeError::BufferFull)
+        );
+        assert_eq!(
+            encode(&[&[Step::Text("\x01")]], &mut buffer),
+            Err(EncodeError::InvalidText)
+        );
+    }
+
+    #[test]
+    fn plays_a_step_per_tick() {
+        let mut player = Player::<64>::new(ascii);
+        // Press LCtrl, tap C, type "aA", release LCtrl.
+        assert!(player.start(&[1, 2, 0xE0, 1, 1, 0x06, b'a', b'A', 1, 3, 0xE0, 0]));
+        assert!(!player.start(b"a"), "one macro at a time");
+
+        let held = play(&mut player, 9);
+        let expected: [&[u16]; 9] = [
+            &[0xE0],
+            &[0xE0, 0x06],
+            &[0xE0],
+            &[0xE0, 0x04],
+            &[0xE0],
+            &[0xE0, LSFT | 0x04],
+            &[0xE0],
+            &[],
+            &[],
+        ];
+        assert_eq!(held, expected);
+        assert!(!player.is_running());
+    }
+
+    #[test]
+    fn waits_for_delay() {
+        let mut player = Player::<64>::new(ascii);
+        player.start(&[1, 2, 0xE1, 1, 4, b'3', b'|', b'b']);
+        let held = play(&mut player, 8);
+        let expected: [&[u16]; 8] = [
+            &[0xE1],
+            &[0xE1],
+            &[0xE1],
+            &[0xE1],
+            &[0xE1, 0x05],
+            &[0xE1],
+            // Keys held at the end are released.
+            &[],
+            &[],
+        ];
+        assert_eq!(held, expected);
+    }
+
+    #[test]
+    fn stops_on_malformed_bytes() {
+        let mut player = Player::<64>::new(ascii);
+        player.start(&[1, 2, 0xE0, 1, 4, b'x', b'|', b'a']);
+        player.tick(0);
+        player.tick(1);
+        assert!(!player.is_running());
+        assert_eq!(player.pressed().count(), 0);
+    }
+}
@@ -0,0 +1,317 @@
+use heapless::Vec;
+
+const MAX_HELD: usize = 8;
+// Full scale of analog depth.
+pub const DEPTH_FULL: u8 = u8::MAX;
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKey {
+    Up,
+    Down,
+    Left,
+    Right,
+    WheelUp,
+    WheelDown,
+    WheelLeft,
+    WheelRight,
+    // Button bit. 0: left, 1: right, 2: middle, 3: back, 4: forward
+    Button(u8),
+    // Use acceleration profile while held.
+    Profile(u8),
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccelCurve {
+    Linear,
+    Quadratic,
+}
+
+// Speed grows from min_speed to max_speed over time_to_max ticks after delay.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccelProfile {
+    // ticks between movements.
+    pub interval: u16,
+    // ticks before acceleration starts.
+    pub delay: u16,
+    pub time_to_max: u16,
+    // counts per movement.
+    pub min_speed: u8,
+    pub max_speed: u8,
+    pub curve: AccelCurve,
+    // ticks between wheel steps.
+    pub wheel_interval: u16,
+}
+
+impl AccelProfile {
+    pub fn speed(&self, held: u32) -> u8 {
+        let elapsed = held.saturating_sub(self.delay as u32);
+        let total = (self.time_to_max as u32).max(1);
+        let progress = elapsed.min(total);
+        let range = self.max_speed.saturating_sub(self.min_speed) as u32;
+
+        // range * progress^2 overflows u32 at long time_to_max.
+        let delta = match self.curve {
+            AccelCurve::Linear => range * progress / total,
+            AccelCurve::Quadratic => {
+                (range as u64 * (progress as u64).pow(2) / (total as u64).pow(2)) as u32
+            }
+        };
+        self.min_speed + delta as u8
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MouseState {
+    pub buttons: u8,
+    pub x: i8,
+    pub y: i8,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+pub struct MouseKeys<const N: usize> {
+    profiles: [AccelProfile; N],
+    held: Vec<MouseKey, MAX_HELD>,
+    buttons: u8,
+    // ticks since a movement key is pressed.
+    move_ticks: u32,
+    wheel_ticks: u32,
+    depth: Option<u8>,
+    last_buttons: u8,
+}
+
+impl<const N: usize> MouseKeys<N> {
+    // First profile is used without profile key.
+    pub fn new(profiles: [AccelProfile; N]) -> Self {
+        Self {
+            profiles,
+            held: Vec::new(),
+            buttons: 0,
+            move_ticks: 0,
+            wheel_ticks: 0,
+            depth: None,
+            last_buttons: 0,
+        }
+    }
+
+    pub fn press(&mut self, key: MouseKey) {
+        if let MouseKey::Button(bit) = key {
+            self.buttons |= 1 << bit;
+            return;
+        }
+        if self.held.contains(&key) {
+            return;
+        }
+
+        if is_move(key) && !self.held.iter().any(|k| is_move(*k)) {
+            self.move_ticks = 0;
+        }
+        if is_wheel(key) && !self.held.iter().any(|k| is_wheel(*k)) {
+            self.wheel_ticks = 0;
+        }
+        // Ignore if too many keys held. Will be released.
+        let _ = self.held.push(key);
+    }
+
+    pub fn release(&mut self, key: MouseKey) {
+        match key {
+            MouseKey::Button(bit) => self.buttons &= !(1 << bit),
+            key => self.held.retain(|k| *k != key),
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.held.clear();
+        self.buttons = 0;
+    }
+
+    // Scale movement by analog depth of pressed keys. None for full speed.
+    pub fn set_depth(&mut self, depth: Option<u8>) {
+        self.depth = depth;
+    }
+
+    fn profile(&self) -> &AccelProfile {
+        let idx = self.held.iter().rev().find_map(|k| match k {
+            MouseKey::Profile(idx) => Some(*idx as usize),
+            _ => None,
+        });
+        idx.and_then(|idx| self.profiles.get(idx))
+            .unwrap_or(&self.profiles[0])
+    }
+
+    fn axis(&self, neg: MouseKey, pos: MouseKey) -> i8 {
+        match (self.held.contains(&neg), self.held.contains(&pos)) {
+            (true, false) => -1,
+            (false, true) => 1,
+            _ => 0,
+        }
+    }
+
+    // Call every tick. Return a state to report if changed or moving.
+    pub fn tick(&mut self) -> Option<MouseState> {
+        let mut state = MouseState {
+            buttons: self.buttons,
+            ..Default::default()
+        };
+        let profile = *self.profile();
+
+        let (dx, dy) = (
+            self.axis(MouseKey::Left, MouseKey::Right),
+            self.axis(MouseKey::Up, MouseKey::Down),
+        );
+        if dx != 0 || dy != 0 {
+            let interval = profile.interval.max(1) as u32;
+            if self.move_ticks.is_multiple_of(interval) {
+                let mut speed = profile.speed(self.move_ticks) as u16;
+                if let Some(depth) = self.depth {
+                    speed = (speed * depth as u16 / DEPTH_FULL as u16).max(1);
+                }
+                // Same speed for diagonal. 181 / 256 ~= 1 / sqrt(2)
+                if dx != 0 && dy != 0 {
+                    speed = (speed * 181 / 256).max(1);
+                }
+                let speed = speed.min(i8::MAX as u16) as i8;
+                state.x = dx * speed;
+                state.y = dy * speed;
+            }
+            self.move_ticks += 1;
+        }
+
+        let (pan, wheel) = (
+            self.axis(MouseKey::WheelLeft, MouseKey::WheelRight),
+            self.axis(MouseKey::WheelDown, MouseKey::WheelUp),
+        );
+        if pan != 0 || wheel != 0 {
+            let interval = profile.wheel_interval.max(1) as u32;
+            if self.wheel_ticks.is_multiple_of(interval) {
+                state.pan = pan;
+                state.wheel = wheel;
+            }
+            self.wheel_ticks += 1;
+        }
+
+        let changed = state.buttons != self.last_buttons;
+        self.last_buttons = state.buttons;
+        let moved = state.x != 0 || state.y != 0 || state.wheel != 0 || state.pan != 0;
+        match changed || moved {
+            true => Some(state),
+            false => None,
+        }
+    }
+}
+
+fn is_move(key: MouseKey) -> bool {
+    matches!(
+        key,
+        MouseKey::Up | MouseKey::Down | MouseKey::Left | MouseKey::Right
+    )
+}
+
+fn is_wheel(key: MouseKey) -> bool {
+    matches!(
+        key,
+        MouseKey::WheelUp | MouseKey::WheelDown | MouseKey::WheelLeft | MouseKey::WheelRight
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: AccelProfile = AccelProfile {
+        interval: 10,
+        delay: 0,
+        time_to_max: 100,
+        min_speed: 1,
+        max_speed: 11,
+        curve: AccelCurve::Linear,
+        wheel_interval: 50,
+    };
+    const SLOW: AccelProfile = AccelProfile {
+        min_speed: 1,
+        max_speed: 1,
+        ..PROFILE
+    };
+
+    #[test]
+    fn speed_curves() {
+        assert_eq!(PROFILE.speed(0), 1);
+        assert_eq!(PROFILE.speed(50), 6);
+        assert_eq!(PROFILE.speed(100), 11);
+        assert_eq!(PROFILE.speed(1000), 11);
+
+        let quadratic = AccelProfile {
+            curve: AccelCurve::Quadratic,
+            ..PROFILE
+        };
+        assert_eq!(quadratic.speed(50), 3);
+        assert_eq!(quadratic.speed(100), 11);
+
+        let longest = AccelProfile {
+            time_to_max: u16::MAX,
+            min_speed: 0,
+            max_speed: u8::MAX,
+            ..quadratic
+        };
+        assert_eq!(longest.speed(u16::MAX as u32 / 2), 63);
+        assert_eq!(longest.speed(u16::MAX as u32), 255);
+        assert_eq!(longest.speed(u32::MAX), 255);
+    }
+
+    #[test]
+    fn move_with_interval() {
+        let mut mouse = MouseKeys::new([PROFILE]);
+        mouse.press(MouseKey::Right);
+
+        let first = mouse.tick().unwrap();
+        assert_eq!((first.x, first.y), (1, 0));
+        for _ in 1..10 {
+            assert_eq!(mouse.tick(), None);
+        }
+        assert_eq!(mouse.tick().unwrap().x, 2);
+
+        mouse.release(MouseKey::Right);
+        assert_eq!(mouse.tick(), None);
+    }
+
+    #[test]
+    fn buttons_reported_on_change() {
+        let mut mouse = MouseKeys::new([PROFILE]);
+        mouse.press(MouseKey::Button(0));
+        assert_eq!(mouse.tick().unwrap().buttons, 1);
+        assert_eq!(mouse.tick(), None);
+        mouse.release(MouseKey::Button(0));
+        assert_eq!(mouse.tick().unwrap().buttons, 0);
+    }
+
+    #[test]
+    fn profile_and_depth() {
+        let mut mouse = MouseKeys::new([PROFILE, SLOW]);
+        mouse.press(MouseKey::Profile(1));
+        mouse.press(MouseKey::Down);
+        for _ in 0..100 {
+            if let Some(state) = mouse.tick() {
+                assert_eq!(state.y, 1);
+            }
+        }
+
+        mouse.release(MouseKey::Profile(1));
+        mouse.set_depth(Some(DEPTH_FULL / 2));
+        let mut last = None;
+        for _ in 0..100 {
+            if let Some(state) = mouse.tick() {
+                last = Some(state.y);
+            }
+        }
+        assert_eq!(last, Some(5));
+    }
+
+    #[test]
+    fn wheel() {
+        let mut mouse = MouseKeys::new([PROFILE]);
+        mouse.press(MouseKey::WheelUp);
+        assert_eq!(mouse.tick().unwrap().wheel, 1);
+        let steps = (0..99).filter_map(|_| mouse.tick()).count();
+        assert_eq!(steps, 1);
+    }
+}
@@ -0,0 +1,299 @@
+use heapless::Vec;
+
+use crate::combo::Key;
+use crate::event::Event;
+use crate::stage::{Bindings, Stage};
+
+// One-shot keys active at once.
+const MAX_ACTIVE: usize = 4;
+
+// A tap holds `key` of the layout until the next key is pressed, usually a modifier or layer
+// on a row without switches. Held, it acts as `key` held.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OneShot {
+    pub key: Key,
+    // Released unused after this.
+    pub timeout_ms: u16,
+    // Tapped again while waiting, held until the next tap. Otherwise the second tap cancels.
+    pub lock: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // `used` once another key is pressed meanwhile.
+    Held { used: bool },
+    // Tapped, waiting for the next key.
+    Waiting { deadline: u64 },
+    Locked,
+}
+
+#[derive(Debug)]
+struct Active {
+    one_shot: usize,
+    // Key bound to the one-shot.
+    trigger: Key,
+    state: State,
+}
+
+// Keys bound to a one-shot press its key on press, and release it after the next press of
+// another key. Several one-shots stack up to MAX_ACTIVE.
+pub struct OneShots<'a> {
+    one_shots: &'a [OneShot],
+    active: Vec<Active, MAX_ACTIVE>,
+    // Presses which locked or released a one-shot. Their release is dropped.
+    swallowed: Vec<Key, MAX_ACTIVE>,
+}
+
+impl<'a> OneShots<'a> {
+    pub fn new(one_shots: &'a [OneShot]) -> Self {
+        Self {
+            one_shots,
+            active: Vec::new(),
+            swallowed: Vec::new(),
+        }
+    }
+
+    fn press(&mut self, key: Key, one_shot: Option<u8>, out: &mut impl FnMut(Event)) {
+        let one_shot = one_shot
+            .map(usize::from)
+            .filter(|o| *o < self.one_shots.len());
+        let Some(one_shot) = one_shot else {
+            out(Event::KeyPress(key.0, key.1));
+            for active in self.active.iter_mut() {
+                if let State::Held { used } = &mut active.state {
+                    *used = true;
+                }
+            }
+            return self.release_where(out, |state| matches!(state, State::Waiting { .. }));
+        };
+
+        match self.active.iter().position(|a| a.one_shot == one_shot) {
+            Some(index) => {
+                let active = &mut self.active[index];
+                match active.state {
+                    State::Waiting { .. } if self.one_shots[one_shot].lock => {
+                        active.state = State::Locked;
+                    }
+                    State::Held { .. } => {}
+                    _ => {
+                        let (i, j) = self.one_shots[one_shot].key;
+                        out(Event::KeyRelease(i, j));
+                        self.active.swap_remove(index);
+                    }
+                }
+                let _ = self.swallowed.push(key);
+            }
+            None => {
+                let active = Active {
+                    one_shot,
+                    trigger: key,
+                    state: State::Held { used: false },
+                };
+                match self.active.push(active) {
+                    Ok(()) => {
+                        let (i, j) = self.one_shots[one_shot].key;
+                        out(Event::KeyPress(i, j));
+                    }
+                    Err(_) => {
+                        let _ = self.swallowed.push(key);
+                    }
+                }
+            }
+        }
+    }
+
+    fn release(&mut self, key: Key, now_ms: u64, out: &mut impl FnMut(Event)) {
+        if let Some(index) = self.swallowed.iter().position(|k| *k == key) {
+            self.swallowed.swap_remove(index);
+            return;
+        }
+
+        let held = self
+            .active
+            .iter()
+            .position(|a| a.trigger == key && matches!(a.state, State::Held { .. }));
+        let Some(index) = held else {
+            return out(Event::KeyRelease(key.0, key.1));
+        };
+        let active = &mut self.active[index];
+        let one_shot = &self.one_shots[active.one_shot];
+        match active.state {
+            // Acted as the key held.
+            State::Held { used: true } => {
+                out(Event::KeyRelease(one_shot.key.0, one_shot.key.1));
+                self.active.swap_remove(index);
+            }
+            _ => {
+                active.state = State::Waiting {
+                    deadline: now_ms + one_shot.timeout_ms as u64,
+                }
+            }
+        }
+    }
+
+    fn release_where(&mut self, out: &mut impl FnMut(Event), f: impl Fn(State) -> bool) {
+        let one_shots = self.one_shots;
+        self.active.retain(|active| {
+            if !f(active.state) {
+                return true;
+            }
+            let (i, j) = one_shots[active.one_shot].key;
+            out(Event::KeyRelease(i, j));
+            false
+        });
+    }
+}
+
+impl Stage for OneShots<'_> {
+    // The one-shot of a key is looked up on press.
+    fn event(
+        &mut self,
+        event: Event,
+        now_ms: u64,
+        bindings: &impl Bindings,
+        out: &mut impl FnMut(Event),
+    ) {
+        match event {
+            Event::KeyPress(i, j) => self.press((i, j), bindings.one_shot((i, j)), out),
+            Event::KeyRelease(i, j) => self.release((i, j), now_ms, out),
+            Event::None => {}
+        }
+    }
+
+    // One-shots not used in time are released.
+    fn tick(&mut self, now_ms: u64, _: &impl Bindings, out: &mut impl FnMut(Event)) {
+        self.release_where(
+            out,
+            |state| matches!(state, State::Waiting { deadline } if now_ms >= deadline),
+        );
+    }
+
+    fn deadline(&self) -> Option<u64> {
+        self.active
+            .iter()
+            .filter_map(|a| match a.state {
+                State::Waiting { deadline } => Some(deadline),
+                _ => None,
+            })
+            .min()
+    }
+
+    fn reset(&mut self) {
+        self.active.clear();
+        self.swallowed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage::test::{run, tick, TestBindings};
+    use Event::{KeyPress as P, KeyRelease as R};
+
+    // Shift and layer 1 on the virtual row 5.
+    static ONE_SHOTS: [OneShot; 2] = [
+        OneShot {
+            key: (5, 0),
+            timeout_ms: 1000,
+            lock: true,
+        },
+        OneShot {
+            key: (5, 1),
+            timeout_ms: 500,
+            lock: false,
+        },
+    ];
+
+    // Shift on (3, 0), layer 1 on (4, 5).
+    static BINDINGS: TestBindings = TestBindings {
+        layer: 0,
+        tap_dances: &[],
+        one_shots: &[((3, 0), 0), ((4, 5), 1)],
+    };
+
+    #[test]
+    fn applies_to_next_key() {
+        let mut one_shots = OneShots::new(&ONE_SHOTS);
+        let out = run(&mut one_shots, &BINDINGS, &[(P(3, 0), 0), (R(3, 0), 50)]);
+        assert_eq!(out, [P(5, 0)]);
+        assert_eq!(one_shots.deadline(), Some(1050));
+
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[(P(1, 1), 300), (P(1, 2), 310), (R(1, 1), 320)],
+        );
+        assert_eq!(out, [P(1, 1), R(5, 0), P(1, 2), R(1, 1)]);
+    }
+
+    #[test]
+    fn stacks_and_times_out() {
+        let mut one_shots = OneShots::new(&ONE_SHOTS);
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[(P(3, 0), 0), (R(3, 0), 10), (P(4, 5), 20), (R(4, 5), 30)],
+        );
+        assert_eq!(out, [P(5, 0), P(5, 1)]);
+        assert_eq!(one_shots.deadline(), Some(530));
+        assert_eq!(tick(&mut one_shots, &BINDINGS, 530).as_slice(), [R(5, 1)]);
+
+        let out = run(&mut one_shots, &BINDINGS, &[(P(1, 1), 600)]);
+        assert_eq!(out, [P(1, 1), R(5, 0)]);
+        assert_eq!(one_shots.deadline(), None);
+    }
+
+    #[test]
+    fn held_acts_as_key() {
+        let mut one_shots = OneShots::new(&ONE_SHOTS);
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[(P(3, 0), 0), (P(1, 1), 10), (R(1, 1), 20), (R(3, 0), 30)],
+        );
+        assert_eq!(out, [P(5, 0), P(1, 1), R(1, 1), R(5, 0)]);
+        let out = run(&mut one_shots, &BINDINGS, &[(P(1, 2), 40)]);
+        assert_eq!(out, [P(1, 2)]);
+    }
+
+    #[test]
+    fn double_tap_locks() {
+        let mut one_shots = OneShots::new(&ONE_SHOTS);
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[(P(3, 0), 0), (R(3, 0), 10), (P(3, 0), 20), (R(3, 0), 30)],
+        );
+        assert_eq!(out, [P(5, 0)]);
+        assert_eq!(one_shots.deadline(), None);
+
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[(P(1, 1), 5000), (R(1, 1), 5010)],
+        );
+        assert_eq!(out, [P(1, 1), R(1, 1)], "locked past the timeout");
+
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[(P(3, 0), 6000), (R(3, 0), 6010)],
+        );
+        assert_eq!(out, [R(5, 0)]);
+
+        // Without lock the second tap cancels.
+        let out = run(
+            &mut one_shots,
+            &BINDINGS,
+            &[
+                (P(4, 5), 7000),
+                (R(4, 5), 7010),
+                (P(4, 5), 7020),
+                (R(4, 5), 7030),
+            ],
+        );
+        assert_eq!(out, [P(5, 1), R(5, 1)]);
+        let out = run(&mut one_shots, &BINDINGS, &[(P(1, 1), 7040)]);
+        assert_eq!(out, [P(1, 1)]);
+    }
+}
@@ -0,0 +1,164 @@
+use keyberon::key_code::KeyCode;
+
+// Keyboard usages below modifiers. 0xE8 and above are not standard keyboard usages.
+pub const NKRO_KEYS: usize = 0xE0;
+// | modifiers(1) | bitmap of usage 0x00..NKRO_KEYS |
+pub const NKRO_REPORT_SIZE: usize = 1 + NKRO_KEYS / 8;
+
+// N-key rollover keyboard. Same modifiers as boot keyboard, keys as bitmap.
+// LEDs are set through the boot keyboard interface.
+#[rustfmt::skip]
+pub const NKRO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop)
+    0x09, 0x06,        // Usage (Keyboard)
+    0xA1, 0x01,        // Collection (Application)
+    // Modifiers
+    0x05, 0x07,        //   Usage Page (Keyboard/Keypad)
+    0x19, 0xE0,        //   Usage Minimum (Left Control)
+    0x29, 0xE7,        //   Usage Maximum (Right GUI)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x25, 0x01,        //   Logical Maximum (1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, 0x08,        //   Report Count (8)
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    // Keys
+    0x05, 0x07,        //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,        //   Usage Minimum (0)
+    0x29, (NKRO_KEYS - 1) as u8, //   Usage Maximum (NKRO_KEYS - 1)
+    0x75, 0x01,        //   Report Size (1)
+    0x95, NKRO_KEYS as u8,       //   Report Count (NKRO_KEYS)
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0xC0,              // End Collection
+];
+
+// System control and consumer control on one interface, told apart by report id.
+// | report id(1) | usage(2, little endian) |
+pub const EXTRA_REPORT_SIZE: usize = 3;
+pub const SYSTEM_REPORT_ID: u8 = 1;
+pub const CONSUMER_REPORT_ID: u8 = 2;
+
+#[rustfmt::skip]
+pub const EXTRA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,        // Usage Page (Generic Desktop)
+    0x09, 0x80,        // Usage (System Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, SYSTEM_REPORT_ID, //   Report ID (SYSTEM_REPORT_ID)
+    0x19, 0x01,        //   Usage Minimum (1)
+    0x2A, 0xB7, 0x00,  //   Usage Maximum (0xB7)
+    0x15, 0x01,        //   Logical Minimum (1)
+    0x26, 0xB7, 0x00,  //   Logical Maximum (0xB7)
+    0x75, 0x10,        //   Report Size (16)
+    0x95, 0x01,        //   Report Count (1)
+    0x81, 0x00,        //   Input (Data, Array, Absolute)
+    0xC0,              // End Collection
+    0x05, 0x0C,        // Usage Page (Consumer)
+    0x09, 0x01,        // Usage (Consumer Control)
+    0xA1, 0x01,        // Collection (Application)
+    0x85, CONSUMER_REPORT_ID, //   Report ID (CONSUMER_REPORT_ID)
+    0x19, 0x01,        //   Usage Minimum (1)
+    0x2A, 0xA0, 0x02,  //   Usage Maximum (0x2A0)
+    0x15, 0x01,        //   Logical Minimum (1)
+    0x26, 0xA0, 0x02,  //   Logical Maximum (0x2A0)
+    0x75, 0x10,        //   Report Size (16)
+    0x95, 0x01,        //   Report Count (1)
+    0x81, 0x00,        //   Input (Data, Array, Absolute)
+    0xC0,              // End Collection
+];
+
+// Usage 0 releases the key.
+pub fn extra_report(report_id: u8, usage: u16) -> [u8; EXTRA_REPORT_SIZE] {
+    let [lo, hi] = usage.to_le_bytes();
+    [report_id, lo, hi]
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NkroReport([u8; NKRO_REPORT_SIZE]);
+
+impl Default for NkroReport {
+    fn default() -> Self {
+        Self([0u8; NKRO_REPORT_SIZE])
+    }
+}
+
+impl NkroReport {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn pressed(&mut self, kc: KeyCode) {
+        let code = kc as u8;
+        match code {
+            // Modifiers
+            0xE0..=0xE7 => self.0[0] |= 1 << (code - 0xE0),
+            // No event, ErrorRollOver, POSTFail, ErrorUndefined
+            0x00..=0x03 => {}
+            code if (code as usize) < NKRO_KEYS => {
+                self.0[1 + code as usize / 8] |= 1 << (code % 8);
+            }
+            // Media keys are reported by consumer control.
+            _ => {}
+        }
+    }
+}
+
+impl core::iter::FromIterator<KeyCode> for NkroReport {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = KeyCode>,
+    {
+        let mut report = Self::default();
+        for kc in iter {
+            report.pressed(kc);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modifiers_and_keys() {
+        let report: NkroReport = [KeyCode::LShift, KeyCode::A, KeyCode::RGui]
+            .into_iter()
+            .collect();
+        let bytes = report.as_bytes();
+        assert_eq!(bytes[0], 0b1000_0010);
+        assert_eq!(bytes[1], 1 << 4);
+        assert!(bytes[2..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn more_than_six_keys() {
+        let keys = [
+            KeyCode::A,
+            KeyCode::B,
+            KeyCode::C,
+            KeyCode::D,
+            KeyCode::E,
+            KeyCode::F,
+            KeyCode::G,
+            KeyCode::H,
+        ];
+        let report: NkroReport = keys.into_iter().collect();
+        let pressed = report.as_bytes()[1..]
+            .iter()
+            .map(|b| b.count_ones())
+            .sum::<u32>();
+        assert_eq!(pressed, keys.len() as u32);
+    }
+
+    #[test]
+    fn extra_reports() {
+        assert_eq!(extra_report(SYSTEM_REPORT_ID, 0x82), [1, 0x82, 0x00]);
+        assert_eq!(extra_report(CONSUMER_REPORT_ID, 0x0CD), [2, 0xCD, 0x00]);
+        assert_eq!(extra_report(CONSUMER_REPORT_ID, 0), [2, 0, 0]);
+    }
+
+    #[test]
+    fn media_keys_ignored() {
+        let report: NkroReport = [KeyCode::MediaPlayPause].into_iter().collect();
+        assert_eq!(report, NkroReport::default());
+    }
+}
@@ -110,6 +110,22 @@ where
         &self.values
     }
 
+    pub fn debounce(&self) -> u8 {
+        self.debouncer.nb_bounce()
+    }
+
+    pub fn set_debounce(&mut self, nb_bounce: u8) {
+        self.debouncer.set_nb_bounce(nb_bounce);
+    }
+
+    pub fn thresholds(&self) -> &[[RX::AdcUnit; RXSIZE]; TXSIZE] {
+        &self.thresholds
+    }
+
+    pub fn set_thresholds(&mut self, thresholds: [[RX::AdcUnit; RXSIZE]; TXSIZE]) {
+        self.thresholds = thresholds;
+    }
+
     //discharge all lines for inital bounding.
     pub fn dischage_all(&mut self) {
         for rx_idx in 0..RXSIZE {
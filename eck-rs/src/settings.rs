@@ -0,0 +1,580 @@
+// Key-value settings log on NOR flash.
+//
+// The region is split in pages used round-robin. Only one page is active. Records are
+// appended to it and the latest record of a key wins. When the active page is full, live
+// records are copied to the next page and the page header is written last, so a power
+// loss at any point leaves the previous page in use.
+//
+// Page:   | magic(4) | sequence(4) | record | record | ... | erased |
+// Record: | key(2) | len(2) | crc16(2) | version(1) | flags(1) | data(len) | padding |
+use embedded_storage::nor_flash::NorFlash;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::crc::{crc16, crc16_update};
+
+const PAGE_MAGIC: [u8; 4] = *b"ECKS";
+const PAGE_HEADER_SIZE: u32 = 8;
+const RECORD_HEADER_SIZE: usize = 8;
+const ERASED_KEY: u16 = 0xffff;
+const FLAG_VALUE: u8 = 0xff;
+const FLAG_REMOVED: u8 = 0x00;
+// Records are copied in chunks of this size. Must be a multiple of the write size.
+const COPY_CHUNK: usize = 64;
+
+// Largest value serialized in one record.
+pub const MAX_VALUE_SIZE: usize = 1024;
+
+// Identifies a setting. Records of other version are ignored, e.g. after a format change.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    pub id: u16,
+    pub version: u8,
+}
+
+impl Key {
+    pub const fn new(id: u16, version: u8) -> Self {
+        assert!(id != ERASED_KEY);
+        Self { id, version }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, PartialEq, Eq)]
+pub enum Error<E> {
+    Flash(E),
+    // Live records don't fit in a page.
+    Full,
+    TooLarge,
+    Serialize,
+}
+
+impl<E> From<E> for Error<E> {
+    fn from(e: E) -> Self {
+        Error::Flash(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RecordHeader {
+    key: u16,
+    len: u16,
+    crc: u16,
+    version: u8,
+    flags: u8,
+}
+
+impl RecordHeader {
+    fn from_bytes(buf: &[u8; RECORD_HEADER_SIZE]) -> Self {
+        Self {
+            key: u16::from_le_bytes([buf[0], buf[1]]),
+            len: u16::from_le_bytes([buf[2], buf[3]]),
+            crc: u16::from_le_bytes([buf[4], buf[5]]),
+            version: buf[6],
+            flags: buf[7],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_HEADER_SIZE] {
+        let mut buf = [0u8; RECORD_HEADER_SIZE];
+        buf[0..2].copy_from_slice(&self.key.to_le_bytes());
+        buf[2..4].copy_from_slice(&self.len.to_le_bytes());
+        buf[4..6].copy_from_slice(&self.crc.to_le_bytes());
+        buf[6] = self.version;
+        buf[7] = self.flags;
+        buf
+    }
+
+    fn is_erased(&self) -> bool {
+        self.to_bytes().iter().all(|b| *b == 0xff)
+    }
+
+    fn crc(key: u16, version: u8, flags: u8, data: &[u8]) -> u16 {
+        let [k0, k1] = key.to_le_bytes();
+        crc16_update(crc16(&[k0, k1, version, flags]), data)
+    }
+}
+
+pub struct Settings<F: NorFlash> {
+    flash: F,
+    start: u32,
+    page_size: u32,
+    pages: u32,
+    active: u32,
+    sequence: u32,
+    // Next record offset in the active page. page_size if unusable.
+    free: u32,
+    buf: [u8; MAX_VALUE_SIZE],
+}
+
+impl<F: NorFlash> Settings<F> {
+    // Use `pages` pages of `page_size` from `start`. Page size must be a multiple of the
+    // erase size. An empty region is formatted.
+    pub fn mount(
+        flash: F,
+        start: u32,
+        page_size: u32,
+        pages: u32,
+    ) -> Result<Self, Error<F::Error>> {
+        assert!(pages >= 2);
+        assert!(page_size.is_multiple_of(F::ERASE_SIZE as u32));
+        assert!(COPY_CHUNK.is_multiple_of(F::WRITE_SIZE));
+        assert!(RECORD_HEADER_SIZE.is_multiple_of(F::WRITE_SIZE));
+
+        let mut settings = Self {
+            flash,
+            start,
+            page_size,
+            pages,
+            active: 0,
+            sequence: 0,
+            free: page_size,
+            buf: [0; MAX_VALUE_SIZE],
+        };
+
+        let mut latest = None;
+        for page in 0..pages {
+            if let Some(sequence) = settings.page_sequence(page)? {
+                if latest.is_none_or(|(_, s)| sequence > s) {
+                    latest = Some((page, sequence));
+                }
+            }
+        }
+
+        match latest {
+            Some((page, sequence)) => {
+                settings.active = page;
+                settings.sequence = sequence;
+                settings.free = settings.find_free()?;
+            }
+            None => settings.format(0, 1)?,
+        }
+        Ok(settings)
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    // Latest value of the key. None if not stored, removed or stored in other version.
+    pub fn fetch<T: DeserializeOwned>(&mut self, key: Key) -> Result<Option<T>, Error<F::Error>> {
+        let Some(len) = self.read(key)? else {
+            return Ok(None);
+        };
+        // Trailing data means the value was stored as another type.
+        match postcard::take_from_bytes(&self.buf[..len]) {
+            Ok((value, [])) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    // Store the value. Nothing is written if it's unchanged.
+    pub fn store<T: Serialize + ?Sized>(
+        &mut self,
+        key: Key,
+        value: &T,
+    ) -> Result<(), Error<F::Error>> {
+        let mut data = [0u8; MAX_VALUE_SIZE];
+        let len = postcard::to_slice(value, &mut data)
+            .map_err(|e| match e {
+                postcard::Error::SerializeBufferFull => Error::TooLarge,
+                _ => Error::Serialize,
+            })?
+            .len();
+
+        if self
+            .read(key)?
+            .is_some_and(|l| self.buf[..l] == data[..len])
+        {
+            return Ok(());
+        }
+        self.append(key, FLAG_VALUE, &data[..len])
+    }
+
+    pub fn remove(&mut self, key: Key) -> Result<(), Error<F::Error>> {
+        if self.find(key.id)?.is_none() {
+            return Ok(());
+        }
+        self.append(key, FLAG_REMOVED, &[])
+    }
+
+    // Erase every page. Settings fall back to defaults.
+    pub fn clear(&mut self) -> Result<(), Error<F::Error>> {
+        let next = (self.active + 1) % self.pages;
+        self.format(next, self.sequence + 1)?;
+        for page in (0..self.pages).filter(|p| *p != next) {
+            self.erase(page)?;
+        }
+        Ok(())
+    }
+
+    fn page_start(&self, page: u32) -> u32 {
+        self.start + page * self.page_size
+    }
+
+    fn page_sequence(&mut self, page: u32) -> Result<Option<u32>, F::Error> {
+        let mut header = [0u8; PAGE_HEADER_SIZE as usize];
+        self.flash.read(self.page_start(page), &mut header)?;
+        if header[..4] != PAGE_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ])))
+    }
+
+    fn erase(&mut self, page: u32) -> Result<(), F::Error> {
+        let start = self.page_start(page);
+        self.flash.erase(start, start + self.page_size)
+    }
+
+    fn format(&mut self, page: u32, sequence: u32) -> Result<(), F::Error> {
+        self.erase(page)?;
+        self.write_page_header(page, sequence)?;
+        self.active = page;
+        self.sequence = sequence;
+        self.free = PAGE_HEADER_SIZE;
+        Ok(())
+    }
+
+    fn write_page_header(&mut self, page: u32, sequence: u32) -> Result<(), F::Error> {
+        let mut header = [0u8; PAGE_HEADER_SIZE as usize];
+        header[..4].copy_from_slice(&PAGE_MAGIC);
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+        self.flash.write(self.page_start(page), &header)
+    }
+
+    fn record_size(len: u16) -> u32 {
+        let size = RECORD_HEADER_SIZE + len as usize;
+        (size.div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE) as u32
+    }
+
+    // Header of a valid record at offset of the page. Data is left in buf.
+    fn read_record(&mut self, page: u32, offset: u32) -> Result<Option<RecordHeader>, F::Error> {
+        if offset + RECORD_HEADER_SIZE as u32 > self.page_size {
+            return Ok(None);
+        }
+
+        let address = self.page_start(page) + offset;
+        let mut bytes = [0u8; RECORD_HEADER_SIZE];
+        self.flash.read(address, &mut bytes)?;
+        let header = RecordHeader::from_bytes(&bytes);
+        let len = header.len as usize;
+        if header.key == ERASED_KEY
+            || len > MAX_VALUE_SIZE
+            || offset + Self::record_size(header.len) > self.page_size
+        {
+            return Ok(None);
+        }
+
+        let data = &mut self.buf[..len];
+        self.flash.read(address + RECORD_HEADER_SIZE as u32, data)?;
+        if RecordHeader::crc(header.key, header.version, header.flags, data) != header.crc {
+            return Ok(None);
+        }
+        Ok(Some(header))
+    }
+
+    // End of the records in the active page.
+    fn find_free(&mut self) -> Result<u32, F::Error> {
+        let mut offset = PAGE_HEADER_SIZE;
+        while let Some(header) = self.read_record(self.active, offset)? {
+            offset += Self::record_size(header.len);
+        }
+
+        // Interrupted write. Don't append after it, the next write compacts the page.
+        let mut bytes = [0u8; RECORD_HEADER_SIZE];
+        if offset + RECORD_HEADER_SIZE as u32 <= self.page_size {
+            self.flash
+                .read(self.page_start(self.active) + offset, &mut bytes)?;
+            if !RecordHeader::from_bytes(&bytes).is_erased() {
+                return Ok(self.page_size);
+            }
+        }
+        Ok(offset)
+    }
+
+    // Offset and header of the latest record of the key in the active page.
+    fn find(&mut self, key: u16) -> Result<Option<(u32, RecordHeader)>, F::Error> {
+        let mut found = None;
+        let mut offset = PAGE_HEADER_SIZE;
+        while offset < self.free {
+            let Some(header) = self.read_record(self.active, offset)? else {
+                break;
+            };
+            if header.key == key {
+                found = Some((offset, header));
+            }
+            offset += Self::record_size(header.len);
+        }
+        Ok(found.filter(|(_, h)| h.flags != FLAG_REMOVED))
+    }
+
+    // Read the latest value of the key to buf.
+    fn read(&mut self, key: Key) -> Result<Option<usize>, F::Error> {
+        let Some((offset, header)) = self.find(key.id)? else {
+            return Ok(None);
+        };
+        if header.version != key.version {
+            return Ok(None);
+        }
+        self.read_record(self.active, offset)?;
+        Ok(Some(header.len as usize))
+    }
+
+    fn append(&mut self, key: Key, flags: u8, data: &[u8]) -> Result<(), Error<F::Error>> {
+        let len = data.len() as u16;
+        if self.free + Self::record_size(len) > self.page_size {
+            self.compact()?;
+            if self.free + Self::record_size(len) > self.page_size {
+                return Err(Error::Full);
+            }
+        }
+
+        let header = RecordHeader {
+            key: key.id,
+            len,
+            crc: RecordHeader::crc(key.id, key.version, flags, data),
+            version: key.version,
+            flags,
+        };
+
+        // Write the record in aligned chunks. Padding stays erased.
+        let address = self.page_start(self.active) + self.free;
+        self.flash.write(address, &header.to_bytes())?;
+        let mut written = 0;
+        while written < data.len() {
+            let mut chunk = [0xffu8; COPY_CHUNK];
+            let size = (data.len() - written).min(COPY_CHUNK);
+            chunk[..size].copy_from_slice(&data[written..written + size]);
+            let aligned = size.div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE;
+            let offset = (RECORD_HEADER_SIZE + written) as u32;
+            self.flash.write(address + offset, &chunk[..aligned])?;
+            written += size;
+        }
+
+        self.free += Self::record_size(len);
+        Ok(())
+    }
+
+    // Copy live records to the next page.
+    fn compact(&mut self) -> Result<(), F::Error> {
+        let (from, from_free) = (self.active, self.free.min(self.page_size));
+        let to = (from + 1) % self.pages;
+        self.erase(to)?;
+
+        let mut offset = PAGE_HEADER_SIZE;
+        let mut to_offset = PAGE_HEADER_SIZE;
+        while offset < from_free {
+            let Some(header) = self.read_record(from, offset)? else {
+                break;
+            };
+            let size = Self::record_size(header.len);
+            if header.flags != FLAG_REMOVED && self.is_latest(from, offset + size, header.key)? {
+                self.copy(
+                    self.page_start(from) + offset,
+                    self.page_start(to) + to_offset,
+                    size,
+                )?;
+                to_offset += size;
+            }
+            offset += size;
+        }
+
+        // Written last. The page is ignored until here.
+        self.write_page_header(to, self.sequence + 1)?;
+        self.active = to;
+        self.sequence += 1;
+        self.free = to_offset;
+        Ok(())
+    }
+
+    // No record of the key from offset.
+    fn is_latest(&mut self, page: u32, mut next: u32, key: u16) -> Result<bool, F::Error> {
+        while let Some(header) = self.read_record(page, next)? {
+            if header.key == key {
+                return Ok(false);
+            }
+            next += Self::record_size(header.len);
+        }
+        Ok(true)
+    }
+
+    fn copy(&mut self, from: u32, to: u32, size: u32) -> Result<(), F::Error> {
+        let mut chunk = [0u8; COPY_CHUNK];
+        let mut copied = 0;
+        while copied < size {
+            let len = ((size - copied) as usize).min(COPY_CHUNK);
+            self.flash.read(from + copied, &mut chunk[..len])?;
+            self.flash.write(to + copied, &chunk[..len])?;
+            copied += len as u32;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+    const PAGE_SIZE: u32 = 256;
+    const PAGES: u32 = 3;
+    const A: Key = Key::new(1, 1);
+    const B: Key = Key::new(2, 1);
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct PowerLoss;
+
+    impl NorFlashError for PowerLoss {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    // NOR flash in RAM. Writes only clear bits. Power is lost after `budget` writes.
+    struct RamFlash {
+        data: [u8; (PAGE_SIZE * PAGES) as usize],
+        erases: [u32; PAGES as usize],
+        budget: Option<usize>,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                data: [0xff; (PAGE_SIZE * PAGES) as usize],
+                erases: [0; PAGES as usize],
+                budget: None,
+            }
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = PowerLoss;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 8;
+        const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            for page in from / PAGE_SIZE..to / PAGE_SIZE {
+                self.erases[page as usize] += 1;
+            }
+            self.data[from as usize..to as usize].fill(0xff);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            assert_eq!(offset as usize % Self::WRITE_SIZE, 0);
+            assert_eq!(bytes.len() % Self::WRITE_SIZE, 0);
+            if let Some(budget) = self.budget.as_mut() {
+                if *budget == 0 {
+                    return Err(PowerLoss);
+                }
+                *budget -= 1;
+            }
+            for (i, byte) in bytes.iter().enumerate() {
+                self.data[offset as usize + i] &= byte;
+            }
+            Ok(())
+        }
+    }
+
+    fn mount(flash: RamFlash) -> Settings<RamFlash> {
+        Settings::mount(flash, 0, PAGE_SIZE, PAGES).unwrap()
+    }
+
+    #[test]
+    fn store_and_fetch() {
+        let mut settings = mount(RamFlash::new());
+        assert_eq!(settings.fetch::<u32>(A), Ok(None));
+
+        settings.store(A, &1234u32).unwrap();
+        settings.store(B, &[1u16, 2, 3]).unwrap();
+        settings.store(A, &5678u32).unwrap();
+        assert_eq!(settings.fetch::<u32>(A), Ok(Some(5678)));
+
+        // Survives reboot.
+        let mut settings = mount(settings.release());
+        assert_eq!(settings.fetch::<u32>(A), Ok(Some(5678)));
+        assert_eq!(settings.fetch::<[u16; 3]>(B), Ok(Some([1, 2, 3])));
+
+        settings.remove(A).unwrap();
+        assert_eq!(settings.fetch::<u32>(A), Ok(None));
+        assert_eq!(settings.fetch::<u32>(Key::new(2, 2)), Ok(None));
+        assert_eq!(settings.fetch::<[u16; 2]>(B), Ok(None));
+    }
+
+    #[test]
+    fn wear_leveling() {
+        let mut settings = mount(RamFlash::new());
+        settings.store(B, &[7u8; 32]).unwrap();
+        for i in 0..300u32 {
+            settings.store(A, &i).unwrap();
+        }
+        assert_eq!(settings.fetch::<u32>(A), Ok(Some(299)));
+        assert_eq!(settings.fetch::<[u8; 32]>(B), Ok(Some([7; 32])));
+
+        let flash = settings.release();
+        let (min, max) = (flash.erases.iter().min(), flash.erases.iter().max());
+        assert!(max.unwrap() - min.unwrap() <= 1, "{:?}", flash.erases);
+    }
+
+    #[test]
+    fn too_large() {
+        let mut settings = mount(RamFlash::new());
+        assert_eq!(settings.store(A, &[0u8; 32]), Ok(()));
+        assert_eq!(settings.store(B, &[0u8; 250][..]), Err(Error::Full));
+        assert_eq!(settings.fetch::<[u8; 32]>(A), Ok(Some([0; 32])));
+    }
+
+    #[test]
+    fn power_loss() {
+        // Cut the power at every write while values are rewritten.
+        for budget in 0..120 {
+            let mut settings = mount(RamFlash::new());
+            settings.store(B, &0xabu8).unwrap();
+            settings.flash.budget = Some(budget);
+
+            let mut stored = None;
+            for i in 0..60u32 {
+                match settings.store(A, &[i; 4]) {
+                    Ok(()) => stored = Some(i),
+                    Err(_) => break,
+                }
+            }
+
+            let mut flash = settings.release();
+            flash.budget = None;
+            let mut settings = mount(flash);
+            let value = settings.fetch::<[u32; 4]>(A).unwrap();
+            // The last value or the one being written.
+            assert!(
+                value.map(|v| v[0]) == stored
+                    || value.map(|v| v[0]) == stored.map(|s| s + 1).or(Some(0)),
+                "budget {}: {:?} {:?}",
+                budget,
+                value,
+                stored
+            );
+            assert_eq!(settings.fetch::<u8>(B), Ok(Some(0xab)));
+
+            // Still writable.
+            settings.store(A, &[99u32; 4]).unwrap();
+            assert_eq!(settings.fetch::<[u32; 4]>(A), Ok(Some([99; 4])));
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// Wire format of the split link. Independent of the HAL to be tested on the host.
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+use crate::crc::crc16;
+use crate::error::KeyboardError;
+use crate::event::Event;
+
+// Bump it on any incompatible change of `Message`.
+pub const PROTOCOL_VERSION: u16 = 7;
+
+pub const MAX_ANALOG_VALUES: usize = 8;
+
+// Settings changed on the master which apply to both halves.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSync {
+    Debounce(u8),
+}
+
+// Steps of calibrating the slave half. The master types them to the host.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationCue {
+    Release,
+    PressAll,
+    Canceled,
+    Saved,
+    SaveFailed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    // Sent while the link is down. `link_up` asks the other half to reply.
+    Hello {
+        version: u16,
+        link_up: bool,
+    },
+    // Advertise USB power and role of the sender for master election.
+    Heartbeat {
+        usb: bool,
+        master: bool,
+    },
+    // Cumulative ack of sequenced messages.
+    Ack(u8),
+    // Sender (re)started its sequence.
+    Sync(u8),
+    Key(u8, Event),
+    Config(u8, ConfigSync),
+    // Raw adc values of a tx line.
+    Analog {
+        tx: u8,
+        values: Vec<u16, MAX_ANALOG_VALUES>,
+    },
+    // Thresholds of a tx line. Sent with Analog for monitoring.
+    Thresholds {
+        tx: u8,
+        values: Vec<u16, MAX_ANALOG_VALUES>,
+    },
+    // Master state pushed to the slave. `monitor` asks the slave to send raw values.
+    State {
+        layer: u8,
+        leds: u8,
+        suspended: bool,
+        monitor: bool,
+    },
+    // Deepest press of mouse movement keys on the slave, None if none of them is pressed.
+    // Sent on change and with heartbeat. A lost one is repaired in a period.
+    MouseDepth(Option<u8>),
+    Cue(u8, CalibrationCue),
+}
+
+impl Message {
+    // Sequenced messages are acked and retransmitted.
+    pub fn seq(&self) -> Option<u8> {
+        match self {
+            Message::Sync(seq)
+            | Message::Key(seq, _)
+            | Message::Config(seq, _)
+            | Message::Cue(seq, _) => Some(*seq),
+            _ => None,
+        }
+    }
+}
+
+const SEQ_WINDOW: u8 = 0x80;
+
+pub fn next_seq(seq: u8) -> u8 {
+    seq.wrapping_add(1)
+}
+
+pub fn prev_seq(seq: u8) -> u8 {
+    seq.wrapping_sub(1)
+}
+
+// true if `seq` is `base` or sent before `base`.
+pub fn seq_not_after(seq: u8, base: u8) -> bool {
+    base.wrapping_sub(seq) < SEQ_WINDOW
+}
+
+// Frame: COBS([postcard(Message), crc16]) + 0x00
+pub const MAX_FRAME_SIZE: usize = 32;
+const CRC_SIZE: usize = 2;
+// COBS overhead byte and delimiter.
+const MAX_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - CRC_SIZE - 2;
+const FRAME_DELIMITER: u8 = 0x00;
+
+pub type FrameBuf = Vec<u8, MAX_FRAME_SIZE>;
+
+pub fn encode(msg: &Message) -> Result<FrameBuf, KeyboardError> {
+    let mut raw = [0u8; MAX_PAYLOAD_SIZE + CRC_SIZE];
+    let len = postcard::to_slice(msg, &mut raw[..MAX_PAYLOAD_SIZE])
+        .map_err(|_| KeyboardError::Serialize)?
+        .len();
+    let crc = crc16(&raw[..len]);
+    raw[len..len + CRC_SIZE].copy_from_slice(&crc.to_le_bytes());
+
+    let mut frame = [0u8; MAX_FRAME_SIZE];
+    let encoded = cobs::encode(&raw[..len + CRC_SIZE], &mut frame);
+    frame[encoded] = FRAME_DELIMITER;
+    FrameBuf::from_slice(&frame[..encoded + 1]).map_err(|_| KeyboardError::BufferOverflow)
+}
+
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: FrameBuf,
+    overflow: bool,
+}
+
+impl FrameDecoder {
+    pub fn push(&mut self, byte: u8) -> Result<Option<Message>, KeyboardError> {
+        if byte != FRAME_DELIMITER {
+            if self.buf.push(byte).is_err() {
+                self.overflow = true;
+            }
+            return Ok(None);
+        }
+
+        let mut buf = core::mem::take(&mut self.buf);
+        if core::mem::take(&mut self.overflow) {
+            return Err(KeyboardError::BufferOverflow);
+        }
+        // consecutive delimiters.
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        let len = cobs::decode_in_place(&mut buf).map_err(|_| KeyboardError::InvaildHeader)?;
+        if len <= CRC_SIZE {
+            return Err(KeyboardError::InvaildHeader);
+        }
+
+        let (payload, crc) = buf[..len].split_at(len - CRC_SIZE);
+        if crc16(payload).to_le_bytes() != crc {
+            return Err(KeyboardError::InvailedCRC);
+        }
+
+        match postcard::from_bytes(payload) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(_) => Err(KeyboardError::Serialize),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(decoder: &mut FrameDecoder, bytes: &[u8]) -> Option<Message> {
+        let mut msg = None;
+        for byte in bytes {
+            if let Ok(Some(m)) = decoder.push(*byte) {
+                msg = Some(m);
+            }
+        }
+        msg
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = Vec::from_slice(&[0, 1, 2000, 4095, u16::MAX, 7, 8, 9]).unwrap();
+        let messages = [
+            Message::Hello {
+                version: PROTOCOL_VERSION,
+                link_up: true,
+            },
+            Message::Heartbeat {
+                usb: true,
+                master: false,
+            },
+            Message::Ack(0xff),
+            Message::Sync(0),
+            Message::Key(3, Event::KeyPress(4, 11)),
+            Message::Key(4, Event::KeyRelease(0, 0)),
+            Message::Config(5, ConfigSync::Debounce(2)),
+            Message::Analog {
+                tx: 3,
+                values: values.clone(),
+            },
+            Message::Thresholds { tx: 0, values },
+            Message::State {
+                layer: 1,
+                leds: 0b101,
+                suspended: true,
+                monitor: true,
+            },
+            Message::MouseDepth(Some(128)),
+            Message::MouseDepth(None),
+            Message::Cue(6, CalibrationCue::SaveFailed),
+        ];
+
+        let mut decoder = FrameDecoder::default();
+        for msg in messages {
+            let frame = encode(&msg).unwrap();
+            assert_eq!(frame.last(), Some(&0));
+            assert!(!frame[..frame.len() - 1].contains(&0));
+            assert_eq!(decode_all(&mut decoder, &frame), Some(msg));
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_is_dropped() {
+        let frame = encode(&Message::Key(1, Event::KeyPress(1, 2))).unwrap();
+        let mut decoder = FrameDecoder::default();
+
+        for idx in 0..frame.len() - 1 {
+            let mut corrupted = frame.clone();
+            corrupted[idx] ^= 0x10;
+            if corrupted[idx] == 0 {
+                continue;
+            }
+            assert_eq!(decode_all(&mut decoder, &corrupted), None);
+        }
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let msg = Message::Key(9, Event::KeyRelease(2, 3));
+        let frame = encode(&msg).unwrap();
+        let mut decoder = FrameDecoder::default();
+
+        // Truncated frame, then the complete one.
+        let mut bytes: Vec<u8, 128> = Vec::from_slice(&frame[3..]).unwrap();
+        bytes.extend_from_slice(&frame).unwrap();
+        assert_eq!(decode_all(&mut decoder, &bytes), Some(msg.clone()));
+
+        // Garbage longer than a frame.
+        let mut bytes: Vec<u8, 128> = Vec::new();
+        bytes.resize(MAX_FRAME_SIZE * 2, 0x55).unwrap();
+        bytes.push(0).unwrap();
+        bytes.extend_from_slice(&frame).unwrap();
+        assert_eq!(decode_all(&mut decoder, &bytes), Some(msg));
+    }
+
+    #[test]
+    fn sequence_window() {
+        assert_eq!(next_seq(0xff), 0);
+        assert_eq!(prev_seq(0), 0xff);
+        assert!(seq_not_after(5, 5));
+        assert!(seq_not_after(4, 5));
+        assert!(!seq_not_after(6, 5));
+        assert!(seq_not_after(0xfe, 1));
+        assert!(!seq_not_after(1, 0xfe));
+    }
+}
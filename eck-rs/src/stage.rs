@@ -0,0 +1,222 @@
+use crate::combo::Key;
+use crate::event::Event;
+
+// Looked up on the layout when a key is pressed.
+pub trait Bindings {
+    fn layer(&self) -> u8;
+    // Tap dance bound to the key on the current layer.
+    fn tap_dance(&self, key: Key) -> Option<u8>;
+    // One-shot bound to the key on the current layer.
+    fn one_shot(&self, key: Key) -> Option<u8>;
+}
+
+// Sits between the matrix events and the layout, e.g. combos. Events may be held back until a
+// later event or the deadline, and come out in order through `out`.
+pub trait Stage {
+    fn event(
+        &mut self,
+        event: Event,
+        now_ms: u64,
+        bindings: &impl Bindings,
+        out: &mut impl FnMut(Event),
+    );
+
+    // Call at deadline to let held back events through.
+    fn tick(&mut self, now_ms: u64, bindings: &impl Bindings, out: &mut impl FnMut(Event));
+
+    fn deadline(&self) -> Option<u64>;
+
+    // Forget every key, e.g. after the layout is reset.
+    fn reset(&mut self);
+}
+
+// Events out of the first stage go through the second. The second one's expired events go
+// first on tick.
+pub struct Chain<A, B>(pub A, pub B);
+
+impl<A: Stage, B: Stage> Stage for Chain<A, B> {
+    fn event(
+        &mut self,
+        event: Event,
+        now_ms: u64,
+        bindings: &impl Bindings,
+        out: &mut impl FnMut(Event),
+    ) {
+        let Chain(first, second) = self;
+        first.event(event, now_ms, bindings, &mut |e| {
+            second.event(e, now_ms, bindings, out)
+        });
+    }
+
+    fn tick(&mut self, now_ms: u64, bindings: &impl Bindings, out: &mut impl FnMut(Event)) {
+        let Chain(first, second) = self;
+        second.tick(now_ms, bindings, out);
+        first.tick(now_ms, bindings, &mut |e| {
+            second.event(e, now_ms, bindings, out)
+        });
+    }
+
+    fn deadline(&self) -> Option<u64> {
+        self.0.deadline().into_iter().chain(self.1.deadline()).min()
+    }
+
+    fn reset(&mut self) {
+        self.0.reset();
+        self.1.reset();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use heapless::Vec;
+
+    // Keys bound to tap dances and one-shots on `layer`.
+    #[derive(Default)]
+    pub struct TestBindings {
+        pub layer: u8,
+        pub tap_dances: &'static [(Key, u8)],
+        pub one_shots: &'static [(Key, u8)],
+    }
+
+    impl Bindings for TestBindings {
+        fn layer(&self) -> u8 {
+            self.layer
+        }
+
+        fn tap_dance(&self, key: Key) -> Option<u8> {
+            find(self.tap_dances, key)
+        }
+
+        fn one_shot(&self, key: Key) -> Option<u8> {
+            find(self.one_shots, key)
+        }
+    }
+
+    fn find(bound: &[(Key, u8)], key: Key) -> Option<u8> {
+        bound
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, index)| *index)
+    }
+
+    // Events at their time, each after a tick.
+    pub fn run(
+        stage: &mut impl Stage,
+        bindings: &TestBindings,
+        events: &[(Event, u64)],
+    ) -> Vec<Event, 16> {
+        let mut out = Vec::new();
+        for (e, at) in events {
+            stage.tick(*at, bindings, &mut |e| out.push(e).unwrap());
+            stage.event(*e, *at, bindings, &mut |e| out.push(e).unwrap());
+        }
+        out
+    }
+
+    pub fn tick(stage: &mut impl Stage, bindings: &TestBindings, now_ms: u64) -> Vec<Event, 16> {
+        let mut out = Vec::new();
+        stage.tick(now_ms, bindings, &mut |e| out.push(e).unwrap());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test::{run, tick, TestBindings};
+    use super::*;
+    use Event::KeyPress as P;
+
+    // Holds back a press for 10 ms. Others pass meanwhile.
+    #[derive(Default)]
+    struct Delay {
+        held: Option<(Key, u64)>,
+    }
+
+    impl Stage for Delay {
+        fn event(
+            &mut self,
+            event: Event,
+            now_ms: u64,
+            _: &impl Bindings,
+            out: &mut impl FnMut(Event),
+        ) {
+            match event {
+                P(i, j) if self.held.is_none() => self.held = Some(((i, j), now_ms + 10)),
+                e => out(e),
+            }
+        }
+
+        fn tick(&mut self, now_ms: u64, _: &impl Bindings, out: &mut impl FnMut(Event)) {
+            if self.deadline().is_some_and(|deadline| now_ms >= deadline) {
+                let ((i, j), _) = self.held.take().unwrap();
+                out(P(i, j));
+            }
+        }
+
+        fn deadline(&self) -> Option<u64> {
+            self.held.map(|(_, deadline)| deadline)
+        }
+
+        fn reset(&mut self) {
+            self.held = None;
+        }
+    }
+
+    // Key (0, 0) acts as (9, layer).
+    struct Layer;
+
+    impl Stage for Layer {
+        fn event(&mut self, event: Event, _: u64, b: &impl Bindings, out: &mut impl FnMut(Event)) {
+            match event {
+                P(0, 0) => out(P(9, b.layer())),
+                e => out(e),
+            }
+        }
+
+        fn tick(&mut self, _: u64, _: &impl Bindings, _: &mut impl FnMut(Event)) {}
+
+        fn deadline(&self) -> Option<u64> {
+            None
+        }
+
+        fn reset(&mut self) {}
+    }
+
+    #[test]
+    fn passes_on() {
+        let mut chain = Chain(Delay::default(), Chain(Layer, Delay::default()));
+        let bindings = TestBindings {
+            layer: 2,
+            ..Default::default()
+        };
+        let out = run(&mut chain, &bindings, &[(P(0, 0), 0)]);
+        assert!(out.is_empty());
+        assert_eq!(chain.deadline(), Some(10));
+
+        assert!(
+            tick(&mut chain, &bindings, 10).is_empty(),
+            "held by the second delay"
+        );
+        assert_eq!(chain.deadline(), Some(20));
+        assert_eq!(tick(&mut chain, &bindings, 20).as_slice(), [P(9, 2)]);
+        assert_eq!(chain.deadline(), None);
+    }
+
+    #[test]
+    fn ticks_later_stage_first() {
+        let mut chain = Chain(Delay::default(), Delay::default());
+        let bindings = TestBindings::default();
+        run(&mut chain, &bindings, &[(P(1, 1), 0)]);
+        assert!(tick(&mut chain, &bindings, 10).is_empty());
+        run(&mut chain, &bindings, &[(P(2, 2), 15)]);
+
+        // Both expired. (2, 2) would pass the second delay still holding (1, 1).
+        assert_eq!(tick(&mut chain, &bindings, 25).as_slice(), [P(1, 1)]);
+        assert_eq!(tick(&mut chain, &bindings, 35).as_slice(), [P(2, 2)]);
+
+        run(&mut chain, &bindings, &[(P(3, 3), 40)]);
+        chain.reset();
+        assert_eq!(chain.deadline(), None);
+    }
+}
@@ -0,0 +1,287 @@
+use heapless::Vec;
+
+use crate::combo::Key;
+use crate::event::Event;
+use crate::stage::{Bindings, Stage};
+
+// Tap dance keys held at once.
+const MAX_HELD: usize = 4;
+
+// Taps of a key in a row act as different keys of the layout, usually of a row without
+// switches. Each tap or release within `tapping_term_ms` of the last one continues the dance.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapDance<'a> {
+    // Tapped once, twice, ...
+    pub taps: &'a [Key],
+    // Held after no tap, one tap, ... The tap key of the count is held if missing.
+    pub holds: &'a [Key],
+    pub tapping_term_ms: u16,
+}
+
+impl TapDance<'_> {
+    // No tap nor hold can follow.
+    fn is_last(&self, count: usize, pressed: bool) -> bool {
+        count >= self.taps.len() && (!pressed || count > self.holds.len())
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    dance: usize,
+    key: Key,
+    count: usize,
+    pressed: bool,
+    deadline: u64,
+}
+
+// Counts taps of a key bound to a dance. Its presses are held back until the tapping term
+// passes, another key is pressed, or no more taps can follow, then act as the key of the count.
+pub struct TapDances<'a> {
+    dances: &'a [TapDance<'a>],
+    pending: Option<Pending>,
+    // Dance key and the key it acts as, released with it.
+    held: Vec<(Key, Key), MAX_HELD>,
+}
+
+impl<'a> TapDances<'a> {
+    pub fn new(dances: &'a [TapDance<'a>]) -> Self {
+        Self {
+            dances,
+            pending: None,
+            held: Vec::new(),
+        }
+    }
+
+    fn press(&mut self, key: Key, dance: Option<u8>, now_ms: u64, out: &mut impl FnMut(Event)) {
+        match &mut self.pending {
+            Some(p) if p.key == key => {
+                p.count += 1;
+                p.pressed = true;
+                p.deadline = now_ms + self.dances[p.dance].tapping_term_ms as u64;
+            }
+            _ => {
+                if self.pending.is_some() {
+                    self.resolve(out);
+                }
+                let dance = dance.map(usize::from).filter(|d| *d < self.dances.len());
+                let Some(dance) = dance else {
+                    return out(Event::KeyPress(key.0, key.1));
+                };
+                self.pending = Some(Pending {
+                    dance,
+                    key,
+                    count: 1,
+                    pressed: true,
+                    deadline: now_ms + self.dances[dance].tapping_term_ms as u64,
+                });
+            }
+        }
+        self.resolve_if_last(out);
+    }
+
+    fn release(&mut self, key: Key, now_ms: u64, out: &mut impl FnMut(Event)) {
+        if let Some(p) = self.pending.as_mut().filter(|p| p.key == key) {
+            p.pressed = false;
+            p.deadline = now_ms + self.dances[p.dance].tapping_term_ms as u64;
+            return self.resolve_if_last(out);
+        }
+
+        match self.held.iter().position(|(k, _)| *k == key) {
+            Some(index) => {
+                let (_, (i, j)) = self.held.swap_remove(index);
+                out(Event::KeyRelease(i, j));
+            }
+            None => out(Event::KeyRelease(key.0, key.1)),
+        }
+    }
+
+    fn resolve_if_last(&mut self, out: &mut impl FnMut(Event)) {
+        if let Some(p) = &self.pending {
+            if self.dances[p.dance].is_last(p.count, p.pressed) {
+                self.resolve(out);
+            }
+        }
+    }
+
+    // Press the key of the current count. It is tapped if the dance key is released.
+    fn resolve(&mut self, out: &mut impl FnMut(Event)) {
+        let Some(p) = self.pending.take() else {
+            return;
+        };
+        let dance = &self.dances[p.dance];
+        let tap = dance.taps.get(p.count - 1);
+        let action = match p.pressed {
+            true => dance.holds.get(p.count - 1).or(tap),
+            false => tap,
+        };
+        let Some(&(i, j)) = action else {
+            return;
+        };
+
+        out(Event::KeyPress(i, j));
+        // Tapped, also when too many are held.
+        if !p.pressed || self.held.push((p.key, (i, j))).is_err() {
+            out(Event::KeyRelease(i, j));
+        }
+    }
+}
+
+impl Stage for TapDances<'_> {
+    // The dance of a key is looked up on press.
+    fn event(
+        &mut self,
+        event: Event,
+        now_ms: u64,
+        bindings: &impl Bindings,
+        out: &mut impl FnMut(Event),
+    ) {
+        match event {
+            Event::KeyPress(i, j) => self.press((i, j), bindings.tap_dance((i, j)), now_ms, out),
+            Event::KeyRelease(i, j) => self.release((i, j), now_ms, out),
+            Event::None => {}
+        }
+    }
+
+    // The dance ends with the key of its count.
+    fn tick(&mut self, now_ms: u64, _: &impl Bindings, out: &mut impl FnMut(Event)) {
+        if self.deadline().is_some_and(|deadline| now_ms >= deadline) {
+            self.resolve(out);
+        }
+    }
+
+    fn deadline(&self) -> Option<u64> {
+        self.pending.as_ref().map(|p| p.deadline)
+    }
+
+    fn reset(&mut self) {
+        self.pending = None;
+        self.held.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stage::test::{run, tick, TestBindings};
+    use Event::{KeyPress as P, KeyRelease as R};
+
+    const D: Key = (2, 11);
+    const OTHER: Key = (0, 1);
+
+    // Escape, Caps Lock, ... on the virtual row 5.
+    static DANCES: [TapDance; 2] = [
+        TapDance {
+            taps: &[(5, 0), (5, 1), (5, 2)],
+            holds: &[(5, 3), (5, 4)],
+            tapping_term_ms: 200,
+        },
+        TapDance {
+            taps: &[(5, 5)],
+            holds: &[],
+            tapping_term_ms: 100,
+        },
+    ];
+
+    // D dances the first one, (0, 0) the second.
+    static BINDINGS: TestBindings = TestBindings {
+        layer: 0,
+        tap_dances: &[(D, 0), ((0, 0), 1)],
+        one_shots: &[],
+    };
+
+    #[test]
+    fn counts_taps() {
+        let mut dances = TapDances::new(&DANCES);
+        let out = run(&mut dances, &BINDINGS, &[(P(2, 11), 0), (R(2, 11), 50)]);
+        assert!(out.is_empty());
+        assert_eq!(dances.deadline(), Some(250));
+        assert_eq!(
+            tick(&mut dances, &BINDINGS, 250).as_slice(),
+            [P(5, 0), R(5, 0)]
+        );
+
+        let out = run(
+            &mut dances,
+            &BINDINGS,
+            &[(P(2, 11), 1000), (R(2, 11), 1050), (P(2, 11), 1150)],
+        );
+        assert!(out.is_empty());
+        // No tap nor hold can follow the third press, it acts right away.
+        let out = run(
+            &mut dances,
+            &BINDINGS,
+            &[(R(2, 11), 1200), (P(2, 11), 1300)],
+        );
+        assert_eq!(out, [P(5, 2)]);
+        assert_eq!(dances.deadline(), None);
+        let out = run(&mut dances, &BINDINGS, &[(R(2, 11), 1350)]);
+        assert_eq!(out, [R(5, 2)]);
+    }
+
+    #[test]
+    fn tap_then_hold() {
+        let mut dances = TapDances::new(&DANCES);
+        let out = run(
+            &mut dances,
+            &BINDINGS,
+            &[(P(2, 11), 0), (R(2, 11), 50), (P(2, 11), 100)],
+        );
+        assert!(out.is_empty());
+        assert_eq!(tick(&mut dances, &BINDINGS, 300).as_slice(), [P(5, 4)]);
+        let out = run(&mut dances, &BINDINGS, &[(P(1, 1), 400), (R(2, 11), 500)]);
+        assert_eq!(out, [P(1, 1), R(5, 4)]);
+
+        // Held on the first press.
+        let mut dances = TapDances::new(&DANCES);
+        run(&mut dances, &BINDINGS, &[(P(2, 11), 0)]);
+        assert_eq!(tick(&mut dances, &BINDINGS, 200).as_slice(), [P(5, 3)]);
+    }
+
+    #[test]
+    fn other_key_resolves() {
+        let mut dances = TapDances::new(&DANCES);
+        let out = run(
+            &mut dances,
+            &BINDINGS,
+            &[(P(2, 11), 0), (R(2, 11), 50), (P(2, 11), 100)],
+        );
+        assert!(out.is_empty());
+        // Held while another key is pressed.
+        let out = run(&mut dances, &BINDINGS, &[(P(1, 1), 120)]);
+        assert_eq!(out, [P(5, 4), P(1, 1)]);
+        let out = run(&mut dances, &BINDINGS, &[(R(2, 11), 130), (R(1, 1), 140)]);
+        assert_eq!(out, [R(5, 4), R(1, 1)]);
+
+        // Another dance too.
+        let out = run(
+            &mut dances,
+            &BINDINGS,
+            &[(P(2, 11), 200), (R(2, 11), 210), (P(0, 0), 220)],
+        );
+        assert_eq!(
+            out,
+            [P(5, 0), R(5, 0), P(5, 5)],
+            "a single key dance acts right away"
+        );
+        let out = run(&mut dances, &BINDINGS, &[(R(0, 0), 230)]);
+        assert_eq!(out, [R(5, 5)]);
+    }
+
+    #[test]
+    fn passes_other_keys() {
+        let mut dances = TapDances::new(&DANCES);
+        let out = run(
+            &mut dances,
+            &BINDINGS,
+            &[(P(OTHER.0, OTHER.1), 0), (R(OTHER.0, OTHER.1), 10)],
+        );
+        assert_eq!(out, [P(0, 1), R(0, 1)]);
+
+        let bindings = TestBindings {
+            tap_dances: &[(D, 7)],
+            ..Default::default()
+        };
+        let out = run(&mut dances, &bindings, &[(P(D.0, D.1), 20)]);
+        assert_eq!(out, [P(2, 11)], "not a dance");
+    }
+}
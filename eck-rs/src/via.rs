@@ -0,0 +1,61 @@
+// VIA raw HID protocol ids shared by the firmware and host tools.
+// https://www.caniusevia.com/docs/specification
+pub const REPORT_SIZE: usize = 32;
+pub const PROTOCOL_VERSION: u16 = 0x000C;
+
+// Raw HID interface usage.
+pub const USAGE_PAGE: u16 = 0xFF60;
+pub const USAGE: u16 = 0x61;
+
+// Command ids.
+pub const GET_PROTOCOL_VERSION: u8 = 0x01;
+pub const GET_KEYBOARD_VALUE: u8 = 0x02;
+pub const SET_KEYBOARD_VALUE: u8 = 0x03;
+pub const DYNAMIC_KEYMAP_GET_KEYCODE: u8 = 0x04;
+pub const DYNAMIC_KEYMAP_SET_KEYCODE: u8 = 0x05;
+pub const DYNAMIC_KEYMAP_RESET: u8 = 0x06;
+pub const CUSTOM_SET_VALUE: u8 = 0x07;
+pub const CUSTOM_GET_VALUE: u8 = 0x08;
+pub const CUSTOM_SAVE: u8 = 0x09;
+pub const EEPROM_RESET: u8 = 0x0A;
+pub const DYNAMIC_KEYMAP_MACRO_GET_COUNT: u8 = 0x0C;
+pub const DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE: u8 = 0x0D;
+pub const DYNAMIC_KEYMAP_MACRO_GET_BUFFER: u8 = 0x0E;
+pub const DYNAMIC_KEYMAP_MACRO_SET_BUFFER: u8 = 0x0F;
+pub const DYNAMIC_KEYMAP_MACRO_RESET: u8 = 0x10;
+pub const DYNAMIC_KEYMAP_GET_LAYER_COUNT: u8 = 0x11;
+pub const DYNAMIC_KEYMAP_GET_BUFFER: u8 = 0x12;
+pub const DYNAMIC_KEYMAP_SET_BUFFER: u8 = 0x13;
+pub const UNHANDLED: u8 = 0xFF;
+
+// Keyboard value ids.
+pub const UPTIME: u8 = 0x01;
+pub const LAYOUT_OPTIONS: u8 = 0x02;
+pub const SWITCH_MATRIX_STATE: u8 = 0x03;
+pub const FIRMWARE_VERSION: u8 = 0x04;
+
+// Custom values of this keyboard. Data follows | command | channel | value id |
+pub const CUSTOM_CHANNEL: u8 = 0x00;
+pub const CUSTOM_DATA_START: usize = 3;
+
+// Get only. | layers | rows | cols | tx | rx |
+pub const MATRIX_SIZE: u8 = 0x01;
+// | tx | rx | threshold(2) | of the master half.
+pub const THRESHOLD: u8 = 0x02;
+// | count | of the master half.
+pub const DEBOUNCE: u8 = 0x03;
+// | side | stored in the master half.
+pub const SIDE: u8 = 0x04;
+// Get only. Request | half | tx |, half is ANALOG_LOCAL or ANALOG_REMOTE.
+// Response | side | then | value(2) | threshold(2) | of each rx. Side is ANALOG_NONE if the
+// remote half has not sent values lately.
+pub const ANALOG: u8 = 0x05;
+
+pub const ANALOG_LOCAL: u8 = 0x00;
+pub const ANALOG_REMOTE: u8 = 0x01;
+pub const ANALOG_NONE: u8 = 0xFE;
+
+pub const SIDE_LEFT: u8 = 0x00;
+pub const SIDE_RIGHT: u8 = 0x01;
+// Not stored, read from the handedness pin.
+pub const SIDE_PIN: u8 = 0xFF;
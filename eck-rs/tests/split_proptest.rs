@@ -0,0 +1,98 @@
+use eck_rs::event::Event;
+use eck_rs::split::{encode, CalibrationCue, ConfigSync, FrameDecoder, Message, MAX_ANALOG_VALUES};
+use proptest::prelude::*;
+
+fn event() -> impl Strategy<Value = Event> {
+    prop_oneof![
+        (any::<u8>(), any::<u8>()).prop_map(|(i, j)| Event::KeyPress(i, j)),
+        (any::<u8>(), any::<u8>()).prop_map(|(i, j)| Event::KeyRelease(i, j)),
+        Just(Event::None),
+    ]
+}
+
+fn message() -> impl Strategy<Value = Message> {
+    prop_oneof![
+        (any::<u16>(), any::<bool>())
+            .prop_map(|(version, link_up)| Message::Hello { version, link_up }),
+        (any::<bool>(), any::<bool>()).prop_map(|(usb, master)| Message::Heartbeat { usb, master }),
+        any::<u8>().prop_map(Message::Ack),
+        any::<u8>().prop_map(Message::Sync),
+        (any::<u8>(), event()).prop_map(|(seq, e)| Message::Key(seq, e)),
+        (any::<u8>(), any::<u8>())
+            .prop_map(|(seq, n)| Message::Config(seq, ConfigSync::Debounce(n))),
+        (
+            any::<u8>(),
+            prop::collection::vec(any::<u16>(), 0..=MAX_ANALOG_VALUES)
+        )
+            .prop_map(|(tx, values)| Message::Analog {
+                tx,
+                values: heapless::Vec::from_slice(&values).unwrap(),
+            }),
+        (
+            any::<u8>(),
+            prop::collection::vec(any::<u16>(), 0..=MAX_ANALOG_VALUES)
+        )
+            .prop_map(|(tx, values)| Message::Thresholds {
+                tx,
+                values: heapless::Vec::from_slice(&values).unwrap(),
+            }),
+        (any::<u8>(), any::<u8>(), any::<bool>(), any::<bool>()).prop_map(
+            |(layer, leds, suspended, monitor)| Message::State {
+                layer,
+                leds,
+                suspended,
+                monitor,
+            }
+        ),
+        any::<Option<u8>>().prop_map(Message::MouseDepth),
+        (
+            any::<u8>(),
+            prop_oneof![
+                Just(CalibrationCue::Release),
+                Just(CalibrationCue::PressAll),
+                Just(CalibrationCue::Canceled),
+                Just(CalibrationCue::Saved),
+                Just(CalibrationCue::SaveFailed),
+            ]
+        )
+            .prop_map(|(seq, cue)| Message::Cue(seq, cue)),
+    ]
+}
+
+fn decode(decoder: &mut FrameDecoder, bytes: &[u8]) -> Vec<Message> {
+    bytes
+        .iter()
+        .filter_map(|b| decoder.push(*b).ok().flatten())
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+        let mut decoder = FrameDecoder::default();
+        decode(&mut decoder, &bytes);
+    }
+
+    #[test]
+    fn frames_round_trip(msgs in prop::collection::vec(message(), 1..16)) {
+        let mut decoder = FrameDecoder::default();
+        let mut bytes = Vec::new();
+        for msg in msgs.iter() {
+            bytes.extend_from_slice(&encode(msg).unwrap());
+        }
+        prop_assert_eq!(decode(&mut decoder, &bytes), msgs);
+    }
+
+    #[test]
+    fn frames_round_trip_after_garbage(
+        garbage in prop::collection::vec(any::<u8>(), 0..128),
+        msg in message(),
+    ) {
+        let mut decoder = FrameDecoder::default();
+        decode(&mut decoder, &garbage);
+        // delimiter ends any partial frame left by the garbage.
+        decoder.push(0).ok();
+        let frame = encode(&msg).unwrap();
+        prop_assert_eq!(decode(&mut decoder, &frame), vec![msg]);
+    }
+}
@@ -1,5 +1,626 @@
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::{env, fs, path::PathBuf, process};
+
+#[path = "src/layout_size.rs"]
+mod layout_size;
+use layout_size::{
+    left_matrix_transform, right_matrix_transform, COLS, MACRO_BUFFER_SIZE, MACRO_COUNT,
+    MAX_COMBO_KEYS, MAX_ONE_SHOTS, MAX_TAP_DANCES, N_LAYERS, ROWS, RX_SIZE, TX_SIZE,
+};
+
+const DEFAULT_KEYMAP: &str = "keymap.toml";
+const VIA_JSON: &str = "via.json";
+
 fn main() {
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    if let Err(e) = generate_keymap().and_then(|()| check_via()) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Keymap {
+    layer: Vec<Layer>,
+    #[serde(default)]
+    combo: Vec<Combo>,
+    #[serde(default, rename = "macro")]
+    macros: Vec<Macro>,
+    #[serde(default)]
+    tap_dance: Vec<TapDance>,
+    #[serde(default)]
+    one_shot: Vec<OneShot>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    name: Option<String>,
+    // ROWS lines of COLS keys.
+    keys: String,
+}
+
+// Positions are (row, col) of the layout.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Combo {
+    name: Option<String>,
+    keys: Vec<(usize, usize)>,
+    key: (usize, usize),
+    layers: Option<Vec<usize>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Macro {
+    name: Option<String>,
+    steps: Vec<MacroStep>,
+}
+
+// eck_rs::macros::Step. Keys are keyberon KeyCode names.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "lowercase")]
+enum MacroStep {
+    Tap(String),
+    Press(String),
+    Release(String),
+    Delay(u16),
+    Text(String),
+}
+
+// Keys in the syntax of layers, pressed on the row after them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TapDance {
+    name: Option<String>,
+    taps: Vec<String>,
+    #[serde(default)]
+    holds: Vec<String>,
+    // Milliseconds, config::TAPPING_TERM if not given.
+    tapping_term: Option<u16>,
+}
+
+// Key in the syntax of layers, pressed on the row after them.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OneShot {
+    name: Option<String>,
+    key: String,
+    // Locked by a double tap.
+    #[serde(default)]
+    lock: bool,
+    // Milliseconds, config::ONE_SHOT_TIMEOUT if not given.
+    timeout: Option<u16>,
+}
+
+// Parts of the VIA definition checked against the matrix.
+#[derive(Deserialize)]
+struct Via {
+    matrix: ViaMatrix,
+    layouts: ViaLayouts,
+}
+
+#[derive(Deserialize)]
+struct ViaMatrix {
+    rows: usize,
+    cols: usize,
+}
+
+// KLE rows. Keys are "row,col" labels between objects of key properties.
+#[derive(Deserialize)]
+struct ViaLayouts {
+    keymap: Vec<Vec<serde_json::Value>>,
+}
+
+// Keys in keyberon layout! syntax. Described in keymap.toml.
+enum Entry {
+    KeyCode(String),
+    Trans,
+    Layer(usize),
+    MultipleKeyCodes(Vec<String>),
+    Action(String),
+}
+
+// Write layers::LAYERS, COMBOS, MACROS, TAP_DANCES, ONE_SHOTS and VIRTUAL_KEYS to OUT_DIR
+// from the keymap file.
+fn generate_keymap() -> Result<(), String> {
+    println!("cargo:rerun-if-env-changed=KEYMAP");
+    println!("cargo:rerun-if-changed=src/layout_size.rs");
+    let path = env::var("KEYMAP").unwrap_or_else(|_| DEFAULT_KEYMAP.to_string());
+    println!("cargo:rerun-if-changed={}", path);
+
+    let text = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+    let keymap: Keymap = toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let layers = layers_source(&keymap).map_err(|e| format!("{}: {}", path, e))?;
+    let combos = combos_source(&keymap).map_err(|e| format!("{}: {}", path, e))?;
+    let macros = macros_source(&keymap).map_err(|e| format!("{}: {}", path, e))?;
+    let mut keys = Vec::new();
+    let tap_dances =
+        tap_dances_source(&keymap, &mut keys).map_err(|e| format!("{}: {}", path, e))?;
+    let one_shots = one_shots_source(&keymap, &mut keys).map_err(|e| format!("{}: {}", path, e))?;
+    let virtual_keys = virtual_keys_source(keys).map_err(|e| format!("{}: {}", path, e))?;
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let sources = [
+        ("layers.rs", layers),
+        ("combos.rs", combos),
+        ("macros.rs", macros),
+        ("tap_dances.rs", tap_dances),
+        ("one_shots.rs", one_shots),
+        ("virtual_keys.rs", virtual_keys),
+    ];
+    for (file, source) in sources {
+        let dest = out_dir.join(file);
+        let source = format!("// Generated by build.rs from {}.\n{}", path, source);
+        fs::write(&dest, source).map_err(|e| format!("{}: {}", dest.display(), e))?;
+    }
+    Ok(())
+}
+
+fn layers_source(keymap: &Keymap) -> Result<String, String> {
+    if keymap.layer.len() != N_LAYERS {
+        return Err(format!(
+            "{} layers, expected {} (N_LAYERS in src/layout_size.rs)",
+            keymap.layer.len(),
+            N_LAYERS
+        ));
+    }
+
+    let mut out = String::from("layout::layout! {\n");
+    for (i, layer) in keymap.layer.iter().enumerate() {
+        let name = match &layer.name {
+            Some(name) => format!("layer {} \"{}\"", i, name),
+            None => format!("layer {}", i),
+        };
+        let rows = parse_layer(&layer.keys).map_err(|e| format!("{} {}", name, e))?;
+        for (r, row) in rows.iter().enumerate() {
+            for (c, key) in row.iter().enumerate() {
+                check_bound(key, keymap)
+                    .map_err(|e| format!("{} row {}: key {}: {}", name, r, c, e))?;
+            }
+        }
+
+        writeln!(out, "    {{ // {}", name).unwrap();
+        for row in rows {
+            let keys: Vec<_> = row.iter().map(to_tokens).collect();
+            writeln!(out, "        [{}]", keys.join(" ")).unwrap();
+        }
+        out.push_str("    }\n");
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+fn combos_source(keymap: &Keymap) -> Result<String, String> {
+    let mut out = String::from("[\n");
+    for (i, combo) in keymap.combo.iter().enumerate() {
+        let name = match &combo.name {
+            Some(name) => format!("combo {} \"{}\"", i, name),
+            None => format!("combo {}", i),
+        };
+        check_combo(combo).map_err(|e| format!("{} {}", name, e))?;
+
+        let keys: Vec<_> = combo
+            .keys
+            .iter()
+            .map(|(row, col)| format!("({}, {})", row, col))
+            .collect();
+        let layers = match &combo.layers {
+            Some(layers) => format!("Some(&{:?})", layers),
+            None => "None".to_string(),
+        };
+        writeln!(out, "    // {}", name).unwrap();
+        writeln!(
+            out,
+            "    Combo {{ keys: &[{}], key: {:?}, layers: {} }},",
+            keys.join(", "),
+            combo.key,
+            layers
+        )
+        .unwrap();
+    }
+    out.push_str("]\n");
+    Ok(out)
+}
+
+fn check_combo(combo: &Combo) -> Result<(), String> {
+    if !(2..=MAX_COMBO_KEYS).contains(&combo.keys.len()) {
+        return Err(format!(
+            "needs 2 to {} keys (MAX_COMBO_KEYS in src/layout_size.rs)",
+            MAX_COMBO_KEYS
+        ));
+    }
+    for &(row, col) in combo.keys.iter().chain([&combo.key]) {
+        if row >= ROWS || col >= COLS {
+            return Err(format!(
+                "key ({}, {}) is out of the {}x{} layout",
+                row, col, ROWS, COLS
+            ));
+        }
+    }
+    for (i, key) in combo.keys.iter().enumerate() {
+        if combo.keys[..i].contains(key) {
+            return Err(format!("key {:?} is listed twice", key));
+        }
+    }
+    if combo.keys.contains(&combo.key) {
+        return Err("acts as one of its own keys".to_string());
+    }
+    if let Some(layer) = combo.layers.iter().flatten().find(|l| **l >= N_LAYERS) {
+        return Err(format!(
+            "layer {} is out of range, N_LAYERS is {}",
+            layer, N_LAYERS
+        ));
+    }
+    Ok(())
+}
+
+fn macros_source(keymap: &Keymap) -> Result<String, String> {
+    if keymap.macros.len() > MACRO_COUNT as usize {
+        return Err(format!(
+            "{} macros, up to {} (MACRO_COUNT in src/layout_size.rs)",
+            keymap.macros.len(),
+            MACRO_COUNT
+        ));
+    }
+
+    let mut out = String::from("[\n");
+    // NUL ends each macro in the buffer.
+    let mut size = keymap.macros.len();
+    for (i, m) in keymap.macros.iter().enumerate() {
+        let name = match &m.name {
+            Some(name) => format!("macro {} \"{}\"", i, name),
+            None => format!("macro {}", i),
+        };
+        let mut steps = Vec::new();
+        for (j, step) in m.steps.iter().enumerate() {
+            let (tokens, len) =
+                macro_step(step).map_err(|e| format!("{} step {}: {}", name, j, e))?;
+            steps.push(tokens);
+            size += len;
+        }
+        writeln!(out, "    // {}", name).unwrap();
+        writeln!(out, "    &[{}],", steps.join(", ")).unwrap();
+    }
+    if size > MACRO_BUFFER_SIZE {
+        return Err(format!(
+            "macros take {} bytes, up to {} (MACRO_BUFFER_SIZE in src/layout_size.rs)",
+            size, MACRO_BUFFER_SIZE
+        ));
+    }
+    out.push_str("]\n");
+    Ok(out)
+}
+
+// Step as tokens and its size in the macro buffer.
+fn macro_step(step: &MacroStep) -> Result<(String, usize), String> {
+    let key = |name: &str| match is_identifier(name) {
+        true => Ok(name.to_string()),
+        false => Err(format!("'{}' is not a keycode name", name)),
+    };
+    let step = match step {
+        MacroStep::Tap(name) => (format!("Step::Tap({})", key(name)?), 3),
+        MacroStep::Press(name) => (format!("Step::Press({})", key(name)?), 3),
+        MacroStep::Release(name) => (format!("Step::Release({})", key(name)?), 3),
+        MacroStep::Delay(ms) => (format!("Step::Delay({})", ms), 3 + ms.to_string().len()),
+        MacroStep::Text(text) => {
+            if let Some(c) = text
+                .chars()
+                .find(|c| !(c.is_ascii_graphic() || matches!(c, ' ' | '\n' | '\t')))
+            {
+                return Err(format!(
+                    "{:?} can't be typed, text is printable ASCII, newline and tab",
+                    c
+                ));
+            }
+            (format!("Step::Text({:?})", text), text.len())
+        }
+    };
+    Ok(step)
+}
+
+// Taps and holds take the next keys of the virtual row.
+fn tap_dances_source(keymap: &Keymap, keys: &mut Vec<String>) -> Result<String, String> {
+    if keymap.tap_dance.len() > MAX_TAP_DANCES {
+        return Err(format!(
+            "{} tap dances, up to {} (MAX_TAP_DANCES in src/layout_size.rs)",
+            keymap.tap_dance.len(),
+            MAX_TAP_DANCES
+        ));
+    }
+
+    let mut dances = String::from("[\n");
+    for (i, dance) in keymap.tap_dance.iter().enumerate() {
+        let name = match &dance.name {
+            Some(name) => format!("tap dance {} \"{}\"", i, name),
+            None => format!("tap dance {}", i),
+        };
+        if dance.taps.is_empty() {
+            return Err(format!("{} needs taps", name));
+        }
+        if dance.tapping_term == Some(0) {
+            return Err(format!("{} tapping_term must be more than 0", name));
+        }
+
+        let mut positions = |list: &[String], what: &str| -> Result<String, String> {
+            let mut out = Vec::new();
+            for (j, key) in list.iter().enumerate() {
+                let entry = parse_key(key)
+                    .and_then(|entry| match entry {
+                        Entry::Trans => Err("a dance key can't be transparent".to_string()),
+                        Entry::Action(name) if tap_dance_index(&name).is_some() => {
+                            Err("a dance key can't be a tap dance".to_string())
+                        }
+                        entry => check_bound(&entry, keymap).map(|()| entry),
+                    })
+                    .map_err(|e| format!("{} {} {} '{}': {}", name, what, j, key, e))?;
+                out.push(format!("({}, {})", ROWS, keys.len()));
+                keys.push(to_tokens(&entry));
+            }
+            Ok(out.join(", "))
+        };
+        let taps = positions(&dance.taps, "tap")?;
+        let holds = positions(&dance.holds, "hold")?;
+        let term = match dance.tapping_term {
+            Some(ms) => ms.to_string(),
+            None => "crate::config::TAPPING_TERM.as_millis() as u16".to_string(),
+        };
+
+        writeln!(dances, "    // {}", name).unwrap();
+        writeln!(
+            dances,
+            "    TapDance {{ taps: &[{}], holds: &[{}], tapping_term_ms: {} }},",
+            taps, holds, term
+        )
+        .unwrap();
+    }
+    dances.push_str("]\n");
+    Ok(dances)
+}
+
+// Keys take the next keys of the virtual row.
+fn one_shots_source(keymap: &Keymap, keys: &mut Vec<String>) -> Result<String, String> {
+    if keymap.one_shot.len() > MAX_ONE_SHOTS {
+        return Err(format!(
+            "{} one-shots, up to {} (MAX_ONE_SHOTS in src/layout_size.rs)",
+            keymap.one_shot.len(),
+            MAX_ONE_SHOTS
+        ));
+    }
+
+    let mut out = String::from("[\n");
+    for (i, one_shot) in keymap.one_shot.iter().enumerate() {
+        let name = match &one_shot.name {
+            Some(name) => format!("one-shot {} \"{}\"", i, name),
+            None => format!("one-shot {}", i),
+        };
+        if one_shot.timeout == Some(0) {
+            return Err(format!("{} timeout must be more than 0", name));
+        }
+        let entry = parse_key(&one_shot.key)
+            .and_then(|entry| match entry {
+                Entry::Trans => Err("can't be transparent".to_string()),
+                Entry::Action(name)
+                    if tap_dance_index(&name).is_some() || one_shot_index(&name).is_some() =>
+                {
+                    Err("can't be a tap dance or one-shot".to_string())
+                }
+                entry => Ok(entry),
+            })
+            .map_err(|e| format!("{} key '{}': {}", name, one_shot.key, e))?;
+        let timeout = match one_shot.timeout {
+            Some(ms) => ms.to_string(),
+            None => "crate::config::ONE_SHOT_TIMEOUT.as_millis() as u16".to_string(),
+        };
+
+        writeln!(out, "    // {}", name).unwrap();
+        writeln!(
+            out,
+            "    OneShot {{ key: ({}, {}), timeout_ms: {}, lock: {} }},",
+            ROWS,
+            keys.len(),
+            timeout,
+            one_shot.lock
+        )
+        .unwrap();
+        keys.push(to_tokens(&entry));
+    }
+    out.push_str("]\n");
+    Ok(out)
+}
+
+// layers::VIRTUAL_KEYS, the row after the layers.
+fn virtual_keys_source(mut keys: Vec<String>) -> Result<String, String> {
+    if keys.len() > COLS {
+        return Err(format!(
+            "tap dances and one-shots have {} keys, up to {} (COLS in src/layout_size.rs)",
+            keys.len(),
+            COLS
+        ));
+    }
+    keys.resize(COLS, "{Action::NoOp}".to_string());
+    Ok(format!(
+        "layout::layout! {{\n    {{ // tap dances and one-shots\n        [{}]\n    }}\n}}\n",
+        keys.join(" ")
+    ))
+}
+
+// TDn and OSn bind a defined tap dance or one-shot.
+fn check_bound(key: &Entry, keymap: &Keymap) -> Result<(), String> {
+    let Entry::Action(name) = key else {
+        return Ok(());
+    };
+    let (index, count, what) = match (tap_dance_index(name), one_shot_index(name)) {
+        (Some(index), _) => (index, keymap.tap_dance.len(), "tap dance"),
+        (_, Some(index)) => (index, keymap.one_shot.len(), "one-shot"),
+        _ => return Ok(()),
+    };
+    match index < count {
+        true => Ok(()),
+        false => Err(format!("{} is not a {}, {} are defined", name, what, count)),
+    }
+}
+
+// TDn binds nth tap dance.
+fn tap_dance_index(name: &str) -> Option<usize> {
+    name.strip_prefix("TD")?.parse().ok()
+}
+
+// OSn binds nth one-shot.
+fn one_shot_index(name: &str) -> Option<usize> {
+    name.strip_prefix("OS")?.parse().ok()
+}
+
+fn parse_layer(keys: &str) -> Result<Vec<Vec<Entry>>, String> {
+    let lines: Vec<_> = keys.lines().filter(|l| !l.trim().is_empty()).collect();
+    if lines.len() != ROWS {
+        return Err(format!(
+            "has {} rows, expected {} (ROWS in src/layout_size.rs)",
+            lines.len(),
+            ROWS
+        ));
+    }
+
+    let mut rows = Vec::new();
+    for (r, line) in lines.iter().enumerate() {
+        let row = parse_row(line).map_err(|e| format!("row {}: {}", r, e))?;
+        if row.len() != COLS {
+            return Err(format!(
+                "row {}: {} keys, expected {} (COLS in src/layout_size.rs)",
+                r,
+                row.len(),
+                COLS
+            ));
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+fn parse_row(line: &str) -> Result<Vec<Entry>, String> {
+    let mut keys = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let (key, tail) = match rest.chars().next().unwrap() {
+            open @ ('(' | '[' | '{') => {
+                let close = match open {
+                    '(' => ')',
+                    '[' => ']',
+                    _ => '}',
+                };
+                let end = rest
+                    .find(close)
+                    .ok_or_else(|| format!("key {}: '{}' is not closed", keys.len(), open))?;
+                (&rest[..=end], &rest[end + 1..])
+            }
+            _ => rest.split_at(rest.find(char::is_whitespace).unwrap_or(rest.len())),
+        };
+        keys.push(parse_key(key).map_err(|e| format!("key {} '{}': {}", keys.len(), key, e))?);
+        rest = tail.trim_start();
+    }
+    Ok(keys)
+}
+
+fn parse_key(key: &str) -> Result<Entry, String> {
+    // Between the brackets, which are ASCII.
+    let inner = || &key[1..key.len() - 1];
+    match key.chars().next() {
+        Some('(') => match inner().trim().parse() {
+            Ok(layer) if layer < N_LAYERS => Ok(Entry::Layer(layer)),
+            Ok(_) => Err(format!("layer out of range, N_LAYERS is {}", N_LAYERS)),
+            Err(_) => Err("expected a layer number".to_string()),
+        },
+        Some('[') => {
+            let names: Vec<_> = inner().split_whitespace().map(str::to_string).collect();
+            match names.iter().find(|name| !is_identifier(name)) {
+                _ if names.is_empty() => Err("expected keycode names".to_string()),
+                Some(name) => Err(format!("'{}' is not a keycode name", name)),
+                None => Ok(Entry::MultipleKeyCodes(names)),
+            }
+        }
+        Some('{') => match is_identifier(inner().trim()) {
+            true => Ok(Entry::Action(inner().trim().to_string())),
+            false => Err("expected an action constant name".to_string()),
+        },
+        _ if key == "t" => Ok(Entry::Trans),
+        _ if is_identifier(key) => Ok(Entry::KeyCode(key.to_string())),
+        _ => Err("expected a keycode name, t, (layer), [keycodes] or {action}".to_string()),
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn to_tokens(key: &Entry) -> String {
+    match key {
+        Entry::KeyCode(name) => name.clone(),
+        Entry::Trans => "t".to_string(),
+        Entry::Layer(layer) => format!("({})", layer),
+        Entry::MultipleKeyCodes(names) => format!("[{}]", names.join(" ")),
+        Entry::Action(name) => format!("{{{}}}", name),
+    }
+}
+
+// via.json shows every switch of both halves at its position of the matrix transform, so that
+// VIA edits the keys that exist.
+fn check_via() -> Result<(), String> {
+    println!("cargo:rerun-if-changed={}", VIA_JSON);
+    let text = fs::read_to_string(VIA_JSON).map_err(|e| format!("{}: {}", VIA_JSON, e))?;
+    let via: Via = serde_json::from_str(&text).map_err(|e| format!("{}: {}", VIA_JSON, e))?;
+    check_via_keys(&via).map_err(|e| format!("{}: {}", VIA_JSON, e))
+}
+
+fn check_via_keys(via: &Via) -> Result<(), String> {
+    if (via.matrix.rows, via.matrix.cols) != (ROWS, COLS) {
+        return Err(format!(
+            "matrix is {} rows of {} cols, expected {} of {} (src/layout_size.rs)",
+            via.matrix.rows, via.matrix.cols, ROWS, COLS
+        ));
+    }
+
+    let mut keys = BTreeSet::new();
+    for key in via.layouts.keymap.iter().flatten() {
+        let Some(label) = key.as_str() else {
+            continue;
+        };
+        let position = label
+            .lines()
+            .next()
+            .and_then(|legend| legend.split_once(','))
+            .and_then(|(row, col)| Some((row.parse::<u8>().ok()?, col.parse::<u8>().ok()?)))
+            .ok_or_else(|| format!("key '{}' is not row,col", label))?;
+        if !keys.insert(position) {
+            return Err(format!("key {},{} is listed twice", position.0, position.1));
+        }
+    }
+
+    let transforms: [fn(u8, u8) -> (u8, u8); 2] = [left_matrix_transform, right_matrix_transform];
+    let switches: BTreeSet<_> = transforms
+        .iter()
+        .flat_map(|transform| {
+            (0..TX_SIZE as u8)
+                .flat_map(move |tx| (0..RX_SIZE as u8).map(move |rx| transform(tx, rx)))
+        })
+        .collect();
+    if let Some((row, col)) = keys.difference(&switches).next() {
+        return Err(format!(
+            "key {},{} has no switch in the matrix transform (src/layout_size.rs)",
+            row, col
+        ));
+    }
+    if let Some((row, col)) = switches.difference(&keys).next() {
+        return Err(format!("switch at {},{} is not in the layout", row, col));
+    }
+    Ok(())
 }
@@ -0,0 +1,102 @@
+use eck_rs::mouse::MouseKey;
+use keyberon::action::Action;
+use usbd_hid::descriptor::{MediaKey, SystemControlKey};
+
+use crate::layout_size::{MAX_ONE_SHOTS, MAX_TAP_DANCES};
+
+// Actions not handled by keyberon. Applied in hid::keyberon_tick.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CustomAction {
+    // Consumer page usage. Reported by consumer control interface.
+    Consumer(u16),
+    // Generic desktop system control usage. Reported by system control interface.
+    System(u8),
+    NkroToggle,
+    // Caps Lock until a key which is not part of a word.
+    CapsWord,
+    // Reported by mouse interface.
+    Mouse(MouseKey),
+    // QMK keycode of a key with modifiers. Added to keyboard report while held.
+    Modded(u16),
+    // Index of a macro in the macro buffer. Played on press.
+    Macro(u8),
+    // Index of layers::TAP_DANCES. Resolved by the event router before the layout.
+    TapDance(u8),
+    // Index of layers::ONE_SHOTS. Resolved by the event router before the layout.
+    OneShot(u8),
+}
+
+const fn consumer(key: u16) -> Action<CustomAction> {
+    Action::Custom(CustomAction::Consumer(key))
+}
+
+const fn system(key: u8) -> Action<CustomAction> {
+    Action::Custom(CustomAction::System(key))
+}
+
+pub const MUTE: Action<CustomAction> = consumer(MediaKey::Mute as u16);
+pub const VOLU: Action<CustomAction> = consumer(MediaKey::VolumeIncrement as u16);
+pub const VOLD: Action<CustomAction> = consumer(MediaKey::VolumeDecrement as u16);
+pub const MPLY: Action<CustomAction> = consumer(MediaKey::PlayPause as u16);
+pub const MNXT: Action<CustomAction> = consumer(MediaKey::NextTrack as u16);
+pub const MPRV: Action<CustomAction> = consumer(MediaKey::PrevTrack as u16);
+pub const SLEP: Action<CustomAction> = system(SystemControlKey::Sleep as u8);
+pub const NKRO: Action<CustomAction> = Action::Custom(CustomAction::NkroToggle);
+pub const CW_TOGG: Action<CustomAction> = Action::Custom(CustomAction::CapsWord);
+
+const fn mouse(key: MouseKey) -> Action<CustomAction> {
+    Action::Custom(CustomAction::Mouse(key))
+}
+
+pub const MS_U: Action<CustomAction> = mouse(MouseKey::Up);
+pub const MS_D: Action<CustomAction> = mouse(MouseKey::Down);
+pub const MS_L: Action<CustomAction> = mouse(MouseKey::Left);
+pub const MS_R: Action<CustomAction> = mouse(MouseKey::Right);
+pub const WH_U: Action<CustomAction> = mouse(MouseKey::WheelUp);
+pub const WH_D: Action<CustomAction> = mouse(MouseKey::WheelDown);
+pub const WH_L: Action<CustomAction> = mouse(MouseKey::WheelLeft);
+pub const WH_R: Action<CustomAction> = mouse(MouseKey::WheelRight);
+pub const BTN1: Action<CustomAction> = mouse(MouseKey::Button(0));
+pub const BTN2: Action<CustomAction> = mouse(MouseKey::Button(1));
+pub const BTN3: Action<CustomAction> = mouse(MouseKey::Button(2));
+// Acceleration profiles in config::MOUSE_PROFILES while held.
+pub const ACL1: Action<CustomAction> = mouse(MouseKey::Profile(1));
+pub const ACL2: Action<CustomAction> = mouse(MouseKey::Profile(2));
+
+const fn macro_key(index: u8) -> Action<CustomAction> {
+    Action::Custom(CustomAction::Macro(index))
+}
+
+// Macros of keymap.toml, or as edited in VIA. Up to config::MACRO_COUNT.
+pub const M0: Action<CustomAction> = macro_key(0);
+pub const M1: Action<CustomAction> = macro_key(1);
+pub const M2: Action<CustomAction> = macro_key(2);
+pub const M3: Action<CustomAction> = macro_key(3);
+pub const M4: Action<CustomAction> = macro_key(4);
+pub const M5: Action<CustomAction> = macro_key(5);
+pub const M6: Action<CustomAction> = macro_key(6);
+pub const M7: Action<CustomAction> = macro_key(7);
+
+const fn tap_dance(index: u8) -> Action<CustomAction> {
+    Action::Custom(CustomAction::TapDance(index))
+}
+
+// Tap dances of keymap.toml.
+pub const TD0: Action<CustomAction> = tap_dance(0);
+pub const TD1: Action<CustomAction> = tap_dance(1);
+pub const TD2: Action<CustomAction> = tap_dance(2);
+pub const TD3: Action<CustomAction> = tap_dance(3);
+// One for each tap dance build.rs takes.
+const _: [Action<CustomAction>; MAX_TAP_DANCES] = [TD0, TD1, TD2, TD3];
+
+const fn one_shot(index: u8) -> Action<CustomAction> {
+    Action::Custom(CustomAction::OneShot(index))
+}
+
+// One-shots of keymap.toml.
+pub const OS0: Action<CustomAction> = one_shot(0);
+pub const OS1: Action<CustomAction> = one_shot(1);
+pub const OS2: Action<CustomAction> = one_shot(2);
+pub const OS3: Action<CustomAction> = one_shot(3);
+// One for each one-shot build.rs takes.
+const _: [Action<CustomAction>; MAX_ONE_SHOTS] = [OS0, OS1, OS2, OS3];
@@ -0,0 +1,217 @@
+// HID keyboard interface of boot subclass, so that BIOS and boot loaders find the keyboard.
+// Hosts in boot protocol only read its 8-byte reports, keys are sent here instead of NKRO.
+// embassy-usb HID class declares no subclass and rejects boot protocol.
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{debug, warn};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::InterfaceNumber;
+use embassy_usb::{Builder, Handler};
+use static_cell::StaticCell;
+use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+
+pub const REPORT_SIZE: usize = 8;
+// LED output report.
+pub const OUT_REPORT_SIZE: usize = 1;
+
+const USB_CLASS_HID: u8 = 0x03;
+const USB_SUBCLASS_BOOT: u8 = 0x01;
+const USB_PROTOCOL_KEYBOARD: u8 = 0x01;
+
+const HID_DESC_DESCTYPE_HID: u8 = 0x21;
+const HID_DESC_DESCTYPE_HID_REPORT: u8 = 0x22;
+const HID_DESC_SPEC_1_11: [u8; 2] = [0x11, 0x01];
+
+const HID_REQ_GET_REPORT: u8 = 0x01;
+const HID_REQ_GET_IDLE: u8 = 0x02;
+const HID_REQ_GET_PROTOCOL: u8 = 0x03;
+const HID_REQ_SET_REPORT: u8 = 0x09;
+const HID_REQ_SET_IDLE: u8 = 0x0A;
+const HID_REQ_SET_PROTOCOL: u8 = 0x0B;
+
+// wValue of SET_PROTOCOL and GET_PROTOCOL.
+const PROTOCOL_BOOT: u8 = 0;
+const PROTOCOL_REPORT: u8 = 1;
+
+// Set by the host. Report protocol after USB reset.
+static BOOT_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+static CONTROL: StaticCell<Control> = StaticCell::new();
+
+pub fn is_boot_protocol() -> bool {
+    BOOT_PROTOCOL.load(Ordering::Relaxed)
+}
+
+// Hosts select the protocol again after USB reset.
+fn reset() {
+    BOOT_PROTOCOL.store(false, Ordering::Relaxed);
+}
+
+pub struct BootKeyboard<'d, D: Driver<'d>> {
+    reader: BootKeyboardReader<'d, D>,
+    writer: BootKeyboardWriter<'d, D>,
+}
+
+pub struct BootKeyboardReader<'d, D: Driver<'d>> {
+    ep_out: D::EndpointOut,
+}
+
+pub struct BootKeyboardWriter<'d, D: Driver<'d>> {
+    ep_in: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> BootKeyboard<'d, D> {
+    // LED output reports come on the OUT endpoint, or with SET_REPORT to `set_leds` if the
+    // host doesn't use it.
+    pub fn new(builder: &mut Builder<'d, D>, set_leds: fn(u8)) -> Self {
+        let report_descriptor = KeyboardReport::desc();
+        let len = report_descriptor.len() as u16;
+        let hid_descriptor = [
+            HID_DESC_SPEC_1_11[0],
+            HID_DESC_SPEC_1_11[1],
+            // Country code not supported.
+            0x00,
+            // One report descriptor.
+            0x01,
+            HID_DESC_DESCTYPE_HID_REPORT,
+            len as u8,
+            (len >> 8) as u8,
+        ];
+
+        let mut func = builder.function(USB_CLASS_HID, USB_SUBCLASS_BOOT, USB_PROTOCOL_KEYBOARD);
+        let mut iface = func.interface();
+        let if_num = iface.interface_number();
+        let mut alt = iface.alt_setting(
+            USB_CLASS_HID,
+            USB_SUBCLASS_BOOT,
+            USB_PROTOCOL_KEYBOARD,
+            None,
+        );
+        alt.descriptor(HID_DESC_DESCTYPE_HID, &hid_descriptor);
+        let ep_in = alt.endpoint_interrupt_in(REPORT_SIZE as u16, 1);
+        let ep_out = alt.endpoint_interrupt_out(OUT_REPORT_SIZE as u16, 1);
+        drop(func);
+
+        let control = CONTROL.init(Control {
+            if_num,
+            report_descriptor,
+            hid_descriptor,
+            set_leds,
+        });
+        builder.handler(control);
+        Self {
+            reader: BootKeyboardReader { ep_out },
+            writer: BootKeyboardWriter { ep_in },
+        }
+    }
+
+    pub fn split(self) -> (BootKeyboardReader<'d, D>, BootKeyboardWriter<'d, D>) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<'d, D: Driver<'d>> BootKeyboardReader<'d, D> {
+    pub async fn ready(&mut self) {
+        self.ep_out.wait_enabled().await
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8; OUT_REPORT_SIZE]) -> Result<usize, EndpointError> {
+        self.ep_out.read(buf).await
+    }
+}
+
+impl<'d, D: Driver<'d>> BootKeyboardWriter<'d, D> {
+    pub async fn write(&mut self, report: &[u8; REPORT_SIZE]) -> Result<(), EndpointError> {
+        self.ep_in.wait_enabled().await;
+        self.ep_in.write(report).await
+    }
+}
+
+struct Control {
+    if_num: InterfaceNumber,
+    report_descriptor: &'static [u8],
+    // Same as in the configuration descriptor, after length and type.
+    hid_descriptor: [u8; 7],
+    set_leds: fn(u8),
+}
+
+impl Control {
+    fn is_mine(&self, req: &Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == self.if_num.0 as u16
+    }
+}
+
+impl Handler for Control {
+    fn reset(&mut self) {
+        reset();
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if !self.is_mine(&req) || req.request_type != RequestType::Class {
+            return None;
+        }
+        let response = match req.request {
+            HID_REQ_SET_PROTOCOL => match req.value as u8 {
+                PROTOCOL_BOOT | PROTOCOL_REPORT => {
+                    let boot = req.value as u8 == PROTOCOL_BOOT;
+                    debug!("USB boot protocol: {:?}", boot);
+                    BOOT_PROTOCOL.store(boot, Ordering::Relaxed);
+                    OutResponse::Accepted
+                }
+                _ => OutResponse::Rejected,
+            },
+            // Reports are sent on change only.
+            HID_REQ_SET_IDLE => OutResponse::Accepted,
+            HID_REQ_SET_REPORT => {
+                if let Some(leds) = data.first() {
+                    (self.set_leds)(*leds);
+                }
+                OutResponse::Accepted
+            }
+            _ => OutResponse::Rejected,
+        };
+        Some(response)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_mine(&req) {
+            return None;
+        }
+        let response = match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match (req.value >> 8) as u8 {
+                HID_DESC_DESCTYPE_HID_REPORT => InResponse::Accepted(self.report_descriptor),
+                HID_DESC_DESCTYPE_HID => {
+                    let len = self.hid_descriptor.len() + 2;
+                    buf[0] = len as u8;
+                    buf[1] = HID_DESC_DESCTYPE_HID;
+                    buf[2..len].copy_from_slice(&self.hid_descriptor);
+                    InResponse::Accepted(&buf[..len])
+                }
+                _ => InResponse::Rejected,
+            },
+            (RequestType::Class, HID_REQ_GET_PROTOCOL) => {
+                buf[0] = match is_boot_protocol() {
+                    true => PROTOCOL_BOOT,
+                    false => PROTOCOL_REPORT,
+                };
+                InResponse::Accepted(&buf[..1])
+            }
+            // Indefinite idle rate.
+            (RequestType::Class, HID_REQ_GET_IDLE) => {
+                buf[0] = 0;
+                InResponse::Accepted(&buf[..1])
+            }
+            // Reports go on the endpoint. Nothing pressed as far as control requests go.
+            (RequestType::Class, HID_REQ_GET_REPORT) => {
+                buf[..REPORT_SIZE].fill(0);
+                InResponse::Accepted(&buf[..REPORT_SIZE])
+            }
+            (RequestType::Class, request) => {
+                warn!("Unsupported HID request: {:?}", request);
+                InResponse::Rejected
+            }
+            _ => return None,
+        };
+        Some(response)
+    }
+}
@@ -0,0 +1,139 @@
+use defmt::*;
+use eck_rs::analog::{RxModule, TxModule};
+use eck_rs::calibration::{Calibration, CalibrationRecord, Calibrator};
+use eck_rs::scanner::ECScanner;
+use eck_rs::settings::{Error, Key, Settings};
+use eck_rs::split::CalibrationCue;
+use embassy_time::{Instant, Timer};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::comm;
+use crate::config::{
+    AdcUnit, CALIBRATION_DONE_TIMEOUT, CALIBRATION_IDLE_SCANS, CALIBRATION_MIN_TRAVEL,
+    CALIBRATION_RELEASE_DELAY, CALIBRATION_THRESHOLD_PERCENT, RX_SIZE, SCAN_DELAY, TX_SIZE,
+};
+use crate::hid;
+use crate::role;
+use crate::storage;
+use crate::SplitSide;
+
+pub type MatrixCalibration = Calibration<TX_SIZE, RX_SIZE>;
+
+// Halves have their own flash. Keyed by side so a board flashed as the other side
+// doesn't load thresholds of the other matrix.
+fn key(side: SplitSide) -> Key {
+    match side {
+        SplitSide::Left => storage::CALIBRATION_LEFT,
+        SplitSide::Right => storage::CALIBRATION_RIGHT,
+    }
+}
+
+// Saved calibration. None if missing, corrupted or recorded for other matrix size.
+pub fn load<F: NorFlash>(settings: &mut Settings<F>, side: SplitSide) -> Option<MatrixCalibration> {
+    let record = match settings.fetch::<CalibrationRecord>(key(side)) {
+        Ok(record) => record?,
+        Err(e) => {
+            error!("Failed to read calibration: {:?}", Debug2Format(&e));
+            return None;
+        }
+    };
+
+    let calibration = MatrixCalibration::from_record(&record);
+    if calibration.is_none() {
+        warn!("Saved calibration doesn't match the matrix.");
+    }
+    calibration
+}
+
+pub fn save<F: NorFlash>(
+    settings: &mut Settings<F>,
+    side: SplitSide,
+    calibration: &MatrixCalibration,
+) -> Result<(), Error<F::Error>> {
+    info!("Save calibration of {:?} half.", side);
+    settings.store(key(side), &calibration.to_record())
+}
+
+pub fn cue_text(cue: CalibrationCue) -> &'static str {
+    match cue {
+        CalibrationCue::Release => "Calibration: release all keys.\n",
+        CalibrationCue::PressAll => "Press every key to the bottom.\n",
+        CalibrationCue::Canceled => "Calibration canceled.\n",
+        CalibrationCue::Saved => "Calibration saved.\n",
+        CalibrationCue::SaveFailed => "Failed to save calibration.\n",
+    }
+}
+
+// Typed to the host by the master, for the slave too over the split link.
+fn cue(cue: CalibrationCue) {
+    match role::is_master() {
+        true => hid::type_text(cue_text(cue)),
+        false => comm::send_cue(cue),
+    }
+}
+
+// Calibrate this half and save the result. The other half keeps working.
+// Cues are typed to the host by the master. Results are logged.
+pub async fn run<TX: TxModule, RX: RxModule<AdcUnit = AdcUnit>>(
+    scanner: &mut ECScanner<TX, RX, TX_SIZE, RX_SIZE>,
+    side: SplitSide,
+    transform: fn(u8, u8) -> (u8, u8),
+    defaults: &[[AdcUnit; RX_SIZE]; TX_SIZE],
+) -> Option<MatrixCalibration> {
+    info!("Start calibration of {:?} half.", side);
+    cue(CalibrationCue::Release);
+    Timer::after(CALIBRATION_RELEASE_DELAY).await;
+
+    let mut calibrator = Calibrator::<TX_SIZE, RX_SIZE>::new(CALIBRATION_MIN_TRAVEL);
+    for _ in 0..CALIBRATION_IDLE_SCANS {
+        scan_all(scanner);
+        calibrator.sample_idle(scanner.raw_values());
+        Timer::after(SCAN_DELAY).await;
+    }
+
+    // Done when no key is pressed for a while.
+    cue(CalibrationCue::PressAll);
+    let mut last_pressed = Instant::now();
+    while last_pressed.elapsed() < CALIBRATION_DONE_TIMEOUT {
+        scan_all(scanner);
+        if calibrator.sample_pressed(scanner.raw_values()) {
+            last_pressed = Instant::now();
+        }
+        Timer::after(SCAN_DELAY).await;
+    }
+
+    if calibrator.calibrated_keys() == 0 {
+        warn!("No key pressed. Calibration canceled.");
+        cue(CalibrationCue::Canceled);
+        return None;
+    }
+
+    let calibration = calibrator.finish(CALIBRATION_THRESHOLD_PERCENT, defaults);
+    for (tx, row) in calibration.keys.iter().enumerate() {
+        for (rx, key) in row.iter().enumerate() {
+            let pos = transform(tx as u8, rx as u8);
+            let state = match calibrator.is_calibrated(tx, rx) {
+                true => "calibrated",
+                false => "default",
+            };
+            info!(
+                "Key {:?}: resting {}, bottom {}, threshold {} ({})",
+                pos, key.resting, key.bottom, key.threshold, state
+            );
+        }
+    }
+
+    match storage::with_settings(|s| save(s, side, &calibration)) {
+        Ok(()) => cue(CalibrationCue::Saved),
+        Err(e) => {
+            error!("Failed to save calibration: {:?}", Debug2Format(&e));
+            cue(CalibrationCue::SaveFailed);
+        }
+    }
+    Some(calibration)
+}
+
+// Key events are dropped while calibrating.
+fn scan_all<TX: TxModule, RX: RxModule>(scanner: &mut ECScanner<TX, RX, TX_SIZE, RX_SIZE>) {
+    while scanner.scan().is_some() {}
+}
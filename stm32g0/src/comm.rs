@@ -1,104 +1,126 @@
-use core::{default::Default, num::TryFromIntError};
+use core::{cell::Cell, default::Default};
 use defmt::*;
-use eck_rs::event::Event;
+use eck_rs::{
+    event::Event,
+    split::{
+        encode, next_seq, prev_seq, seq_not_after, CalibrationCue, ConfigSync, FrameBuf,
+        FrameDecoder, Message, PROTOCOL_VERSION,
+    },
+};
+use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
 use embassy_stm32::{
     self,
     usart::{self, RingBufferedUartRx, RxDma, TxDma, UartRx, UartTx},
 };
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Instant, Timer};
 // use embedded_io::asynch::Read;
 // use embedded_io::asynch::Write;
-use embedded_io::blocking::ReadExactError;
+use heapless::{Deque, Vec};
 use static_cell::StaticCell;
 
-use crate::event_channel::EventSender;
+use crate::calibration;
+use crate::config::{
+    RX_SIZE, SPLIT_ACK_TIMEOUT, SPLIT_ANALOG_PERIOD, SPLIT_HEARTBEAT_PERIOD, SPLIT_LINK_TIMEOUT,
+    SPLIT_MAX_RETRIES, SPLIT_MOUSE_DEPTH_PERIOD, SPLIT_THRESHOLDS_ROUNDS, TX_SIZE,
+};
+use crate::event_channel::{EventReceiver, EventSender};
+use crate::hid;
+use crate::monitor::{self, MatrixCommand};
+use crate::role::{self, RemoteRole, Role, RoleSubscriber};
+use crate::shared_state::{self, STATE_CHANGED};
 
 const RING_BUFFER_SIZE: usize = 255;
 const MSG_BUFFER_SIZE: usize = 255;
+// Max number of keys on a half.
+const HALF_KEYS: usize = TX_SIZE * RX_SIZE;
+// Enough to resend every held key after sync frame on resync.
+const RETRANSMIT_QUEUE_SIZE: usize = 32;
 static RX_RING_BUFFER: StaticCell<[u8; RING_BUFFER_SIZE]> = StaticCell::new();
 
-#[derive(Debug, Clone)]
-pub enum CommError {
-    Usart(usart::Error),
-    UsartRead(ReadExactError<usart::Error>),
-    Serialize(postcard::Error),
-    TryFromIntError(TryFromIntError),
-    NotImplemented,
-    Invailed,
+// Cumulative ack to send to the other half.
+static ACK_OUT: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+// Cumulative ack received from the other half.
+static ACK_IN: Signal<CriticalSectionRawMutex, u8> = Signal::new();
+// Link state changes detected by CommRx.
+static LINK_EVENTS: Channel<CriticalSectionRawMutex, LinkEvent, 4> = Channel::new();
+// Settings changed on this half, sent to the slave by the master.
+static CONFIG_OUT: Channel<CriticalSectionRawMutex, ConfigSync, 4> = Channel::new();
+// Calibration cues of the slave, held until the link is up.
+static CUE_OUT: Channel<CriticalSectionRawMutex, CalibrationCue, 4> = Channel::new();
+
+static LINK_STATS: Mutex<CriticalSectionRawMutex, Cell<LinkStats>> =
+    Mutex::new(Cell::new(LinkStats::new()));
+
+#[derive(defmt::Format, Debug, Clone, Copy)]
+pub struct LinkStats {
+    // frames received with valid crc.
+    pub rx_frames: u32,
+    // corrupted or truncated frames.
+    pub rx_dropped: u32,
+    // already received frames which are retransmitted.
+    pub rx_duplicates: u32,
+    // frames received after a lost frame. Discarded and wait retransmission.
+    pub rx_out_of_order: u32,
+    pub tx_frames: u32,
+    pub tx_retries: u32,
+    // frames given up after SPLIT_MAX_RETRIES.
+    pub tx_dropped: u32,
+    pub link_downs: u32,
+    // hello from the other half with different protocol version.
+    pub version_mismatches: u32,
 }
 
-impl From<usart::Error> for CommError {
-    fn from(err: usart::Error) -> Self {
-        Self::Usart(err)
+impl LinkStats {
+    const fn new() -> Self {
+        Self {
+            rx_frames: 0,
+            rx_dropped: 0,
+            rx_duplicates: 0,
+            rx_out_of_order: 0,
+            tx_frames: 0,
+            tx_retries: 0,
+            tx_dropped: 0,
+            link_downs: 0,
+            version_mismatches: 0,
+        }
     }
 }
 
-impl From<ReadExactError<usart::Error>> for CommError {
-    fn from(err: ReadExactError<usart::Error>) -> Self {
-        Self::UsartRead(err)
-    }
+pub fn link_stats() -> LinkStats {
+    LINK_STATS.lock(|s| s.get())
 }
 
-impl From<postcard::Error> for CommError {
-    fn from(err: postcard::Error) -> Self {
-        Self::Serialize(err)
+// Apply a setting of the master to the slave too. Ignored on the slave.
+pub fn sync_config(config: ConfigSync) {
+    if CONFIG_OUT.try_send(config).is_err() {
+        warn!("Config sync queue is full.");
     }
 }
 
-impl From<TryFromIntError> for CommError {
-    fn from(err: TryFromIntError) -> Self {
-        Self::TryFromIntError(err)
+// Calibration cue of the slave for the master to type.
+pub fn send_cue(cue: CalibrationCue) {
+    if CUE_OUT.try_send(cue).is_err() {
+        warn!("Calibration cue queue is full.");
     }
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
-enum ReadState {
-    #[default]
-    Header,
-    Type,
-    TxIdx,
-    RxIdx,
+fn update_stats(f: impl FnOnce(&mut LinkStats)) {
+    LINK_STATS.lock(|s| {
+        let mut stats = s.get();
+        f(&mut stats);
+        s.set(stats);
+    });
 }
 
-// index will not excess 0xff, Use it as a header.
-const HEADER_BYTE: u8 = 0xff;
-
-#[derive(Default)]
-struct ReadStateMachine {
-    state: ReadState,
-    buf: [u8; 3],
-}
-
-impl ReadStateMachine {
-    fn push(&mut self, byte: u8) -> Result<Option<Event>, CommError> {
-        if self.state != ReadState::Header && byte == HEADER_BYTE {
-            self.state = ReadState::Header;
-            return Err(CommError::Invailed);
-        }
-
-        match self.state {
-            ReadState::Header => {
-                self.state = ReadState::Type;
-            }
-            ReadState::Type => {
-                self.state = ReadState::TxIdx;
-                self.buf[0] = byte
-            }
-            ReadState::TxIdx => {
-                self.state = ReadState::RxIdx;
-                self.buf[1] = byte
-            }
-            ReadState::RxIdx => {
-                self.state = ReadState::Header;
-                return match self.buf[0] {
-                    0 => Ok(Some(Event::KeyPress(self.buf[1], byte))),
-                    1 => Ok(Some(Event::KeyRelease(self.buf[1], byte))),
-                    _ => Err(CommError::NotImplemented),
-                };
-            }
-        }
-
-        Ok(None)
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkEvent {
+    Up,
+    Down,
+    // Other half is waiting for our hello.
+    HelloRequest,
 }
 
 pub struct CommRx<'a, T, DMA>
@@ -106,9 +128,17 @@ where
     T: usart::BasicInstance,
     DMA: RxDma<T>,
 {
-    state_machine: ReadStateMachine,
+    decoder: FrameDecoder,
     uart_rx: RingBufferedUartRx<'a, T, DMA>,
     event_sender: EventSender<'a>,
+    // next in-order sequence number from the other half.
+    expected_seq: Option<u8>,
+    link_up: bool,
+    last_received: Instant,
+    // keys pressed on the other half. Released when link is lost.
+    remote_pressed: Vec<(u8, u8), HALF_KEYS>,
+    // None until the first heartbeat and after link down.
+    remote_role: Option<RemoteRole>,
 }
 
 impl<'a, T, DMA> CommRx<'a, T, DMA>
@@ -120,9 +150,14 @@ where
         let uart_buf = RX_RING_BUFFER.init([0u8; RING_BUFFER_SIZE]);
 
         Self {
-            state_machine: ReadStateMachine::default(),
+            decoder: FrameDecoder::default(),
             uart_rx: uart_rx.into_ring_buffered(uart_buf),
             event_sender,
+            expected_seq: None,
+            link_up: false,
+            last_received: Instant::now(),
+            remote_pressed: Vec::new(),
+            remote_role: None,
         }
     }
 
@@ -134,33 +169,653 @@ where
 
         loop {
             let mut buf = [0u8; MSG_BUFFER_SIZE];
-            let res = self.uart_rx.read(&mut buf).await;
+            let res = match with_timeout(SPLIT_LINK_TIMEOUT, self.uart_rx.read(&mut buf)).await {
+                Ok(res) => res,
+                Err(_) => {
+                    self.check_link().await;
+                    continue;
+                }
+            };
             if let Err(e) = res {
                 error!("UART read error: {:?}", e);
                 continue;
             }
             let len = res.unwrap();
             for byte in buf.iter().take(len) {
-                match self.state_machine.push(*byte) {
-                    Ok(Some(e)) => self.event_sender.send(e).await,
+                match self.decoder.push(*byte) {
+                    Ok(Some(msg)) => {
+                        update_stats(|s| s.rx_frames += 1);
+                        if let Some(e) = self.handle_message(msg).await {
+                            self.event_sender.send(e).await;
+                        }
+                    }
                     Ok(None) => {}
-                    Err(e) => error!("Failed to deserialzed. - {:?}", defmt::Debug2Format(&e)),
+                    Err(e) => {
+                        update_stats(|s| s.rx_dropped += 1);
+                        warn!("Failed to deserialzed. - {:?}, {:?}", e, link_stats());
+                    }
                 }
             }
+
+            // Noise keeps read returning, check link with valid frames only.
+            self.check_link().await;
+        }
+    }
+
+    async fn check_link(&mut self) {
+        if !self.link_up || self.last_received.elapsed() < SPLIT_LINK_TIMEOUT {
+            return;
+        }
+
+        update_stats(|s| s.link_downs += 1);
+        warn!("Split link down. {:?}", link_stats());
+        self.link_up = false;
+        self.expected_seq = None;
+        self.remote_role_changed(None);
+        #[cfg(feature = "analog-mouse")]
+        hid::set_remote_mouse_depth(None);
+        LINK_EVENTS.send(LinkEvent::Down).await;
+
+        // Other half will resend held keys on resync.
+        for (i, j) in self.remote_pressed.iter() {
+            self.event_sender.send(Event::KeyRelease(*i, *j)).await;
+        }
+        self.remote_pressed.clear();
+    }
+
+    // Link is up after a hello with same protocol version.
+    async fn hello(&mut self, version: u16, remote_link_up: bool) {
+        if version != PROTOCOL_VERSION {
+            update_stats(|s| s.version_mismatches += 1);
+            error!(
+                "Split protocol version mismatch. local: {}, remote: {}. Update firmware of both halves.",
+                PROTOCOL_VERSION, version
+            );
+            return;
+        }
+
+        self.last_received = Instant::now();
+        if !self.link_up {
+            info!("Split link up.");
+            self.link_up = true;
+            LINK_EVENTS.send(LinkEvent::Up).await;
+        }
+
+        if !remote_link_up {
+            LINK_EVENTS.send(LinkEvent::HelloRequest).await;
+        }
+    }
+
+    async fn handle_message(&mut self, msg: Message) -> Option<Event> {
+        if let Message::Hello { version, link_up } = msg {
+            self.hello(version, link_up).await;
+            return None;
+        }
+
+        // Ignore everything until handshake.
+        if !self.link_up {
+            return None;
+        }
+        self.last_received = Instant::now();
+
+        if let Some(seq) = msg.seq() {
+            if !self.accept_seq(seq, matches!(msg, Message::Sync(_))) {
+                return None;
+            }
+        }
+
+        match msg {
+            Message::Sync(_) | Message::Hello { .. } => None,
+            Message::Heartbeat { usb, master } => {
+                self.remote_role_changed(Some(RemoteRole { usb, master }));
+                None
+            }
+            Message::Ack(seq) => {
+                ACK_IN.signal(seq);
+                None
+            }
+            Message::Key(_, e) => match role::is_master() {
+                true => self.track_remote(e),
+                // Sent before the other half knew this half is not master.
+                false => {
+                    self.remote_pressed.clear();
+                    None
+                }
+            },
+            Message::Config(_, ConfigSync::Debounce(debounce)) if !role::is_master() => {
+                if !monitor::try_send_command(MatrixCommand::SetDebounce(debounce)) {
+                    warn!("Debounce from the master dropped.");
+                }
+                None
+            }
+            Message::State {
+                layer,
+                leds,
+                suspended,
+                monitor,
+            } => {
+                shared_state::update(|s| {
+                    s.layer = layer;
+                    s.leds = leds;
+                    s.suspended = suspended;
+                });
+                if monitor {
+                    monitor::request();
+                }
+                None
+            }
+            Message::Analog { tx, values } if role::is_master() => {
+                monitor::update_remote(tx as usize, &values, false);
+                None
+            }
+            Message::Thresholds { tx, values } if role::is_master() => {
+                monitor::update_remote(tx as usize, &values, true);
+                None
+            }
+            Message::Cue(_, cue) if role::is_master() => {
+                hid::type_text(calibration::cue_text(cue));
+                None
+            }
+            #[cfg(feature = "analog-mouse")]
+            Message::MouseDepth(depth) if role::is_master() => {
+                hid::set_remote_mouse_depth(depth);
+                None
+            }
+            msg => {
+                debug!("Unhandled split message: {:?}", defmt::Debug2Format(&msg));
+                None
+            }
+        }
+    }
+
+    fn remote_role_changed(&mut self, remote: Option<RemoteRole>) {
+        if self.remote_role != remote {
+            debug!("Remote role: {:?}", remote);
+            self.remote_role = remote;
+            role::remote_changed(remote);
+        }
+    }
+
+    // Go-Back-N receiver. Accept only the next message in order and ack it cumulatively.
+    fn accept_seq(&mut self, seq: u8, is_sync: bool) -> bool {
+        match self.expected_seq {
+            Some(expected) if expected != seq && !is_sync => {
+                match seq_not_after(seq, prev_seq(expected)) {
+                    true => update_stats(|s| s.rx_duplicates += 1),
+                    false => update_stats(|s| s.rx_out_of_order += 1),
+                }
+                // Re-ack last in-order message, the other half will retransmit rest of them.
+                ACK_OUT.signal(prev_seq(expected));
+                false
+            }
+            _ => {
+                self.expected_seq = Some(next_seq(seq));
+                ACK_OUT.signal(seq);
+                true
+            }
+        }
+    }
+
+    // Filter out press/release which are already applied. e.g. resent press after resync.
+    fn track_remote(&mut self, e: Event) -> Option<Event> {
+        let pos = match e {
+            Event::KeyPress(i, j) | Event::KeyRelease(i, j) => {
+                self.remote_pressed.iter().position(|k| *k == (i, j))
+            }
+            Event::None => return None,
+        };
+
+        match (e, pos) {
+            (Event::KeyPress(i, j), None) => {
+                if self.remote_pressed.push((i, j)).is_err() {
+                    error!("Too many keys pressed on the other half.");
+                }
+                Some(e)
+            }
+            (Event::KeyRelease(_, _), Some(pos)) => {
+                self.remote_pressed.swap_remove(pos);
+                Some(e)
+            }
+            _ => None,
         }
     }
 }
 
-// send event to other halve.
-pub async fn send<'a, T: usart::BasicInstance, DMA: TxDma<T>>(
-    e: &Event,
-    uart_tx: &mut UartTx<'a, T, DMA>,
-) -> Result<(), CommError> {
-    match e {
-        Event::KeyPress(i, j) => uart_tx.write(&[HEADER_BYTE, 0, *i, *j]).await?,
-        Event::KeyRelease(i, j) => uart_tx.write(&[HEADER_BYTE, 1, *i, *j]).await?,
-        _ => return Err(CommError::NotImplemented),
+// Depth of mouse keys on this half.
+#[cfg(feature = "analog-mouse")]
+async fn mouse_depth_changed() -> Option<u8> {
+    hid::MOUSE_DEPTH_CHANGED.wait().await
+}
+
+// Never changes without analog-mouse.
+#[cfg(not(feature = "analog-mouse"))]
+async fn mouse_depth_changed() -> Option<u8> {
+    core::future::pending().await
+}
+
+enum Trigger {
+    Event(Event),
+    AckIn(u8),
+    AckOut(u8),
+    Link(LinkEvent),
+    State,
+    Role(Role),
+    MouseDepth(Option<u8>),
+    Config(ConfigSync),
+    Cue(CalibrationCue),
+    Timer,
+}
+
+struct Pending {
+    seq: u8,
+    frame: FrameBuf,
+    retries: u8,
+}
+
+pub struct CommTx<'a, T, DMA>
+where
+    T: usart::BasicInstance,
+    DMA: TxDma<T>,
+{
+    uart_tx: UartTx<'a, T, DMA>,
+    next_seq: u8,
+    // messages waiting for ack, in sending order.
+    pending: Deque<Pending, RETRANSMIT_QUEUE_SIZE>,
+    sent_at: Instant,
+    last_sent: Instant,
+    link_up: bool,
+    // keys pressed on this half. Resent to the other half on resync.
+    held: Vec<(u8, u8), HALF_KEYS>,
+    // Raw values sent to the master for monitoring.
+    analog_at: Instant,
+    analog_rounds: u8,
+    // Depth of mouse keys on this half, sent to the master at `mouse_depth_at` if pending.
+    mouse_depth: Option<u8>,
+    mouse_depth_pending: bool,
+    mouse_depth_at: Instant,
+}
+
+impl<'a, T, DMA> CommTx<'a, T, DMA>
+where
+    T: usart::BasicInstance,
+    DMA: TxDma<T>,
+{
+    pub fn new(uart_tx: UartTx<'a, T, DMA>) -> Self {
+        Self {
+            uart_tx,
+            next_seq: 0,
+            pending: Deque::new(),
+            sent_at: Instant::now(),
+            last_sent: Instant::now(),
+            link_up: false,
+            held: Vec::new(),
+            analog_at: Instant::now(),
+            analog_rounds: 0,
+            mouse_depth: None,
+            mouse_depth_pending: false,
+            mouse_depth_at: Instant::now(),
+        }
+    }
+
+    // Master acks messages received by CommRx and pushes its state to the slave.
+    // Slave sends key events to the master and retransmits them until acked.
+    pub async fn run(&mut self, receiver: EventReceiver<'a>) {
+        info!("Start UART write task.");
+        let mut role_changes = role::subscriber();
+
+        loop {
+            match self.next_trigger(&receiver, &mut role_changes).await {
+                Trigger::Event(event) => self.send_event(event).await,
+                Trigger::AckIn(seq) => self.acked(seq),
+                Trigger::AckOut(seq) => self.send(&Message::Ack(seq)).await,
+                Trigger::Link(e) => {
+                    self.link_event(e).await;
+                    if e == LinkEvent::Up {
+                        match role::is_master() {
+                            true => self.send_state().await,
+                            false => self.resync().await,
+                        }
+                    }
+                }
+                Trigger::State => self.send_state().await,
+                Trigger::Role(role) => self.role_changed(role).await,
+                Trigger::MouseDepth(depth) => {
+                    self.mouse_depth = depth;
+                    self.mouse_depth_pending = true;
+                }
+                Trigger::Config(config) => self.send_config(config).await,
+                Trigger::Cue(cue) => self.send_reliable(|seq| Message::Cue(seq, cue)).await,
+                Trigger::Timer => {
+                    if !self.pending.is_empty() && self.sent_at.elapsed() >= SPLIT_ACK_TIMEOUT {
+                        self.retransmit().await;
+                    }
+                    if Instant::now() >= self.heartbeat_at() {
+                        self.heartbeat().await;
+                    }
+                    if self.sends_analog() && Instant::now() >= self.analog_at {
+                        self.send_analog().await;
+                    }
+                    if self.mouse_depth_pending && Instant::now() >= self.mouse_depth_at {
+                        self.send_mouse_depth().await;
+                    }
+                }
+            }
+        }
     }
 
-    Ok(())
+    async fn next_trigger(
+        &self,
+        receiver: &EventReceiver<'a>,
+        role_changes: &mut RoleSubscriber,
+    ) -> Trigger {
+        // Stop taking events until acked if queue is full.
+        let take_events = !role::is_master() && !self.pending.is_full();
+        let next_event = async {
+            match take_events {
+                true => receiver.recv().await,
+                false => core::future::pending().await,
+            }
+        };
+        // Cues wait for the link to the master.
+        let next_cue = async {
+            match self.link_up && !role::is_master() && !self.pending.is_full() {
+                true => CUE_OUT.recv().await,
+                false => core::future::pending().await,
+            }
+        };
+        let mut deadline = match self.pending.is_empty() {
+            true => self.heartbeat_at(),
+            false => self.heartbeat_at().min(self.sent_at + SPLIT_ACK_TIMEOUT),
+        };
+        if self.sends_analog() {
+            deadline = deadline.min(self.analog_at);
+        }
+        if self.mouse_depth_pending {
+            deadline = deadline.min(self.mouse_depth_at);
+        }
+
+        match select4(
+            next_event,
+            select(ACK_IN.wait(), ACK_OUT.wait()),
+            select3(
+                LINK_EVENTS.recv(),
+                STATE_CHANGED.wait(),
+                role_changes.next_message_pure(),
+            ),
+            select4(
+                mouse_depth_changed(),
+                CONFIG_OUT.recv(),
+                next_cue,
+                Timer::at(deadline),
+            ),
+        )
+        .await
+        {
+            Either4::First(event) => Trigger::Event(event),
+            Either4::Second(Either::First(seq)) => Trigger::AckIn(seq),
+            Either4::Second(Either::Second(seq)) => Trigger::AckOut(seq),
+            Either4::Third(Either3::First(e)) => Trigger::Link(e),
+            Either4::Third(Either3::Second(_)) => Trigger::State,
+            Either4::Third(Either3::Third(role)) => Trigger::Role(role),
+            Either4::Fourth(Either4::First(depth)) => Trigger::MouseDepth(depth),
+            Either4::Fourth(Either4::Second(config)) => Trigger::Config(config),
+            Either4::Fourth(Either4::Third(cue)) => Trigger::Cue(cue),
+            Either4::Fourth(Either4::Fourth(_)) => Trigger::Timer,
+        }
+    }
+
+    async fn role_changed(&mut self, role: Role) {
+        match role {
+            // Events of this half were sent to the other half. Start over as master.
+            Role::Master => {
+                self.pending.clear();
+                self.held.clear();
+            }
+            // Restart sequence for the new master.
+            Role::Slave => {
+                if self.link_up {
+                    self.resync().await;
+                }
+            }
+        }
+
+        // Let the other half know new role right away.
+        self.heartbeat().await;
+    }
+
+    async fn send_state(&mut self) {
+        if !self.link_up || !role::is_master() {
+            return;
+        }
+
+        let state = shared_state::get();
+        let msg = Message::State {
+            layer: state.layer,
+            leds: state.leds,
+            suspended: state.suspended,
+            monitor: monitor::is_requested(),
+        };
+        self.send(&msg).await;
+    }
+
+    // Slave relays raw values while the master monitors them.
+    fn sends_analog(&self) -> bool {
+        self.link_up && !role::is_master() && monitor::is_requested()
+    }
+
+    // Unreliable. A lost frame is replaced by the next round.
+    async fn send_analog(&mut self) {
+        let snapshot = monitor::snapshot();
+        let thresholds = self.analog_rounds == 0;
+        for tx in 0..TX_SIZE {
+            let values = Vec::from_slice(&snapshot.values[tx]).unwrap();
+            self.send(&Message::Analog {
+                tx: tx as u8,
+                values,
+            })
+            .await;
+            if thresholds {
+                let values = Vec::from_slice(&snapshot.thresholds[tx]).unwrap();
+                self.send(&Message::Thresholds {
+                    tx: tx as u8,
+                    values,
+                })
+                .await;
+            }
+        }
+        self.analog_rounds = (self.analog_rounds + 1) % SPLIT_THRESHOLDS_ROUNDS;
+        self.analog_at = Instant::now() + SPLIT_ANALOG_PERIOD;
+    }
+
+    // Reliable. Changes made while the link is down stay on the master.
+    async fn send_config(&mut self, config: ConfigSync) {
+        if self.link_up && role::is_master() {
+            self.send_reliable(|seq| Message::Config(seq, config)).await;
+        }
+    }
+
+    // Unreliable, repeated with heartbeat. Only the master scales mouse keys.
+    async fn send_mouse_depth(&mut self) {
+        self.mouse_depth_pending = false;
+        self.mouse_depth_at = Instant::now() + SPLIT_MOUSE_DEPTH_PERIOD;
+        if self.link_up && !role::is_master() {
+            self.send(&Message::MouseDepth(self.mouse_depth)).await;
+        }
+    }
+
+    async fn link_event(&mut self, e: LinkEvent) {
+        match e {
+            LinkEvent::Up => {
+                self.link_up = true;
+                self.send_hello().await;
+            }
+            LinkEvent::Down => {
+                // The other half releases every key of this half.
+                self.link_up = false;
+                self.pending.clear();
+            }
+            LinkEvent::HelloRequest => self.send_hello().await,
+        }
+    }
+
+    fn heartbeat_at(&self) -> Instant {
+        self.last_sent + SPLIT_HEARTBEAT_PERIOD
+    }
+
+    // Keep saying hello until handshake is done.
+    // Master resends state and slave resends mouse depth with heartbeat. A lost one is repaired
+    // in a period.
+    async fn heartbeat(&mut self) {
+        if !self.link_up {
+            self.send_hello().await;
+            return;
+        }
+
+        let heartbeat = Message::Heartbeat {
+            usb: role::usb_powered(),
+            master: role::is_master(),
+        };
+        self.send(&heartbeat).await;
+        self.send_state().await;
+        if cfg!(feature = "analog-mouse") {
+            self.send_mouse_depth().await;
+        }
+    }
+
+    async fn send_hello(&mut self) {
+        let hello = Message::Hello {
+            version: PROTOCOL_VERSION,
+            link_up: self.link_up,
+        };
+        self.send(&hello).await;
+    }
+
+    async fn send(&mut self, msg: &Message) {
+        match encode(msg) {
+            Ok(frame) => {
+                self.last_sent = Instant::now();
+                write(&mut self.uart_tx, &frame).await;
+            }
+            Err(e) => error!("Failed to serialize. - {:?}", e),
+        }
+    }
+
+    async fn send_event(&mut self, event: Event) {
+        debug!(
+            "Send event to other side: {:?}",
+            defmt::Debug2Format(&event)
+        );
+        match event {
+            Event::KeyPress(i, j) => {
+                if self.held.push((i, j)).is_err() {
+                    error!("Too many keys pressed.");
+                }
+            }
+            Event::KeyRelease(i, j) => self.held.retain(|k| *k != (i, j)),
+            Event::None => return,
+        }
+
+        // Held keys will be sent on resync.
+        if self.link_up {
+            self.send_reliable(|seq| Message::Key(seq, event)).await;
+        }
+    }
+
+    // Restart sequence and resend held keys after the link is back.
+    async fn resync(&mut self) {
+        info!("Resync split link. held keys: {}", self.held.len());
+        self.pending.clear();
+        self.send_reliable(Message::Sync).await;
+        for idx in 0..self.held.len() {
+            let (i, j) = self.held[idx];
+            self.send_reliable(|seq| Message::Key(seq, Event::KeyPress(i, j)))
+                .await;
+        }
+    }
+
+    async fn send_reliable(&mut self, msg: impl FnOnce(u8) -> Message) {
+        let seq = self.next_seq;
+        self.next_seq = next_seq(seq);
+
+        let frame = match encode(&msg(seq)) {
+            Ok(frame) => frame,
+            Err(e) => {
+                error!("Failed to serialize. - {:?}", e);
+                return;
+            }
+        };
+
+        if self.pending.is_empty() {
+            self.sent_at = Instant::now();
+        }
+        self.last_sent = Instant::now();
+        write(&mut self.uart_tx, &frame).await;
+
+        let pending = Pending {
+            seq,
+            frame,
+            retries: 0,
+        };
+        if self.pending.push_back(pending).is_err() {
+            error!("Retransmit queue overflow. seq: {}", seq);
+        }
+    }
+
+    fn acked(&mut self, seq: u8) {
+        let mut is_acked = false;
+        while let Some(p) = self.pending.front() {
+            if !seq_not_after(p.seq, seq) {
+                break;
+            }
+            self.pending.pop_front();
+            is_acked = true;
+        }
+
+        if is_acked {
+            self.sent_at = Instant::now();
+        }
+    }
+
+    // Go-Back-N. Resend every unacked message in order.
+    async fn retransmit(&mut self) {
+        let give_up = match self.pending.front_mut() {
+            Some(p) => {
+                p.retries += 1;
+                p.retries > SPLIT_MAX_RETRIES
+            }
+            None => return,
+        };
+
+        if give_up {
+            let dropped = self.pending.len() as u32;
+            self.pending.clear();
+            update_stats(|s| s.tx_dropped += dropped);
+            warn!("Split link not respond. {:?}", link_stats());
+            // Let the other half restart from our next sequence.
+            self.resync().await;
+            return;
+        }
+
+        update_stats(|s| s.tx_retries += 1);
+        warn!(
+            "Retransmit {} messages. {:?}",
+            self.pending.len(),
+            link_stats()
+        );
+        self.sent_at = Instant::now();
+        self.last_sent = Instant::now();
+        for p in self.pending.iter() {
+            write(&mut self.uart_tx, &p.frame).await;
+        }
+    }
+}
+
+async fn write<'a, T: usart::BasicInstance, DMA: TxDma<T>>(
+    uart_tx: &mut UartTx<'a, T, DMA>,
+    frame: &[u8],
+) {
+    update_stats(|s| s.tx_frames += 1);
+    if let Err(err) = uart_tx.write(frame).await {
+        error!("Usart Send Error: {:?}", defmt::Debug2Format(&err));
+    }
 }
@@ -1,7 +1,12 @@
+use eck_rs::mouse::{AccelCurve, AccelProfile};
 use embassy_stm32::gpio::{AnyPin, Output};
 use embassy_stm32::usart::{self, Parity};
 use embassy_time::Duration;
 
+pub use crate::layout_size::{
+    left_matrix_transform, right_matrix_transform, MACRO_BUFFER_SIZE, MACRO_COUNT, RX_SIZE, TX_SIZE,
+};
+
 #[macro_export]
 macro_rules! pushpull_output {
     ($pin:expr) => {
@@ -46,10 +51,99 @@ pub const USB_SERIAL_NUMBER: &str = env!("CARGO_PKG_VERSION");
 
 pub const DISCHARGE_DELAY_CLOCKS: u32 = 2500;
 pub const SCAN_DELAY: Duration = Duration::from_millis(1);
+pub const SUSPENDED_SCAN_DELAY: Duration = Duration::from_millis(10);
 pub const TICK_PERIOD: Duration = Duration::from_millis(1);
+// Report keys with N-key rollover interface. Can be changed at runtime with hid::set_nkro.
+pub const NKRO_DEFAULT: bool = cfg!(feature = "nkro");
+// Retransmit unacked split frames after this.
+pub const SPLIT_ACK_TIMEOUT: Duration = Duration::from_millis(10);
+pub const SPLIT_MAX_RETRIES: u8 = 10;
+// Both halves send heartbeat while idle. The link is down if nothing is received for timeout.
+pub const SPLIT_HEARTBEAT_PERIOD: Duration = Duration::from_millis(50);
+pub const SPLIT_LINK_TIMEOUT: Duration = Duration::from_millis(200);
+// Raw values of the slave for monitoring. Thresholds are sent every ROUNDS of values.
+pub const SPLIT_ANALOG_PERIOD: Duration = Duration::from_millis(100);
+pub const SPLIT_THRESHOLDS_ROUNDS: u8 = 10;
+// Depth of mouse keys on the slave is sent on change, at most once a period.
+pub const SPLIT_MOUSE_DEPTH_PERIOD: Duration = Duration::from_millis(10);
+// Mouse keys acceleration. First one is default, others are used while ACL1, ACL2 held.
+// Ticks are TICK_PERIOD.
+pub const MOUSE_PROFILES: [AccelProfile; 3] = [
+    AccelProfile {
+        interval: 16,
+        delay: 100,
+        time_to_max: 1000,
+        min_speed: 2,
+        max_speed: 24,
+        curve: AccelCurve::Quadratic,
+        wheel_interval: 80,
+    },
+    // Precise
+    AccelProfile {
+        interval: 16,
+        delay: 0,
+        time_to_max: 0,
+        min_speed: 1,
+        max_speed: 1,
+        curve: AccelCurve::Linear,
+        wheel_interval: 200,
+    },
+    // Fast
+    AccelProfile {
+        interval: 16,
+        delay: 0,
+        time_to_max: 300,
+        min_speed: 8,
+        max_speed: 40,
+        curve: AccelCurve::Linear,
+        wheel_interval: 30,
+    },
+];
+// Layout (row, col) of mouse movement keys. Press depth scales speed with analog-mouse feature.
+#[cfg(feature = "analog-mouse")]
+pub const MOUSE_ANALOG_KEYS: [(u8, u8); 4] = [(2, 1), (2, 2), (2, 3), (2, 4)];
+// ADC value of fully pressed key. Full speed at this depth.
+#[cfg(feature = "analog-mouse")]
+pub const MOUSE_ANALOG_FULL: AdcUnit = 3000;
+
+// Keys of a combo must all be pressed within this. Combos are in keymap.toml.
+pub const COMBO_TIMEOUT: Duration = Duration::from_millis(50);
+
+// Taps of a tap dance follow each other within this, unless the dance in keymap.toml sets
+// its own. Held past it, the dance key holds.
+pub const TAPPING_TERM: Duration = Duration::from_millis(200);
+
+// Tapped one-shots are released unused after this, unless the one-shot in keymap.toml sets
+// its own.
+pub const ONE_SHOT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Apply edited keymap to the layout when no edit for this long.
+pub const KEYMAP_APPLY_DELAY: Duration = Duration::from_millis(100);
+// Save edited keymap and macros to flash when no edit for this long.
+pub const KEYMAP_SAVE_DELAY: Duration = Duration::from_secs(2);
 
-pub const RX_SIZE: usize = 7;
-pub const TX_SIZE: usize = 4;
+// Redraw period of the console heatmap.
+pub const CONSOLE_HEATMAP_PERIOD: Duration = Duration::from_millis(100);
+// Slave sends raw values while the host has asked for them within this.
+pub const MONITOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bootmagic keys in layout (row, col). Outer keys of the second row on each half.
+// CLEAR removes handedness stored in flash and falls back to the handedness pin.
+pub const BOOTMAGIC_SCAN_TIME: Duration = Duration::from_millis(100);
+pub const BOOTMAGIC_LEFT_CLEAR: (u8, u8) = (1, 0);
+pub const BOOTMAGIC_RIGHT_CLEAR: (u8, u8) = (1, 11);
+// Starts calibration of the half.
+pub const BOOTMAGIC_LEFT_CALIBRATE: (u8, u8) = (2, 0);
+pub const BOOTMAGIC_RIGHT_CALIBRATE: (u8, u8) = (2, 11);
+// Calibration samples resting values, then bottom-out values until no key is pressed for
+// DONE_TIMEOUT. Threshold is at THRESHOLD_PERCENT of the travel.
+pub const CALIBRATION_RELEASE_DELAY: Duration = Duration::from_secs(1);
+pub const CALIBRATION_IDLE_SCANS: u32 = 500;
+pub const CALIBRATION_DONE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const CALIBRATION_MIN_TRAVEL: AdcUnit = 300;
+pub const CALIBRATION_THRESHOLD_PERCENT: u16 = 40;
+// Wait for VBUS bouncing on cable plug before electing master.
+pub const VBUS_DEBOUNCE: Duration = Duration::from_millis(50);
 
 pub type AdcUnit = u16;
 
@@ -69,20 +163,3 @@ pub fn usart_config() -> usart::Config {
     cfg.parity = Parity::ParityEven;
     cfg
 }
-
-// (tx  rx) to layout(row, col)
-pub fn left_matrix_transform(tx: u8, rx: u8) -> (u8, u8) {
-    if rx == (RX_SIZE - 1) as u8 {
-        (4, 2 + tx)
-    } else {
-        (tx, rx)
-    }
-}
-
-pub fn right_matrix_transform(tx: u8, rx: u8) -> (u8, u8) {
-    if rx == 0 {
-        (4, (TX_SIZE + RX_SIZE - 2) as u8 - tx)
-    } else {
-        (tx, rx + 5)
-    }
-}
@@ -0,0 +1,277 @@
+// Diagnostic console on USB CDC-ACM. Commands are listed in eck_rs::console::HELP.
+use core::fmt::{self, Write};
+use defmt::*;
+use eck_rs::console::{Command, LineBuffer, ParseError, SideArg, HELP};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Instant, Timer};
+use embassy_usb::driver::EndpointError;
+use heapless::String;
+
+use crate::comm;
+use crate::config::{AdcUnit, CONSOLE_HEATMAP_PERIOD, RX_SIZE, TX_SIZE};
+use crate::hid::Stm32Cdc;
+use crate::identity::{self, Identity};
+use crate::keymap;
+use crate::layers::{COLS, N_LAYERS, ROWS};
+use crate::monitor::{self, MatrixCommand};
+use crate::storage;
+use crate::SplitSide;
+
+const PACKET_SIZE: usize = 64;
+// 12 bit ADC.
+const ADC_MAX: u32 = 4095;
+
+type Line = String<256>;
+
+#[embassy_executor::task]
+pub async fn console_task(class: &'static mut Stm32Cdc<'static>) {
+    info!("Start console task.");
+    loop {
+        class.wait_connection().await;
+        info!("Console connected.");
+        let _ = session(class).await;
+        info!("Console disconnected.");
+    }
+}
+
+async fn session(class: &mut Stm32Cdc<'static>) -> Result<(), EndpointError> {
+    let mut lines = LineBuffer::new();
+    let mut packet = [0u8; PACKET_SIZE];
+    loop {
+        let n = class.read_packet(&mut packet).await?;
+        for byte in &packet[..n] {
+            let Some(line) = lines.push(*byte) else {
+                continue;
+            };
+            match Command::parse(&line) {
+                Ok(cmd) => execute(class, cmd).await?,
+                Err(ParseError::Empty) => {}
+                Err(e) => write_error(class, e.as_str()).await?,
+            }
+        }
+    }
+}
+
+async fn execute(class: &mut Stm32Cdc<'static>, cmd: Command) -> Result<(), EndpointError> {
+    debug!("Console command: {:?}", cmd);
+    let snapshot = monitor::snapshot();
+    match cmd {
+        Command::Help => write(class, HELP).await?,
+        Command::Values => write_matrix(class, &snapshot.values).await?,
+        Command::Thresholds => write_matrix(class, &snapshot.thresholds).await?,
+        Command::SetThreshold { tx, rx, value } => {
+            if tx >= TX_SIZE || rx >= RX_SIZE {
+                return write_error(class, "key out of range").await;
+            }
+            monitor::send_command(MatrixCommand::SetThreshold { tx, rx, value }).await;
+        }
+        Command::Debounce(None) => {
+            write(class, &line(format_args!("{}", snapshot.debounce))).await?;
+        }
+        Command::Debounce(Some(0)) => {
+            return write_error(class, "debounce must be 1 or more").await
+        }
+        Command::Debounce(Some(n)) => monitor::send_command(MatrixCommand::SetDebounce(n)).await,
+        Command::Heatmap => heatmap(class).await?,
+        Command::Stats => {
+            let uptime_ms = Instant::now().as_millis().max(1);
+            let rate = snapshot.scans as u64 * 1000 / uptime_ms;
+            write(class, &line(format_args!("scans {}", snapshot.scans))).await?;
+            write(class, &line(format_args!("scan_rate {}", rate))).await?;
+            write(class, &line(format_args!("events {}", snapshot.events))).await?;
+            write(
+                class,
+                &line(format_args!("max_scan_us {}", snapshot.max_scan_us)),
+            )
+            .await?;
+        }
+        Command::Link => {
+            let s = comm::link_stats();
+            let stats = [
+                ("rx_frames", s.rx_frames),
+                ("rx_dropped", s.rx_dropped),
+                ("rx_duplicates", s.rx_duplicates),
+                ("rx_out_of_order", s.rx_out_of_order),
+                ("tx_frames", s.tx_frames),
+                ("tx_retries", s.tx_retries),
+                ("tx_dropped", s.tx_dropped),
+                ("link_downs", s.link_downs),
+                ("version_mismatches", s.version_mismatches),
+            ];
+            for (name, value) in stats {
+                write(class, &line(format_args!("{} {}", name, value))).await?;
+            }
+        }
+        Command::Side(None) => {
+            let side = match storage::with_settings(identity::load) {
+                Some(Identity {
+                    side: SplitSide::Left,
+                }) => "left",
+                Some(Identity {
+                    side: SplitSide::Right,
+                }) => "right",
+                None => "pin",
+            };
+            write(class, &line(format_args!("{}", side))).await?;
+        }
+        Command::Side(Some(arg)) => {
+            let res = storage::with_settings(|s| match arg {
+                SideArg::Left => identity::store(
+                    s,
+                    Identity {
+                        side: SplitSide::Left,
+                    },
+                ),
+                SideArg::Right => identity::store(
+                    s,
+                    Identity {
+                        side: SplitSide::Right,
+                    },
+                ),
+                SideArg::Clear => identity::clear(s),
+            });
+            if let Err(e) = res {
+                error!("Failed to write identity: {:?}", Debug2Format(&e));
+                return write_error(class, "flash write failed").await;
+            }
+        }
+        Command::Size => {
+            let sizes = [
+                ("layers", N_LAYERS),
+                ("rows", ROWS),
+                ("cols", COLS),
+                ("tx", TX_SIZE),
+                ("rx", RX_SIZE),
+            ];
+            for (name, value) in sizes {
+                write(class, &line(format_args!("{} {}", name, value))).await?;
+            }
+        }
+        Command::Keycode {
+            layer,
+            row,
+            col,
+            keycode,
+        } => match (keymap::get_keycode(layer, row, col), keycode) {
+            (None, _) => return write_error(class, "key out of range").await,
+            (Some(current), None) => write(class, &line(format_args!("{}", current))).await?,
+            (Some(_), Some(keycode)) => keymap::set_keycode(layer, row, col, keycode),
+        },
+        Command::Snapshot => {
+            // Keep the other half sending values while the host polls.
+            monitor::request();
+            let (local, remote) = match snapshot.side {
+                Some(SplitSide::Left) => ("left", "right"),
+                Some(SplitSide::Right) => ("right", "left"),
+                None => return write_error(class, "matrix not scanned yet").await,
+            };
+            write_half(class, local, &snapshot.values, &snapshot.thresholds).await?;
+            if let Some(r) = monitor::remote() {
+                write_half(class, remote, &r.values, &r.thresholds).await?;
+            }
+        }
+    }
+    write(class, "ok\n").await
+}
+
+// Redraw until any input. Keys over the threshold are marked with '*'.
+async fn heatmap(class: &mut Stm32Cdc<'static>) -> Result<(), EndpointError> {
+    let mut packet = [0u8; PACKET_SIZE];
+    write(class, "\x1b[2J").await?;
+    loop {
+        let snapshot = monitor::snapshot();
+        write(class, "\x1b[H").await?;
+        for (values, thresholds) in snapshot.values.iter().zip(snapshot.thresholds.iter()) {
+            let mut row = Line::new();
+            for (value, threshold) in values.iter().zip(thresholds.iter()) {
+                // Grayscale background of 256 color palette.
+                let shade = 232 + (*value as u32).min(ADC_MAX) * 23 / ADC_MAX;
+                let mark = if value > threshold { '*' } else { ' ' };
+                let _ = write!(row, "\x1b[48;5;{}m{:>5}{}\x1b[0m", shade, value, mark);
+            }
+            let _ = row.push('\n');
+            write(class, &row).await?;
+        }
+
+        match select(
+            class.read_packet(&mut packet),
+            Timer::after(CONSOLE_HEATMAP_PERIOD),
+        )
+        .await
+        {
+            Either::First(res) => return res.map(|_| ()),
+            Either::Second(_) => {}
+        }
+    }
+}
+
+async fn write_matrix(
+    class: &mut Stm32Cdc<'static>,
+    matrix: &[[AdcUnit; RX_SIZE]; TX_SIZE],
+) -> Result<(), EndpointError> {
+    for row in matrix {
+        let mut text = Line::new();
+        for value in row {
+            let _ = write!(text, "{:>5}", value);
+        }
+        let _ = text.push('\n');
+        write(class, &text).await?;
+    }
+    Ok(())
+}
+
+// Lines of "<side> values|thresholds <tx> <rx values>".
+async fn write_half(
+    class: &mut Stm32Cdc<'static>,
+    side: &str,
+    values: &[[AdcUnit; RX_SIZE]; TX_SIZE],
+    thresholds: &[[AdcUnit; RX_SIZE]; TX_SIZE],
+) -> Result<(), EndpointError> {
+    for (name, matrix) in [("values", values), ("thresholds", thresholds)] {
+        for (tx, row) in matrix.iter().enumerate() {
+            let mut text = Line::new();
+            let _ = write!(text, "{} {} {}", side, name, tx);
+            for value in row {
+                let _ = write!(text, " {}", value);
+            }
+            let _ = text.push('\n');
+            write(class, &text).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn write_error(class: &mut Stm32Cdc<'static>, reason: &str) -> Result<(), EndpointError> {
+    write(class, &line(format_args!("error: {}", reason))).await
+}
+
+fn line(args: fmt::Arguments) -> Line {
+    let mut line = Line::new();
+    let _ = line.write_fmt(args);
+    let _ = line.push('\n');
+    line
+}
+
+// Write text in packets. Terminals need CR before LF.
+async fn write(class: &mut Stm32Cdc<'static>, text: &str) -> Result<(), EndpointError> {
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut len = 0;
+    for byte in text.bytes() {
+        if byte == b'\n' {
+            packet[len] = b'\r';
+            len += 1;
+            if len == PACKET_SIZE {
+                class.write_packet(&packet).await?;
+                len = 0;
+            }
+        }
+        packet[len] = byte;
+        len += 1;
+        if len == PACKET_SIZE {
+            class.write_packet(&packet).await?;
+            len = 0;
+        }
+    }
+    // Short packet ends the transfer. Zero length if the last one was full.
+    class.write_packet(&packet[..len]).await
+}
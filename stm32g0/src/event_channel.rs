@@ -9,7 +9,13 @@ pub type EventSender<'a> = Sender<'a, NoopRawMutex, Event, EVENT_CHANNEL_SIZE>;
 
 const EVENT_CHANNEL_SIZE: usize = 20;
 static EVENT_CHANNEL: StaticCell<EventChannel> = StaticCell::new();
+// Events of this half forwarded to the master half while slave.
+static SPLIT_EVENT_CHANNEL: StaticCell<EventChannel> = StaticCell::new();
 
 pub fn init() -> &'static mut EventChannel {
     EVENT_CHANNEL.init(EventChannel::new())
 }
+
+pub fn init_split() -> &'static mut EventChannel {
+    SPLIT_EVENT_CHANNEL.init(EventChannel::new())
+}
@@ -1,49 +1,101 @@
-use defmt::{debug, error, info};
+#[cfg(feature = "analog-mouse")]
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use defmt::{debug, error, info, warn};
+use eck_rs::caps_word::CapsWord;
+use eck_rs::macros::Player;
+use eck_rs::mouse::{MouseKeys, MouseState};
+use eck_rs::report::{
+    extra_report, NkroReport, CONSUMER_REPORT_ID, EXTRA_REPORT_DESCRIPTOR, EXTRA_REPORT_SIZE,
+    NKRO_REPORT_DESCRIPTOR, NKRO_REPORT_SIZE, SYSTEM_REPORT_ID,
+};
+use embassy_futures::select::select;
 use embassy_stm32::{peripherals, usb::Driver};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+#[cfg(feature = "analog-mouse")]
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+#[cfg(feature = "analog-mouse")]
 use embassy_sync::signal::Signal;
-use embassy_time::Timer;
-use embassy_usb::class::hid::{HidReader, HidReaderWriter, HidWriter, State};
+use embassy_time::{Instant, Timer};
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use embassy_usb::class::hid::{HidReaderWriter, HidWriter, ReadError, State};
+use embassy_usb::driver::EndpointError;
 use embassy_usb::{Builder, Config, Handler};
+use heapless::Vec;
+use keyberon::key_code::KbHidReport;
+use keyberon::layout::CustomEvent;
 
 use static_cell::StaticCell;
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
+use usbd_hid::descriptor::{MouseReport, SerializedDescriptor};
 
+use crate::action::CustomAction;
+use crate::boot_keyboard::{self, BootKeyboard, BootKeyboardReader, BootKeyboardWriter};
 use crate::config::{
-    TICK_PERIOD, USB_MANUFACTURER, USB_PID, USB_PRODUCT, USB_SERIAL_NUMBER, USB_VID,
+    MACRO_BUFFER_SIZE, MOUSE_PROFILES, NKRO_DEFAULT, TICK_PERIOD, USB_MANUFACTURER, USB_PID,
+    USB_PRODUCT, USB_SERIAL_NUMBER, USB_VID,
 };
+use crate::keycode;
+use crate::macros;
+use crate::role::{self, Role};
+use crate::via::{self, VIA_REPORT_DESCRIPTOR, VIA_REPORT_SIZE};
 use {defmt_rtt as _, panic_probe as _};
 
-const READ_N: usize = 1;
-const WRITE_N: usize = 8;
+const NKRO_WRITE_N: usize = NKRO_REPORT_SIZE;
+const MAX_PRESSED_USAGES: usize = 4;
+const TYPING_QUEUE_SIZE: usize = 4;
 
 //Type alias for generic USB types.
 pub type Stm32UsbDriver<'a> = Driver<'a, peripherals::USB>;
-pub type Stm32HidReaderWriter<'a> = HidReaderWriter<'a, Stm32UsbDriver<'a>, READ_N, WRITE_N>;
-pub type Stm32HidWriter<'a> = HidWriter<'a, Stm32UsbDriver<'a>, WRITE_N>;
-pub type Stm32HidReader<'a> = HidReader<'a, Stm32UsbDriver<'a>, READ_N>;
+pub type Stm32HidWriter<'a> = BootKeyboardWriter<'a, Stm32UsbDriver<'a>>;
+pub type Stm32HidReader<'a> = BootKeyboardReader<'a, Stm32UsbDriver<'a>>;
+pub type Stm32NkroWriter<'a> = HidWriter<'a, Stm32UsbDriver<'a>, NKRO_WRITE_N>;
+pub type Stm32ExtraWriter<'a> = HidWriter<'a, Stm32UsbDriver<'a>, EXTRA_REPORT_SIZE>;
+pub type Stm32MouseWriter<'a> = HidWriter<'a, Stm32UsbDriver<'a>, 5>;
+pub type Stm32RawHid<'a> =
+    HidReaderWriter<'a, Stm32UsbDriver<'a>, VIA_REPORT_SIZE, VIA_REPORT_SIZE>;
+pub type Stm32Cdc<'a> = CdcAcmClass<'a, Stm32UsbDriver<'a>>;
 pub type Stm32UsbDevice<'a> = embassy_usb::UsbDevice<'a, Stm32UsbDriver<'a>>;
 
 pub struct UsbHid<'a> {
     pub reader: Stm32HidReader<'a>,
     pub writer: Stm32HidWriter<'a>,
+    pub nkro_writer: Stm32NkroWriter<'a>,
+    pub extra_writer: Stm32ExtraWriter<'a>,
+    pub mouse_writer: Stm32MouseWriter<'a>,
+    pub raw_hid: Stm32RawHid<'a>,
+    pub console: Stm32Cdc<'a>,
     pub device: Stm32UsbDevice<'a>,
 }
 
-static CONFIGURED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
-static SUSPENDED: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+// Load/store only. thumbv6m has no atomic read-modify-write.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+static NKRO_ENABLED: AtomicBool = AtomicBool::new(NKRO_DEFAULT);
+// Analog depth of mouse movement keys on this half from the scan task, and on the slave half.
+#[cfg(feature = "analog-mouse")]
+static MOUSE_DEPTH: Mutex<CriticalSectionRawMutex, Cell<(Option<u8>, Option<u8>)>> =
+    Mutex::new(Cell::new((None, None)));
+// Signaled when depth on this half changes. The slave sends it to the master.
+#[cfg(feature = "analog-mouse")]
+pub static MOUSE_DEPTH_CHANGED: Signal<CriticalSectionRawMutex, Option<u8>> = Signal::new();
+static TYPING: Channel<CriticalSectionRawMutex, &'static str, TYPING_QUEUE_SIZE> = Channel::new();
 
 // Store everything on static.
 static USB_CONFIG: StaticCell<Config> = StaticCell::new();
 static USB_BUFFER: StaticCell<UsbBuffer> = StaticCell::new();
-static USB_STATE: StaticCell<State> = StaticCell::new();
+static NKRO_STATE: StaticCell<State> = StaticCell::new();
+static EXTRA_STATE: StaticCell<State> = StaticCell::new();
+static MOUSE_STATE: StaticCell<State> = StaticCell::new();
+static RAW_HID_STATE: StaticCell<State> = StaticCell::new();
+static CONSOLE_STATE: StaticCell<cdc_acm::State> = StaticCell::new();
 static USB_HID: StaticCell<UsbHid> = StaticCell::new();
 static DEVICE_HANDLER: StaticCell<DeviceStateHandler> = StaticCell::new();
 
 // embassy-usb DeviceBuilder needs some buffers for building the descriptors.
+// The configuration descriptor of six functions with IADs is about 254 bytes.
 struct UsbBuffer {
     device_descriptor: [u8; 256],
-    config_descriptor: [u8; 256],
+    config_descriptor: [u8; 512],
     bos_descriptor: [u8; 256],
     msos_descriptor: [u8; 256],
     control_buf: [u8; 64],
@@ -53,7 +105,7 @@ impl UsbBuffer {
     pub fn new() -> Self {
         Self {
             device_descriptor: [0u8; 256],
-            config_descriptor: [0u8; 256],
+            config_descriptor: [0u8; 512],
             bos_descriptor: [0u8; 256],
             msos_descriptor: [0u8; 256],
             control_buf: [0u8; 64],
@@ -69,11 +121,15 @@ pub fn init(driver: Stm32UsbDriver<'static>) -> &'static mut UsbHid<'static> {
     config.serial_number = Some(USB_SERIAL_NUMBER);
     config.max_power = 100;
     config.max_packet_size_0 = 64;
+    // Interface association descriptors for the CDC console.
+    config.device_class = 0xEF;
+    config.device_sub_class = 0x02;
+    config.device_protocol = 0x01;
+    config.composite_with_iads = true;
 
     let buffer = USB_BUFFER.init(UsbBuffer::new());
 
     // Create embassy-usb DeviceBuilder using the driver and config.
-    let state = USB_STATE.init(State::new());
     let mut builder = Builder::new(
         driver,
         *config,
@@ -88,27 +144,166 @@ pub fn init(driver: Stm32UsbDriver<'static>) -> &'static mut UsbHid<'static> {
     builder.handler(handler);
 
     // Create classes on the builder.
-    let config = embassy_usb::class::hid::Config {
-        report_descriptor: KeyboardReport::desc(),
+    // STM32G0 has 7 endpoints besides EP0, each with an IN and an OUT direction. Every IN is
+    // taken: boot keyboard, NKRO, extra, mouse, raw HID and CDC notification and data. OUT are
+    // boot keyboard, raw HID and CDC data. Another IN endpoint panics in build().
+    let (reader, writer) = BootKeyboard::new(&mut builder, set_leds).split();
+
+    // Keys are sent to this interface instead of boot keyboard when NKRO is enabled, unless
+    // the host is in boot protocol.
+    let nkro_config = embassy_usb::class::hid::Config {
+        report_descriptor: NKRO_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: 32,
+    };
+    let nkro_state = NKRO_STATE.init(State::new());
+    let nkro_writer = Stm32NkroWriter::new(&mut builder, nkro_state, nkro_config);
+
+    // Media and power keys. One interface saves an endpoint.
+    let extra_config = embassy_usb::class::hid::Config {
+        report_descriptor: EXTRA_REPORT_DESCRIPTOR,
+        request_handler: None,
+        poll_ms: 10,
+        max_packet_size: 8,
+    };
+    let extra_state = EXTRA_STATE.init(State::new());
+    let extra_writer = Stm32ExtraWriter::new(&mut builder, extra_state, extra_config);
+
+    let mouse_config = embassy_usb::class::hid::Config {
+        report_descriptor: MouseReport::desc(),
+        request_handler: None,
+        poll_ms: 1,
+        max_packet_size: 8,
+    };
+    let mouse_state = MOUSE_STATE.init(State::new());
+    let mouse_writer = Stm32MouseWriter::new(&mut builder, mouse_state, mouse_config);
+
+    // Keymap editor.
+    let raw_hid_config = embassy_usb::class::hid::Config {
+        report_descriptor: VIA_REPORT_DESCRIPTOR,
         request_handler: None,
         poll_ms: 1,
-        max_packet_size: 64,
+        max_packet_size: VIA_REPORT_SIZE as u16,
     };
+    let raw_hid_state = RAW_HID_STATE.init(State::new());
+    let raw_hid = Stm32RawHid::new(&mut builder, raw_hid_state, raw_hid_config);
 
-    let rw = Stm32HidReaderWriter::new(&mut builder, state, config);
-    let (reader, writer) = rw.split();
+    // Diagnostic console.
+    let console_state = CONSOLE_STATE.init(cdc_acm::State::new());
+    let console = Stm32Cdc::new(&mut builder, console_state, 64);
     let device = builder.build();
 
     // Build the builder.
     USB_HID.init(UsbHid {
         reader,
         writer,
+        nkro_writer,
+        extra_writer,
+        mouse_writer,
+        raw_hid,
+        console,
         device,
     })
 }
 
-pub async fn wait_until_configured() {
-    while let false = CONFIGURED.wait().await {}
+pub fn is_configured() -> bool {
+    CONFIGURED.load(Ordering::Relaxed)
+}
+
+pub fn set_nkro(enabled: bool) {
+    info!("NKRO enabled: {:?}", enabled);
+    NKRO_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn is_nkro_enabled() -> bool {
+    NKRO_ENABLED.load(Ordering::Relaxed)
+}
+
+// BIOS and boot loaders only read the boot keyboard.
+fn use_nkro() -> bool {
+    is_nkro_enabled() && !boot_keyboard::is_boot_protocol()
+}
+
+// Type text to the host, e.g. cues of calibration. Only the master has a host.
+pub fn type_text(text: &'static str) {
+    if !role::is_master() {
+        return;
+    }
+    if TYPING.try_send(text).is_err() {
+        warn!("Typing queue is full.");
+    }
+}
+
+// None if no mouse movement key is pressed on this half.
+#[cfg(feature = "analog-mouse")]
+pub fn set_mouse_depth(depth: Option<u8>) {
+    let changed = MOUSE_DEPTH.lock(|d| {
+        let (local, remote) = d.get();
+        d.set((depth, remote));
+        local != depth
+    });
+    if changed {
+        MOUSE_DEPTH_CHANGED.signal(depth);
+    }
+}
+
+// Received from the slave. None after link down.
+#[cfg(feature = "analog-mouse")]
+pub fn set_remote_mouse_depth(depth: Option<u8>) {
+    MOUSE_DEPTH.lock(|d| d.set((d.get().0, depth)));
+}
+
+// Deepest press on either half. None for full speed.
+#[cfg(feature = "analog-mouse")]
+fn mouse_depth() -> Option<u8> {
+    MOUSE_DEPTH.lock(|d| {
+        let (local, remote) = d.get();
+        local.max(remote)
+    })
+}
+
+fn set_leds(leds: u8) {
+    debug!("USB LED report: {:?}", leds);
+    // Slave follows the master.
+    if role::is_master() {
+        crate::shared_state::update(|s| s.leds = leds);
+    }
+}
+
+// Read LED output reports from the host. Forwarded to the slave with shared state.
+#[embassy_executor::task]
+pub async fn led_report_task(reader: &'static mut Stm32HidReader<'static>) {
+    info!("Start LED report task.");
+    let mut buf = [0u8; boot_keyboard::OUT_REPORT_SIZE];
+    loop {
+        reader.ready().await;
+        match reader.read(&mut buf).await {
+            Ok(_) => set_leds(buf[0]),
+            Err(EndpointError::Disabled) => {}
+            Err(e) => warn!("USB LED report error: {:?}", e),
+        }
+    }
+}
+
+// Handle VIA requests. Keymap changes are applied to the layout once edits settle.
+#[embassy_executor::task]
+pub async fn via_task(raw_hid: &'static mut Stm32RawHid<'static>) {
+    info!("Start VIA task.");
+    let mut report = [0u8; VIA_REPORT_SIZE];
+    loop {
+        raw_hid.ready().await;
+        match raw_hid.read(&mut report).await {
+            Ok(_) => {
+                via::handle(&mut report);
+                if let Err(e) = raw_hid.write(&report).await {
+                    error!("USB VIA report error: {}", e);
+                }
+            }
+            Err(ReadError::Disabled) => {}
+            Err(e) => warn!("USB VIA read error: {:?}", e),
+        }
+    }
 }
 
 struct DeviceStateHandler {}
@@ -122,84 +317,314 @@ impl DeviceStateHandler {
 impl Handler for DeviceStateHandler {
     fn enabled(&mut self, enabled: bool) {
         debug!("USB enabled: {:?}", enabled);
-        CONFIGURED.signal(false);
-        SUSPENDED.signal(false);
+        CONFIGURED.store(false, Ordering::Relaxed);
+        crate::shared_state::update(|s| s.suspended = false);
     }
 
     fn reset(&mut self) {
         debug!("USB reset");
-        CONFIGURED.signal(false);
+        CONFIGURED.store(false, Ordering::Relaxed);
     }
 
     fn addressed(&mut self, _addr: u8) {
         debug!("USB addressed");
-        CONFIGURED.signal(false);
+        CONFIGURED.store(false, Ordering::Relaxed);
     }
 
     fn configured(&mut self, configured: bool) {
         debug!("USB configured: {:?}", configured);
-        CONFIGURED.signal(configured);
+        CONFIGURED.store(configured, Ordering::Relaxed);
     }
 
     fn suspended(&mut self, suspended: bool) {
         debug!("USB suspended: {:?}", suspended);
-        if suspended {
-            SUSPENDED.signal(true);
-        } else {
-            SUSPENDED.signal(false);
-        }
+        crate::shared_state::update(|s| s.suspended = suspended);
     }
 }
 
 #[embassy_executor::task]
 pub async fn usb_device_task(device: &'static mut Stm32UsbDevice<'static>) {
-    // Run the USB device.
+    // Run the USB device only while this half is master.
     info!("Start USB device task.");
-    device.run().await;
+    let mut role_changes = role::subscriber();
+    loop {
+        while !role::is_master() {
+            role_changes.next_message_pure().await;
+        }
+
+        let lost_master =
+            async { while let Role::Master = role_changes.next_message_pure().await {} };
+        select(device.run(), lost_master).await;
+
+        info!("Disable USB device.");
+        device.disable().await;
+        CONFIGURED.store(false, Ordering::Relaxed);
+    }
 }
 
 pub struct KeyberonTickRes<'a> {
     hid_writer: &'a mut Stm32HidWriter<'a>,
+    nkro_writer: &'a mut Stm32NkroWriter<'a>,
+    extra_writer: &'a mut Stm32ExtraWriter<'a>,
+    mouse_writer: &'a mut Stm32MouseWriter<'a>,
     layout: &'a crate::layers::SharedLayout,
+    mouse: MouseKeys<{ MOUSE_PROFILES.len() }>,
+    // Pressed usages. The last one is reported, report has a single usage.
+    consumer_pressed: Vec<u16, MAX_PRESSED_USAGES>,
+    system_pressed: Vec<u8, MAX_PRESSED_USAGES>,
+    // Keys with modifiers from keymap editor.
+    modded_pressed: Vec<u16, MAX_PRESSED_USAGES>,
+    // Text being typed and the position. Each character is pressed for a tick, then released.
+    typing: Option<(&'static str, usize)>,
+    // Macro being played. Keeps running after its key is released.
+    macro_player: Player<MACRO_BUFFER_SIZE>,
+    caps_word: CapsWord,
 }
 
 impl<'a> KeyberonTickRes<'a> {
     pub fn new(
         hid_writer: &'a mut Stm32HidWriter<'a>,
+        nkro_writer: &'a mut Stm32NkroWriter<'a>,
+        extra_writer: &'a mut Stm32ExtraWriter<'a>,
+        mouse_writer: &'a mut Stm32MouseWriter<'a>,
         layout: &'a crate::layers::SharedLayout,
     ) -> Self {
-        Self { hid_writer, layout }
+        Self {
+            hid_writer,
+            nkro_writer,
+            extra_writer,
+            mouse_writer,
+            layout,
+            mouse: MouseKeys::new(MOUSE_PROFILES),
+            consumer_pressed: Vec::new(),
+            system_pressed: Vec::new(),
+            modded_pressed: Vec::new(),
+            typing: None,
+            macro_player: Player::new(keycode::from_ascii),
+            caps_word: CapsWord::default(),
+        }
+    }
+
+    // Keycode of the character typed in this tick.
+    fn typed_key(&mut self) -> Option<u16> {
+        if self.typing.is_none() {
+            self.typing = TYPING.try_receive().ok().map(|text| (text, 0));
+        }
+
+        let (text, pos) = self.typing.as_mut()?;
+        let Some(c) = text.as_bytes().get(*pos / 2).copied() else {
+            self.typing = None;
+            return None;
+        };
+        let pressed = *pos % 2 == 0;
+        *pos += 1;
+        match pressed {
+            true => keycode::from_ascii(c),
+            false => None,
+        }
+    }
+
+    fn custom_event(&mut self, event: CustomEvent<CustomAction>) {
+        match event {
+            CustomEvent::Press(CustomAction::Consumer(usage)) => {
+                press(&mut self.consumer_pressed, *usage)
+            }
+            CustomEvent::Release(CustomAction::Consumer(usage)) => {
+                self.consumer_pressed.retain(|u| u != usage)
+            }
+            CustomEvent::Press(CustomAction::System(usage)) => {
+                press(&mut self.system_pressed, *usage)
+            }
+            CustomEvent::Release(CustomAction::System(usage)) => {
+                self.system_pressed.retain(|u| u != usage)
+            }
+            CustomEvent::Press(CustomAction::NkroToggle) => set_nkro(!is_nkro_enabled()),
+            CustomEvent::Press(CustomAction::CapsWord) => self.caps_word.toggle(),
+            CustomEvent::Press(CustomAction::Mouse(key)) => self.mouse.press(*key),
+            CustomEvent::Release(CustomAction::Mouse(key)) => self.mouse.release(*key),
+            CustomEvent::Press(CustomAction::Modded(kc)) => press(&mut self.modded_pressed, *kc),
+            CustomEvent::Release(CustomAction::Modded(kc)) => {
+                self.modded_pressed.retain(|k| k != kc)
+            }
+            CustomEvent::Press(CustomAction::Macro(index)) => {
+                if !macros::with_macro(*index, |bytes| self.macro_player.start(bytes)) {
+                    warn!("Macro {} ignored, another one is playing.", index);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn write_extra(&mut self, report_id: u8, usage_id: u16) -> bool {
+        debug!("USB extra report: {:?}, {:?}", report_id, usage_id);
+        match self
+            .extra_writer
+            .write(&extra_report(report_id, usage_id))
+            .await
+        {
+            Ok(_) => true,
+            Err(e) => {
+                error!("USB extra report error: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn write_mouse(&mut self, state: MouseState) {
+        let report = MouseReport {
+            buttons: state.buttons,
+            x: state.x,
+            y: state.y,
+            wheel: state.wheel,
+            pan: state.pan,
+        };
+        if let Err(e) = self.mouse_writer.write_serialize(&report).await {
+            error!("USB mouse report error: {}", e);
+        }
+    }
+
+    async fn write_boot(&mut self, keyberon_report: &KbHidReport) -> bool {
+        let bytes = keyberon_report.as_bytes();
+        let mut report = [0u8; boot_keyboard::REPORT_SIZE];
+        report.copy_from_slice(bytes);
+
+        debug!("USB report: {:?}", bytes);
+        match self.hid_writer.write(&report).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("USB hid report error: {}", e);
+                false
+            }
+        }
+    }
+
+    async fn write_nkro(&mut self, report: &NkroReport) -> bool {
+        debug!("USB NKRO report: {:?}", report.as_bytes());
+        match self.nkro_writer.write(report.as_bytes()).await {
+            Ok(_) => true,
+            Err(e) => {
+                error!("USB NKRO report error: {}", e);
+                false
+            }
+        }
     }
 }
 
 #[embassy_executor::task]
 pub async fn keyberon_tick(res: &'static mut KeyberonTickRes<'static>) {
-    let mut cur_report: keyberon::key_code::KbHidReport =
-        res.layout.lock(|l| l.borrow().keycodes().collect());
+    let mut cur_report = KbHidReport::default();
+    let mut cur_nkro_report = NkroReport::default();
+    let mut cur_nkro = use_nkro();
+    let mut cur_consumer = 0u16;
+    let mut cur_system = 0u8;
 
     loop {
-        // send key report to USB HID
-        let keyberon_report: keyberon::key_code::KbHidReport = res.layout.lock(|l| {
-            l.borrow_mut().tick();
-            l.borrow().keycodes().collect()
+        let custom_event = res.layout.lock(|l| l.borrow_mut().tick());
+        res.custom_event(custom_event);
+
+        let typed = res.typed_key();
+        res.macro_player.tick(Instant::now().as_millis());
+        let modded = &res.modded_pressed;
+        let played = &res.macro_player;
+        let caps_word = &mut res.caps_word;
+        let caps_lock = crate::shared_state::get().caps_lock();
+        let (keyberon_report, nkro_report, layer) = res.layout.lock(|l| {
+            let l = l.borrow();
+            let keycodes = || {
+                l.keycodes().chain(
+                    modded
+                        .iter()
+                        .copied()
+                        .chain(typed)
+                        .chain(played.pressed())
+                        .flat_map(keycode::modded_key_codes),
+                )
+            };
+            caps_word.keys(keycodes());
+            let caps_tap = caps_word.tick(caps_lock);
+            let keycodes = || keycodes().chain(caps_tap);
+            let report: KbHidReport = keycodes().collect();
+            let nkro_report: NkroReport = keycodes().collect();
+            (report, nkro_report, l.current_layer())
         });
 
-        if cur_report != keyberon_report {
-            let bytes = keyberon_report.as_bytes();
-            let report = KeyboardReport {
-                modifier: bytes[0],
-                reserved: 0,
-                leds: bytes[1],
-                keycodes: [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]],
-            };
+        // Slave follows the layer of master.
+        let is_master = role::is_master();
+        if is_master {
+            crate::shared_state::update(|s| s.layer = layer as u8);
+        }
 
-            debug!("USB report: {:?}", bytes);
-            if let Err(e) = res.hid_writer.write_serialize(&report).await {
-                error!("USB hid report error: {}", e);
-            };
+        // Layout is reset on role change. Nothing is released.
+        if !is_master {
+            res.consumer_pressed.clear();
+            res.system_pressed.clear();
+            res.modded_pressed.clear();
+            res.mouse.release_all();
+            res.typing = None;
+            res.macro_player.stop();
+            res.caps_word = CapsWord::default();
+        }
+
+        #[cfg(feature = "analog-mouse")]
+        res.mouse.set_depth(mouse_depth());
+        let mouse_state = res.mouse.tick();
+
+        // Keep the last sent report until the host is ready. It is sent after configured.
+        if !is_master || !is_configured() {
+            Timer::after(TICK_PERIOD).await;
+            continue;
+        }
+
+        // Release keys on the interface not used anymore and send every key on the other.
+        let nkro = use_nkro();
+        if nkro != cur_nkro {
+            match cur_nkro {
+                // Hosts in boot protocol don't read the NKRO interface.
+                true if !boot_keyboard::is_boot_protocol() => {
+                    res.write_nkro(&NkroReport::default()).await;
+                }
+                true => {}
+                false => {
+                    res.write_boot(&KbHidReport::default()).await;
+                }
+            }
+            cur_report = KbHidReport::default();
+            cur_nkro_report = NkroReport::default();
+            cur_nkro = nkro;
+        }
+
+        match nkro {
+            true if cur_nkro_report != nkro_report => {
+                if res.write_nkro(&nkro_report).await {
+                    cur_nkro_report = nkro_report;
+                }
+            }
+            false if cur_report != keyberon_report => {
+                if res.write_boot(&keyberon_report).await {
+                    cur_report = keyberon_report;
+                }
+            }
+            _ => {}
+        }
+
+        let consumer = res.consumer_pressed.last().copied().unwrap_or(0);
+        if consumer != cur_consumer && res.write_extra(CONSUMER_REPORT_ID, consumer).await {
+            cur_consumer = consumer;
+        }
+        let system = res.system_pressed.last().copied().unwrap_or(0);
+        if system != cur_system && res.write_extra(SYSTEM_REPORT_ID, system as u16).await {
+            cur_system = system;
+        }
+        if let Some(state) = mouse_state {
+            res.write_mouse(state).await;
         }
 
-        cur_report = keyberon_report;
         Timer::after(TICK_PERIOD).await;
     }
 }
+
+fn press<T: PartialEq, const N: usize>(pressed: &mut Vec<T, N>, usage: T) {
+    if !pressed.contains(&usage) && pressed.push(usage).is_err() {
+        warn!("Too many custom keys pressed.");
+    }
+}
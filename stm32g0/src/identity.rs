@@ -0,0 +1,53 @@
+use defmt::*;
+use eck_rs::settings::{Error, Settings};
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::storage::IDENTITY;
+use crate::SplitSide;
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub side: SplitSide,
+}
+
+impl Identity {
+    fn to_byte(self) -> u8 {
+        match self.side {
+            SplitSide::Left => 0,
+            SplitSide::Right => 1,
+        }
+    }
+
+    fn from_byte(side: u8) -> Option<Self> {
+        let side = match side {
+            0 => SplitSide::Left,
+            1 => SplitSide::Right,
+            _ => return None,
+        };
+        Some(Self { side })
+    }
+}
+
+pub fn load<F: NorFlash>(settings: &mut Settings<F>) -> Option<Identity> {
+    match settings.fetch::<u8>(IDENTITY) {
+        Ok(side) => side.and_then(Identity::from_byte),
+        Err(e) => {
+            error!("Failed to read identity: {:?}", Debug2Format(&e));
+            None
+        }
+    }
+}
+
+pub fn store<F: NorFlash>(
+    settings: &mut Settings<F>,
+    identity: Identity,
+) -> Result<(), Error<F::Error>> {
+    info!("Store identity: {:?}", identity);
+    settings.store(IDENTITY, &identity.to_byte())
+}
+
+// Fall back to the handedness pin.
+pub fn clear<F: NorFlash>(settings: &mut Settings<F>) -> Result<(), Error<F::Error>> {
+    info!("Clear identity.");
+    settings.remove(IDENTITY)
+}
@@ -0,0 +1,279 @@
+// Conversion between keyberon actions and QMK keycodes used by VIA.
+use defmt::*;
+use eck_rs::mouse::MouseKey;
+use keyberon::{action::Action, key_code::KeyCode};
+use usbd_hid::descriptor::{MediaKey, SystemControlKey};
+
+use crate::action::CustomAction;
+use crate::config::MACRO_COUNT;
+use crate::layers::{ONE_SHOTS, SPECIAL_ACTIONS, TAP_DANCES, VIRTUAL_KEYS};
+
+pub const KC_NO: u16 = 0x0000;
+pub const KC_TRNS: u16 = 0x0001;
+const KC_SYSTEM_POWER: u16 = 0x00A5;
+const KC_SYSTEM_SLEEP: u16 = 0x00A6;
+const KC_SYSTEM_WAKE: u16 = 0x00A7;
+const KC_AUDIO_MUTE: u16 = 0x00A8;
+const KC_AUDIO_VOL_UP: u16 = 0x00A9;
+const KC_AUDIO_VOL_DOWN: u16 = 0x00AA;
+const KC_MEDIA_NEXT_TRACK: u16 = 0x00AB;
+const KC_MEDIA_PREV_TRACK: u16 = 0x00AC;
+const KC_MEDIA_STOP: u16 = 0x00AD;
+const KC_MEDIA_PLAY_PAUSE: u16 = 0x00AE;
+const KC_MS_UP: u16 = 0x00CD;
+const KC_MS_DOWN: u16 = 0x00CE;
+const KC_MS_LEFT: u16 = 0x00CF;
+const KC_MS_RIGHT: u16 = 0x00D0;
+const KC_MS_BTN1: u16 = 0x00D1;
+const KC_MS_BTN5: u16 = 0x00D5;
+const KC_MS_WH_UP: u16 = 0x00D9;
+const KC_MS_WH_DOWN: u16 = 0x00DA;
+const KC_MS_WH_LEFT: u16 = 0x00DB;
+const KC_MS_WH_RIGHT: u16 = 0x00DC;
+const KC_MS_ACCEL0: u16 = 0x00DD;
+const KC_MS_ACCEL2: u16 = 0x00DF;
+// Key with modifiers. bit 8-11: Ctrl, Shift, Alt, Gui, bit 12: right modifiers.
+const QK_MODS: u16 = 0x0100;
+const QK_MODS_MAX: u16 = 0x1FFF;
+const QK_LSFT: u16 = 0x0200;
+const QK_TO: u16 = 0x5200;
+const QK_MOMENTARY: u16 = 0x5220;
+const QK_DEF_LAYER: u16 = 0x5240;
+// OSL(n) and OSM(mods), same mod bits as QK_MODS from bit 0. Bound to layers::ONE_SHOTS of the
+// same key.
+const QK_ONE_SHOT_LAYER: u16 = 0x5280;
+const QK_ONE_SHOT_MOD: u16 = 0x52A0;
+// TD(n). Index of layers::TAP_DANCES.
+const QK_TAP_DANCE: u16 = 0x5700;
+const QK_MAGIC_TOGGLE_NKRO: u16 = 0x7013;
+// MACRO00.. Index of the VIA macro buffer.
+const QK_MACRO: u16 = 0x7700;
+const QK_CAPS_WORD_TOGGLE: u16 = 0x7C73;
+// KB_0.. Keyboard specific keycodes. Index of layers::SPECIAL_ACTIONS.
+const QK_KB: u16 = 0x7E00;
+
+type KAction = Action<CustomAction>;
+
+pub fn to_action(keycode: u16) -> KAction {
+    let custom = |action| Action::Custom(action);
+    let consumer = |key: MediaKey| custom(CustomAction::Consumer(key as u16));
+    let system = |key: SystemControlKey| custom(CustomAction::System(key as u8));
+    let mouse = |key| custom(CustomAction::Mouse(key));
+
+    match keycode {
+        KC_NO => Action::NoOp,
+        KC_TRNS => Action::Trans,
+        0x0004..=0x00A4 | 0x00E0..=0x00E7 => match key_code(keycode as u8) {
+            Some(kc) => Action::KeyCode(kc),
+            None => Action::NoOp,
+        },
+        KC_SYSTEM_POWER => system(SystemControlKey::PowerDown),
+        KC_SYSTEM_SLEEP => system(SystemControlKey::Sleep),
+        KC_SYSTEM_WAKE => system(SystemControlKey::WakeUp),
+        KC_AUDIO_MUTE => consumer(MediaKey::Mute),
+        KC_AUDIO_VOL_UP => consumer(MediaKey::VolumeIncrement),
+        KC_AUDIO_VOL_DOWN => consumer(MediaKey::VolumeDecrement),
+        KC_MEDIA_NEXT_TRACK => consumer(MediaKey::NextTrack),
+        KC_MEDIA_PREV_TRACK => consumer(MediaKey::PrevTrack),
+        KC_MEDIA_STOP => consumer(MediaKey::Stop),
+        KC_MEDIA_PLAY_PAUSE => consumer(MediaKey::PlayPause),
+        KC_MS_UP => mouse(MouseKey::Up),
+        KC_MS_DOWN => mouse(MouseKey::Down),
+        KC_MS_LEFT => mouse(MouseKey::Left),
+        KC_MS_RIGHT => mouse(MouseKey::Right),
+        KC_MS_BTN1..=KC_MS_BTN5 => mouse(MouseKey::Button((keycode - KC_MS_BTN1) as u8)),
+        KC_MS_WH_UP => mouse(MouseKey::WheelUp),
+        KC_MS_WH_DOWN => mouse(MouseKey::WheelDown),
+        KC_MS_WH_LEFT => mouse(MouseKey::WheelLeft),
+        KC_MS_WH_RIGHT => mouse(MouseKey::WheelRight),
+        KC_MS_ACCEL0..=KC_MS_ACCEL2 => mouse(MouseKey::Profile((keycode - KC_MS_ACCEL0) as u8)),
+        QK_MODS..=QK_MODS_MAX => custom(CustomAction::Modded(keycode)),
+        k if k & 0xFFE0 == QK_MOMENTARY => Action::Layer((k & 0x1F) as usize),
+        // No exclusive layer in keyberon. TO(n) switches default layer.
+        k if k & 0xFFE0 == QK_TO || k & 0xFFE0 == QK_DEF_LAYER => {
+            Action::DefaultLayer((k & 0x1F) as usize)
+        }
+        k if k & 0xFFE0 == QK_ONE_SHOT_LAYER || k & 0xFFE0 == QK_ONE_SHOT_MOD => {
+            match (0..ONE_SHOTS.len() as u8).find(|i| one_shot_keycode(*i) == Some(k)) {
+                Some(index) => custom(CustomAction::OneShot(index)),
+                None => {
+                    warn!("No one-shot of keycode {:#06x} in keymap.toml", k);
+                    Action::NoOp
+                }
+            }
+        }
+        k if k >= QK_TAP_DANCE && ((k - QK_TAP_DANCE) as usize) < TAP_DANCES.len() => {
+            custom(CustomAction::TapDance((k - QK_TAP_DANCE) as u8))
+        }
+        QK_MAGIC_TOGGLE_NKRO => custom(CustomAction::NkroToggle),
+        QK_CAPS_WORD_TOGGLE => custom(CustomAction::CapsWord),
+        k if k >= QK_MACRO && k - QK_MACRO < MACRO_COUNT as u16 => {
+            custom(CustomAction::Macro((k - QK_MACRO) as u8))
+        }
+        k if k >= QK_KB && ((k - QK_KB) as usize) < SPECIAL_ACTIONS.len() => {
+            SPECIAL_ACTIONS[(k - QK_KB) as usize]
+        }
+        k => {
+            warn!("Unsupported keycode: {:#06x}", k);
+            Action::NoOp
+        }
+    }
+}
+
+pub fn from_action(action: &KAction) -> u16 {
+    match action {
+        Action::NoOp => KC_NO,
+        Action::Trans => KC_TRNS,
+        Action::KeyCode(kc) => *kc as u16,
+        Action::Layer(l) => QK_MOMENTARY | *l as u16,
+        Action::DefaultLayer(l) => QK_DEF_LAYER | *l as u16,
+        Action::Custom(custom) => from_custom(custom),
+        action => match SPECIAL_ACTIONS.iter().position(|a| a == action) {
+            Some(idx) => QK_KB + idx as u16,
+            None => {
+                warn!("No keycode for action: {:?}", Debug2Format(action));
+                KC_NO
+            }
+        },
+    }
+}
+
+fn from_custom(action: &CustomAction) -> u16 {
+    match action {
+        CustomAction::Consumer(usage) => match *usage {
+            u if u == MediaKey::Mute as u16 => KC_AUDIO_MUTE,
+            u if u == MediaKey::VolumeIncrement as u16 => KC_AUDIO_VOL_UP,
+            u if u == MediaKey::VolumeDecrement as u16 => KC_AUDIO_VOL_DOWN,
+            u if u == MediaKey::NextTrack as u16 => KC_MEDIA_NEXT_TRACK,
+            u if u == MediaKey::PrevTrack as u16 => KC_MEDIA_PREV_TRACK,
+            u if u == MediaKey::Stop as u16 => KC_MEDIA_STOP,
+            u if u == MediaKey::PlayPause as u16 => KC_MEDIA_PLAY_PAUSE,
+            _ => KC_NO,
+        },
+        CustomAction::System(usage) => match *usage {
+            u if u == SystemControlKey::PowerDown as u8 => KC_SYSTEM_POWER,
+            u if u == SystemControlKey::Sleep as u8 => KC_SYSTEM_SLEEP,
+            u if u == SystemControlKey::WakeUp as u8 => KC_SYSTEM_WAKE,
+            _ => KC_NO,
+        },
+        CustomAction::NkroToggle => QK_MAGIC_TOGGLE_NKRO,
+        CustomAction::CapsWord => QK_CAPS_WORD_TOGGLE,
+        CustomAction::Mouse(key) => match key {
+            MouseKey::Up => KC_MS_UP,
+            MouseKey::Down => KC_MS_DOWN,
+            MouseKey::Left => KC_MS_LEFT,
+            MouseKey::Right => KC_MS_RIGHT,
+            MouseKey::WheelUp => KC_MS_WH_UP,
+            MouseKey::WheelDown => KC_MS_WH_DOWN,
+            MouseKey::WheelLeft => KC_MS_WH_LEFT,
+            MouseKey::WheelRight => KC_MS_WH_RIGHT,
+            MouseKey::Button(b) => KC_MS_BTN1 + *b as u16,
+            MouseKey::Profile(p) => KC_MS_ACCEL0 + *p as u16,
+        },
+        CustomAction::Modded(keycode) => *keycode,
+        CustomAction::Macro(index) => QK_MACRO + *index as u16,
+        CustomAction::TapDance(index) => QK_TAP_DANCE + *index as u16,
+        CustomAction::OneShot(index) => one_shot_keycode(*index).unwrap_or_else(|| {
+            warn!("No keycode for one-shot {}", index);
+            KC_NO
+        }),
+    }
+}
+
+// OSL(n) or OSM(mods) of the nth one-shot. None if its key is not a layer or modifiers of one
+// side.
+fn one_shot_keycode(index: u8) -> Option<u16> {
+    let one_shot = ONE_SHOTS.get(index as usize)?;
+    match VIRTUAL_KEYS.get(one_shot.key.1 as usize)? {
+        Action::Layer(l) => Some(QK_ONE_SHOT_LAYER | *l as u16),
+        Action::KeyCode(kc) => mod_bits(&[*kc]).map(|mods| QK_ONE_SHOT_MOD | mods),
+        Action::MultipleKeyCodes(kcs) => mod_bits(kcs).map(|mods| QK_ONE_SHOT_MOD | mods),
+        _ => None,
+    }
+}
+
+// Mod bits of QK_MODS from bit 0. None unless every key is a modifier of the same side.
+fn mod_bits(kcs: &[KeyCode]) -> Option<u16> {
+    let mut bits = 0;
+    let mut right = None;
+    for kc in kcs {
+        let code = *kc as u8;
+        if !(KeyCode::LCtrl as u8..=KeyCode::RGui as u8).contains(&code) {
+            return None;
+        }
+        let is_right = code >= KeyCode::RCtrl as u8;
+        if right.replace(is_right).is_some_and(|r| r != is_right) {
+            return None;
+        }
+        bits |= 1 << (code & 0x03);
+    }
+    Some(bits | if right? { 0x10 } else { 0 })
+}
+
+// Keycodes pressed by a modded key. e.g. LSFT(KC_1)
+pub fn modded_key_codes(keycode: u16) -> impl Iterator<Item = KeyCode> {
+    let mods = ((keycode >> 8) & 0x0F) as u8;
+    let first = match keycode & 0x1000 != 0 {
+        true => KeyCode::RCtrl as u8,
+        false => KeyCode::LCtrl as u8,
+    };
+    (0..4)
+        .filter(move |bit| mods & (1 << bit) != 0)
+        .filter_map(move |bit| key_code(first + bit))
+        .chain(key_code(keycode as u8))
+}
+
+// Keycode typing an ASCII character on US layout. Shifted characters are modded keys.
+pub fn from_ascii(c: u8) -> Option<u16> {
+    let keycode = match c {
+        b'a'..=b'z' => 0x04 + (c - b'a') as u16,
+        b'A'..=b'Z' => QK_LSFT | (0x04 + (c - b'A') as u16),
+        b'1'..=b'9' => 0x1E + (c - b'1') as u16,
+        b'0' => 0x27,
+        b'!' => QK_LSFT | 0x1E,
+        b'@' => QK_LSFT | 0x1F,
+        b'#' => QK_LSFT | 0x20,
+        b'$' => QK_LSFT | 0x21,
+        b'%' => QK_LSFT | 0x22,
+        b'^' => QK_LSFT | 0x23,
+        b'&' => QK_LSFT | 0x24,
+        b'*' => QK_LSFT | 0x25,
+        b'(' => QK_LSFT | 0x26,
+        b')' => QK_LSFT | 0x27,
+        b'\n' => 0x28,
+        b'\t' => 0x2B,
+        b' ' => 0x2C,
+        b'-' => 0x2D,
+        b'_' => QK_LSFT | 0x2D,
+        b'=' => 0x2E,
+        b'+' => QK_LSFT | 0x2E,
+        b'[' => 0x2F,
+        b'{' => QK_LSFT | 0x2F,
+        b']' => 0x30,
+        b'}' => QK_LSFT | 0x30,
+        b'\\' => 0x31,
+        b'|' => QK_LSFT | 0x31,
+        b';' => 0x33,
+        b':' => QK_LSFT | 0x33,
+        b'\'' => 0x34,
+        b'"' => QK_LSFT | 0x34,
+        b'`' => 0x35,
+        b'~' => QK_LSFT | 0x35,
+        b',' => 0x36,
+        b'<' => QK_LSFT | 0x36,
+        b'.' => 0x37,
+        b'>' => QK_LSFT | 0x37,
+        b'/' => 0x38,
+        b'?' => QK_LSFT | 0x38,
+        _ => return None,
+    };
+    Some(keycode)
+}
+
+fn key_code(code: u8) -> Option<KeyCode> {
+    match code {
+        // SAFETY: KeyCode is repr(u8) HID usage. Every value in these ranges is a variant.
+        0x04..=0xA4 | 0xE0..=0xE7 => Some(unsafe { core::mem::transmute::<u8, KeyCode>(code) }),
+        _ => None,
+    }
+}
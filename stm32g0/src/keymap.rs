@@ -0,0 +1,213 @@
+use core::cell::RefCell;
+use core::ptr::{addr_of, addr_of_mut};
+use defmt::*;
+use eck_rs::settings::{Error, Settings};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::with_timeout;
+use embedded_storage::nor_flash::NorFlash;
+use keyberon::action::Action;
+
+use crate::action::CustomAction;
+use crate::config::{KEYMAP_APPLY_DELAY, KEYMAP_SAVE_DELAY};
+use crate::keycode;
+use crate::layers::{
+    Layout, LayoutLayers, SharedLayout, COLS, LAYERS, LAYOUT_ROWS, N_LAYERS, ROWS, VIRTUAL_KEYS,
+    VIRTUAL_ROW,
+};
+use crate::storage;
+
+// QMK keycodes of every key. Edited by keymap editors and applied to the layout.
+pub type Keymap = [[[u16; COLS]; ROWS]; N_LAYERS];
+
+// Size of keymap buffer in VIA. 2 bytes per key, big endian.
+pub const KEYMAP_BUFFER_SIZE: usize = N_LAYERS * ROWS * COLS * 2;
+
+const NOOP: Action<CustomAction> = Action::NoOp;
+static EMPTY_LAYERS: LayoutLayers = [[[NOOP; COLS]; LAYOUT_ROWS]; N_LAYERS];
+
+// Layers of the running layout. Built from KEYMAP and VIRTUAL_KEYS.
+// Only written in rebuild() while no layout refers to it.
+static mut DYNAMIC_LAYERS: LayoutLayers = [[[NOOP; COLS]; LAYOUT_ROWS]; N_LAYERS];
+
+static KEYMAP: Mutex<ThreadModeRawMutex, RefCell<Keymap>> =
+    Mutex::new(RefCell::new([[[keycode::KC_NO; COLS]; ROWS]; N_LAYERS]));
+// Edited keymap waiting to be applied and saved.
+static KEYMAP_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Compiled LAYERS as keycodes.
+pub fn factory() -> Keymap {
+    let mut keymap = [[[keycode::KC_NO; COLS]; ROWS]; N_LAYERS];
+    for (l, layer) in LAYERS.iter().enumerate() {
+        for (r, row) in layer.iter().enumerate() {
+            for (c, action) in row.iter().enumerate() {
+                keymap[l][r][c] = keycode::from_action(action);
+            }
+        }
+    }
+    keymap
+}
+
+// Empty until apply() at boot.
+pub fn new_shared_layout() -> SharedLayout {
+    Mutex::new(RefCell::new(Layout::new(&EMPTY_LAYERS)))
+}
+
+// Layouts of DYNAMIC_LAYERS are created here only and live in a SharedLayout.
+fn new_layout() -> Layout {
+    // SAFETY: rebuild() writes DYNAMIC_LAYERS only after replacing the layout in the lock.
+    Layout::new(unsafe { &*addr_of!(DYNAMIC_LAYERS) })
+}
+
+// Layers of the running layout. Taking the layout, borrowed from its SharedLayout, keeps
+// rebuild() from replacing it meanwhile.
+pub fn with_layers<R>(_layout: &Layout, f: impl FnOnce(&LayoutLayers) -> R) -> R {
+    // SAFETY: rebuild() replaces the layout in its RefCell before writing, which panics while
+    // the layout is borrowed.
+    f(unsafe { &*addr_of!(DYNAMIC_LAYERS) })
+}
+
+// Release every key, e.g. after a role change.
+pub fn reset(layout: &SharedLayout) {
+    layout.lock(|cell| cell.replace(new_layout()));
+}
+
+pub fn keymap() -> Keymap {
+    KEYMAP.lock(|k| *k.borrow())
+}
+
+// Replace keymap and rebuild the layout, e.g. at boot.
+pub fn apply(layout: &SharedLayout, keymap: &Keymap) {
+    KEYMAP.lock(|k| *k.borrow_mut() = *keymap);
+    rebuild(layout, keymap);
+}
+
+// Keys held are released.
+fn rebuild(layout: &SharedLayout, keymap: &Keymap) {
+    layout.lock(|cell| {
+        cell.replace(Layout::new(&EMPTY_LAYERS));
+
+        // SAFETY: The only layout of DYNAMIC_LAYERS was in the cell, and a borrow of it would
+        // have panicked the replace above.
+        let layers = unsafe { &mut *addr_of_mut!(DYNAMIC_LAYERS) };
+        for (l, layer) in layers.iter_mut().enumerate() {
+            for (r, row) in layer[..ROWS].iter_mut().enumerate() {
+                for (c, action) in row.iter_mut().enumerate() {
+                    *action = keycode::to_action(keymap[l][r][c]);
+                }
+            }
+            layer[VIRTUAL_ROW] = VIRTUAL_KEYS;
+        }
+
+        cell.replace(new_layout());
+    });
+    debug!("Keymap applied.");
+}
+
+// Applied and saved by keymap_edit_task once edits settle. Edits come in bursts from keymap
+// editors, and rebuilding the layout releases held keys.
+pub fn edit(keymap: &Keymap) {
+    KEYMAP.lock(|k| *k.borrow_mut() = *keymap);
+    KEYMAP_CHANGED.signal(());
+}
+
+// Saved keymap. None if not saved or saved with other layout size.
+pub fn load<F: NorFlash>(settings: &mut Settings<F>) -> Option<Keymap> {
+    match settings.fetch::<Keymap>(storage::KEYMAP) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            error!("Failed to read keymap: {:?}", Debug2Format(&e));
+            None
+        }
+    }
+}
+
+pub fn save<F: NorFlash>(
+    settings: &mut Settings<F>,
+    keymap: &Keymap,
+) -> Result<(), Error<F::Error>> {
+    settings.store(storage::KEYMAP, keymap)
+}
+
+// Rebuild the layout once a burst of edits is over, and save once no edit comes for a while.
+#[embassy_executor::task]
+pub async fn keymap_edit_task(layout: &'static SharedLayout) {
+    info!("Start keymap edit task.");
+    loop {
+        KEYMAP_CHANGED.wait().await;
+        loop {
+            while with_timeout(KEYMAP_APPLY_DELAY, KEYMAP_CHANGED.wait())
+                .await
+                .is_ok()
+            {}
+            rebuild(layout, &keymap());
+            if with_timeout(KEYMAP_SAVE_DELAY, KEYMAP_CHANGED.wait())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        let keymap = keymap();
+        match storage::with_settings(|s| save(s, &keymap)) {
+            Ok(()) => info!("Keymap saved."),
+            Err(e) => error!("Failed to save keymap: {:?}", Debug2Format(&e)),
+        }
+    }
+}
+
+pub fn get_keycode(layer: usize, row: usize, col: usize) -> Option<u16> {
+    if layer >= N_LAYERS || row >= ROWS || col >= COLS {
+        return None;
+    }
+    Some(KEYMAP.lock(|k| k.borrow()[layer][row][col]))
+}
+
+pub fn set_keycode(layer: usize, row: usize, col: usize, keycode: u16) {
+    if layer >= N_LAYERS || row >= ROWS || col >= COLS {
+        warn!("Key out of range: {}, {}, {}", layer, row, col);
+        return;
+    }
+
+    let mut keymap = keymap();
+    keymap[layer][row][col] = keycode;
+    edit(&keymap);
+}
+
+// (layer, row, col) of nth key in VIA buffer.
+fn key_position(key: usize) -> (usize, usize, usize) {
+    (key / (ROWS * COLS), key / COLS % ROWS, key % COLS)
+}
+
+// Read keymap as VIA buffer from offset.
+pub fn read_buffer(offset: usize, buf: &mut [u8]) {
+    let keymap = keymap();
+    for (i, byte) in buf.iter_mut().enumerate() {
+        let pos = offset + i;
+        if pos >= KEYMAP_BUFFER_SIZE {
+            break;
+        }
+
+        let (l, r, c) = key_position(pos / 2);
+        *byte = keymap[l][r][c].to_be_bytes()[pos % 2];
+    }
+}
+
+// Write VIA buffer to keymap from offset.
+pub fn write_buffer(offset: usize, data: &[u8]) {
+    let mut keymap = keymap();
+    for (i, byte) in data.iter().enumerate() {
+        let pos = offset + i;
+        if pos >= KEYMAP_BUFFER_SIZE {
+            break;
+        }
+
+        let (l, r, c) = key_position(pos / 2);
+        let mut bytes = keymap[l][r][c].to_be_bytes();
+        bytes[pos % 2] = *byte;
+        keymap[l][r][c] = u16::from_be_bytes(bytes);
+    }
+    edit(&keymap);
+}
@@ -1,4 +1,11 @@
+use crate::action::*;
+pub use crate::layout_size::{COLS, N_LAYERS, ROWS};
 use core::cell::RefCell;
+use eck_rs::combo::{Combo, Key};
+use eck_rs::macros::Step;
+use eck_rs::one_shot::OneShot;
+use eck_rs::stage::Bindings;
+use eck_rs::tap_dance::TapDance;
 use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
 use keyberon::{
     action::{
@@ -9,21 +16,20 @@ use keyberon::{
     key_code::KeyCode::*,
     layout,
 };
-pub const COLS: usize = 12;
-pub const ROWS: usize = 5;
-pub const N_LAYERS: usize = 2;
 
-pub type Layers = layout::Layers<COLS, ROWS, N_LAYERS>;
-#[allow(dead_code)]
-pub type Layout = layout::Layout<COLS, ROWS, N_LAYERS>;
+// Keys of the keymap.
+pub type Layers = layout::Layers<COLS, ROWS, N_LAYERS, CustomAction>;
 
-pub type SharedLayout = Mutex<ThreadModeRawMutex, RefCell<Layout>>;
+// The layout has a row without switches after the keymap. Tap dances and one-shots press its
+// keys.
+pub const VIRTUAL_ROW: usize = ROWS;
+pub const LAYOUT_ROWS: usize = ROWS + 1;
+pub type LayoutLayers = layout::Layers<COLS, LAYOUT_ROWS, N_LAYERS, CustomAction>;
+pub type Layout = layout::Layout<COLS, LAYOUT_ROWS, N_LAYERS, CustomAction>;
 
-pub fn new_shared_layout() -> SharedLayout {
-    Mutex::new(RefCell::new(layout::Layout::new(&LAYERS)))
-}
+pub type SharedLayout = Mutex<ThreadModeRawMutex, RefCell<Layout>>;
 
-const FNSPC: Action = HoldTap(&HoldTapAction {
+const FNSPC: Action<CustomAction> = HoldTap(&HoldTapAction {
     timeout: 200,
     tap_hold_interval: 0,
     config: HoldTapConfig::HoldOnOtherKeyPress,
@@ -31,21 +37,62 @@ const FNSPC: Action = HoldTap(&HoldTapAction {
     tap: k(Space),
 });
 
-#[rustfmt::skip]
-pub static LAYERS: Layers  = layout::layout! {
-    {
-//     | 00(L0) | 01(L1) | 02(L2) | 03(L3) | 04(L4) | 05(L5) | 06(R0) | 07(R1) | 08(R2) | 09(R3) | 10(R4) | 11(R5) |
-/*Row0*/[Grave   Kb1      Kb2      Kb3      Kb4      Kb5      Kb6      Kb7      Kb8      Kb9      Kb0      BSpace  ]
-/*Row1*/[Tab     Q        W        E        R        T        Y        U        I        O        P        Bslash  ]
-/*Row2*/[LCtrl   A        S        D        F        G        H        J        K        L        SColon   Quote   ]
-/*Row3*/[LShift  Z        X        C        V        B        N        M        Comma    Dot      Slash    RShift  ]
-/*Row4*/[No      No       No       LGui     LAlt     {FNSPC}  {FNSPC}  Enter    Down     Up       No       No     ]
-    }{
-//     | 00(L0) | 01(L1) | 02(L2) | 03(L3) | 04(L4) | 05(L5) | 06(R0) | 07(R1) | 08(R2) | 09(R3) | 10(R4) | 11(R5) |
-/*Row0*/[Escape  No       No       No       No       No       No       No       No       Minus    Equal    No      ]
-/*Row1*/[No      No       No       No       No       No       No       No       No       LBracket RBracket No      ]
-/*Row2*/[No      No       No       No       No       No       No       Left     Down     Up       Right    No      ]
-/*Row3*/[No      No       No       No       No       No       No       No       No       No       No       No      ]
-/*Row4*/[No      No       No       No       No       No       No       No       No       No       No       No      ]
+// Actions without keycode. Keymap editors use KB_0, KB_1, ... for these in order.
+pub static SPECIAL_ACTIONS: [Action<CustomAction>; 1] = [FNSPC];
+
+// Compiled from keymap.toml by build.rs.
+pub static LAYERS: Layers = include!(concat!(env!("OUT_DIR"), "/layers.rs"));
+
+// Compiled from keymap.toml by build.rs. Keys of a combo act as another key of LAYERS.
+pub static COMBOS: &[Combo] = &include!(concat!(env!("OUT_DIR"), "/combos.rs"));
+
+// Compiled from keymap.toml by build.rs. Factory macros bound as M0, M1, ... in order.
+pub static MACROS: &[&[Step]] = &include!(concat!(env!("OUT_DIR"), "/macros.rs"));
+
+// Compiled from keymap.toml by build.rs. Taps and holds of a dance are keys of VIRTUAL_KEYS.
+pub static TAP_DANCES: &[TapDance] = &include!(concat!(env!("OUT_DIR"), "/tap_dances.rs"));
+
+// Compiled from keymap.toml by build.rs. Keys of one-shots are keys of VIRTUAL_KEYS.
+pub static ONE_SHOTS: &[OneShot] = &include!(concat!(env!("OUT_DIR"), "/one_shots.rs"));
+
+// VIRTUAL_ROW of every layer.
+pub static VIRTUAL_KEYS: [Action<CustomAction>; COLS] =
+    include!(concat!(env!("OUT_DIR"), "/virtual_keys.rs"))[0][0];
+
+// Bindings of the current layer, looked up by the stages of main::event_router.
+pub struct LayoutBindings<'a>(pub &'a Layout);
+
+impl Bindings for LayoutBindings<'_> {
+    fn layer(&self) -> u8 {
+        self.0.current_layer() as u8
     }
-};
+
+    fn tap_dance(&self, (i, j): Key) -> Option<u8> {
+        match custom_action(self.0, i, j)? {
+            CustomAction::TapDance(index) => Some(index),
+            _ => None,
+        }
+    }
+
+    fn one_shot(&self, (i, j): Key) -> Option<u8> {
+        match custom_action(self.0, i, j)? {
+            CustomAction::OneShot(index) => Some(index),
+            _ => None,
+        }
+    }
+}
+
+// Transparent keys look at the base layer.
+fn custom_action(l: &Layout, i: u8, j: u8) -> Option<CustomAction> {
+    crate::keymap::with_layers(l, |layers| {
+        let at = |layer: usize| layers.get(layer)?.get(i as usize)?.get(j as usize);
+        let action = match at(l.current_layer())? {
+            Action::Trans => at(0)?,
+            action => action,
+        };
+        match action {
+            Action::Custom(custom) => Some(*custom),
+            _ => None,
+        }
+    })
+}
@@ -0,0 +1,34 @@
+// Keymap size, limits and matrix transform. Also read by build.rs to check keymap.toml and
+// via.json.
+pub const COLS: usize = 12;
+pub const ROWS: usize = 5;
+pub const N_LAYERS: usize = 2;
+
+// VIA dynamic macros, bound as M0, M1, ... Factory macros are in keymap.toml.
+pub const MACRO_COUNT: u8 = 8;
+pub const MACRO_BUFFER_SIZE: usize = 512;
+// TD0.. and OS0.. in action.rs.
+pub const MAX_TAP_DANCES: usize = 4;
+pub const MAX_ONE_SHOTS: usize = 4;
+// Keys of a combo.
+pub const MAX_COMBO_KEYS: usize = 4;
+
+pub const RX_SIZE: usize = 7;
+pub const TX_SIZE: usize = 4;
+
+// (tx  rx) to layout(row, col)
+pub fn left_matrix_transform(tx: u8, rx: u8) -> (u8, u8) {
+    if rx == (RX_SIZE - 1) as u8 {
+        (4, 2 + tx)
+    } else {
+        (tx, rx)
+    }
+}
+
+pub fn right_matrix_transform(tx: u8, rx: u8) -> (u8, u8) {
+    if rx == 0 {
+        (4, (TX_SIZE + RX_SIZE - 2) as u8 - tx)
+    } else {
+        (tx, rx + 5)
+    }
+}
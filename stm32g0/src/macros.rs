@@ -0,0 +1,111 @@
+// Macro buffer in VIA dynamic macro format, see eck_rs::macros. Played by hid::keyberon_tick.
+use core::cell::RefCell;
+use defmt::*;
+use eck_rs::macros;
+use eck_rs::settings::{Error, Settings};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, ThreadModeRawMutex};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::with_timeout;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::config::{KEYMAP_SAVE_DELAY, MACRO_BUFFER_SIZE};
+use crate::layers::MACROS;
+use crate::storage;
+
+pub type MacroBuffer = [u8; MACRO_BUFFER_SIZE];
+
+// Saved without the unused end of the buffer.
+type StoredMacros = heapless::Vec<u8, MACRO_BUFFER_SIZE>;
+
+static MACRO_BUFFER: Mutex<ThreadModeRawMutex, RefCell<MacroBuffer>> =
+    Mutex::new(RefCell::new([0; MACRO_BUFFER_SIZE]));
+// Edited macros waiting to be saved.
+static MACROS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+// Compiled MACROS as buffer. build.rs checks that they fit.
+pub fn factory() -> MacroBuffer {
+    let mut buffer = [0; MACRO_BUFFER_SIZE];
+    if let Err(e) = macros::encode(MACROS, &mut buffer) {
+        error!("Failed to encode macros: {:?}", Debug2Format(&e));
+    }
+    buffer
+}
+
+pub fn apply(buffer: &MacroBuffer) {
+    MACRO_BUFFER.lock(|b| *b.borrow_mut() = *buffer);
+}
+
+// Apply and save after a while, like keymap edits.
+pub fn edit(buffer: &MacroBuffer) {
+    apply(buffer);
+    MACROS_CHANGED.signal(());
+}
+
+// Bytes of the nth macro, empty if not defined.
+pub fn with_macro<R>(index: u8, f: impl FnOnce(&[u8]) -> R) -> R {
+    MACRO_BUFFER.lock(|b| f(macros::get(&*b.borrow(), index as usize)))
+}
+
+// Saved macros. None if not saved.
+pub fn load<F: NorFlash>(settings: &mut Settings<F>) -> Option<MacroBuffer> {
+    match settings.fetch::<StoredMacros>(storage::MACROS) {
+        Ok(stored) => stored.map(|stored| {
+            let mut buffer = [0; MACRO_BUFFER_SIZE];
+            buffer[..stored.len()].copy_from_slice(&stored);
+            buffer
+        }),
+        Err(e) => {
+            error!("Failed to read macros: {:?}", Debug2Format(&e));
+            None
+        }
+    }
+}
+
+pub fn save<F: NorFlash>(
+    settings: &mut Settings<F>,
+    buffer: &MacroBuffer,
+) -> Result<(), Error<F::Error>> {
+    let used = buffer.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    // Fits, same capacity.
+    let stored = unwrap!(StoredMacros::from_slice(&buffer[..used]));
+    settings.store(storage::MACROS, &stored)
+}
+
+// Save edited macros once edits settle.
+#[embassy_executor::task]
+pub async fn macros_save_task() {
+    info!("Start macros save task.");
+    loop {
+        MACROS_CHANGED.wait().await;
+        while with_timeout(KEYMAP_SAVE_DELAY, MACROS_CHANGED.wait())
+            .await
+            .is_ok()
+        {}
+
+        let buffer = MACRO_BUFFER.lock(|b| *b.borrow());
+        match storage::with_settings(|s| save(s, &buffer)) {
+            Ok(()) => info!("Macros saved."),
+            Err(e) => error!("Failed to save macros: {:?}", Debug2Format(&e)),
+        }
+    }
+}
+
+// Read macro buffer from offset.
+pub fn read_buffer(offset: usize, buf: &mut [u8]) {
+    MACRO_BUFFER.lock(|b| {
+        let b = b.borrow();
+        let src = b.get(offset..).unwrap_or(&[]);
+        let len = buf.len().min(src.len());
+        buf[..len].copy_from_slice(&src[..len]);
+    });
+}
+
+// Write macro buffer from offset. VIA writes the whole buffer in chunks.
+pub fn write_buffer(offset: usize, data: &[u8]) {
+    let mut buffer = MACRO_BUFFER.lock(|b| *b.borrow());
+    let dest = buffer.get_mut(offset..).unwrap_or(&mut []);
+    let len = data.len().min(dest.len());
+    dest[..len].copy_from_slice(&data[..len]);
+    edit(&buffer);
+}
@@ -1,40 +1,68 @@
 #![no_std]
 #![no_main]
 #![feature(type_alias_impl_trait)]
-use config::MatrixConfig;
+use config::{MatrixConfig, RX_SIZE, TX_SIZE};
 use defmt::*;
 use eck_rs::{
     self,
-    analog::{RxMux, TxCharger},
+    analog::{RxModule, RxMux, TxCharger, TxModule},
+    combo::Combos,
+    event::Event,
     mux::Mux8,
+    one_shot::OneShots,
     scanner::ECScanner,
+    split::ConfigSync,
+    stage::{Chain, Stage},
+    tap_dance::TapDances,
 };
 use embassy_executor::Spawner;
+use embassy_futures::select::{select3, Either3};
 use embassy_stm32::{
-    self, bind_interrupts, gpio, pac,
+    self, bind_interrupts,
+    exti::ExtiInput,
+    flash::Flash,
+    gpio, pac,
     peripherals::{self, DMA1_CH1, DMA2_CH1},
-    usart::{self, Uart, UartTx},
+    usart::{self, Uart},
     usb,
 };
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
+use heapless::Vec;
+use layout_size::MAX_COMBO_KEYS;
 use static_cell::StaticCell;
 use {defmt_rtt as _, panic_probe as _};
 
+mod action;
 mod analog;
+mod boot_keyboard;
+mod calibration;
 mod comm;
 mod config;
+mod console;
 mod event_channel;
 mod hid;
+mod identity;
+mod keycode;
+mod keymap;
 mod layers;
+mod layout_size;
+mod macros;
+mod monitor;
+mod role;
+mod shared_state;
+mod storage;
+mod via;
 
 static KEYBERON_TICK_RES: StaticCell<hid::KeyberonTickRes> = StaticCell::new();
 static SHARED_LAYOUT: StaticCell<layers::SharedLayout> = StaticCell::new();
+// Events out of the stages at once, e.g. held back presses of a combo after tap dances.
+const MAX_ROUTED_EVENTS: usize = 32;
 
 bind_interrupts!(struct UsbIrqs {
     USB_UCPD1_2 => usb::InterruptHandler<peripherals::USB>;
 });
 
-#[derive(defmt::Format, Debug)]
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
 enum SplitSide {
     Left,
     Right,
@@ -50,6 +78,7 @@ impl KeyboardStatus {
         pc6: &mut peripherals::PC6,
         pa0: &mut peripherals::PA0,
         pa8: &mut peripherals::PA8,
+        stored: Option<identity::Identity>,
     ) -> Self {
         let mut left_vbus_pin = gpio::Flex::new(pc6);
         let mut right_vbus_pin = gpio::Flex::new(pa0);
@@ -64,9 +93,11 @@ impl KeyboardStatus {
             left_vbus_pin.set_high();
         }
 
-        let split_side = match handness_pin.is_high() {
-            true => SplitSide::Left,
-            false => SplitSide::Right,
+        // Handedness in flash takes priority. Boards without the strap need it.
+        let split_side = match (stored, handness_pin.is_high()) {
+            (Some(identity), _) => identity.side,
+            (None, true) => SplitSide::Left,
+            (None, false) => SplitSide::Right,
         };
 
         let vbus_dectect = match split_side {
@@ -96,24 +127,49 @@ async fn main(spawner: Spawner) {
 
     // Declare a bounded channel  of 3 u32s.
     let channel = event_channel::init();
-    let layout = SHARED_LAYOUT.init(layers::new_shared_layout());
-
-    let status = KeyboardStatus::new(&mut p.PC6, &mut p.PA0, &mut p.PA8);
-    info!("Keyboard side: {:?}", status.split_side);
+    let layout = SHARED_LAYOUT.init(keymap::new_shared_layout());
+
+    storage::init(Flash::new_blocking(p.FLASH));
+    let stored = storage::with_settings(|s| identity::load(s));
+
+    // Compiled LAYERS is the factory keymap.
+    let saved_keymap = storage::with_settings(|s| keymap::load(s));
+    info!("Saved keymap: {:?}", saved_keymap.is_some());
+    keymap::apply(layout, &saved_keymap.unwrap_or_else(keymap::factory));
+    let saved_macros = storage::with_settings(|s| macros::load(s));
+    info!("Saved macros: {:?}", saved_macros.is_some());
+    macros::apply(&saved_macros.unwrap_or_else(macros::factory));
+    let status = KeyboardStatus::new(&mut p.PC6, &mut p.PA0, &mut p.PA8, stored);
+    info!(
+        "Keyboard side: {:?}, stored: {:?}",
+        status.split_side, stored
+    );
     info!("USB connected: {:?}", status.usb_connected);
 
-    if status.usb_connected {
-        let usb_driver = usb::Driver::new(p.USB, UsbIrqs, p.PA12, p.PA11);
-        let usb_hid = hid::init(usb_driver);
-        spawner.must_spawn(hid::usb_device_task(&mut usb_hid.device));
-
-        hid::wait_until_configured().await;
-
-        let tick_res =
-            KEYBERON_TICK_RES.init(hid::KeyberonTickRes::new(&mut usb_hid.writer, layout));
-        spawner.must_spawn(hid::keyberon_tick(tick_res));
-        spawner.must_spawn(master_event_handler(channel.receiver(), layout));
-    }
+    // USB and layout run on both halves. They are active on master only.
+    let split_channel = event_channel::init_split();
+    let usb_driver = usb::Driver::new(p.USB, UsbIrqs, p.PA12, p.PA11);
+    let usb_hid = hid::init(usb_driver);
+    spawner.must_spawn(hid::usb_device_task(&mut usb_hid.device));
+    spawner.must_spawn(hid::led_report_task(&mut usb_hid.reader));
+    spawner.must_spawn(hid::via_task(&mut usb_hid.raw_hid));
+    spawner.must_spawn(keymap::keymap_edit_task(layout));
+    spawner.must_spawn(macros::macros_save_task());
+    spawner.must_spawn(console::console_task(&mut usb_hid.console));
+
+    let tick_res = KEYBERON_TICK_RES.init(hid::KeyberonTickRes::new(
+        &mut usb_hid.writer,
+        &mut usb_hid.nkro_writer,
+        &mut usb_hid.extra_writer,
+        &mut usb_hid.mouse_writer,
+        layout,
+    ));
+    spawner.must_spawn(hid::keyberon_tick(tick_res));
+    spawner.must_spawn(event_router(
+        channel.receiver(),
+        split_channel.sender(),
+        layout,
+    ));
 
     //Run tasks
     match status.split_side {
@@ -145,11 +201,13 @@ async fn main(spawner: Spawner) {
             let (uart_tx, uart_rx) = uart.split();
 
             let comm_rx = comm::CommRx::new(uart_rx, channel.sender());
+            let comm_tx = comm::CommTx::new(uart_tx);
+            let vbus = ExtiInput::new(gpio::Input::new(p.PC6, gpio::Pull::Down), p.EXTI6);
+            let role_manager = role::RoleManager::new(vbus, SplitSide::Left, status.usb_connected);
+            spawner.must_spawn(left_role_task(role_manager));
             spawner.must_spawn(left_uart_read_task(comm_rx));
-            if !status.usb_connected {
-                spawner.must_spawn(left_slave_event_task(channel.receiver(), uart_tx))
-            }
-            main_task(matrix_cfg, adc, channel.sender()).await;
+            spawner.must_spawn(left_uart_write_task(split_channel.receiver(), comm_tx));
+            main_task(matrix_cfg, adc, channel.sender(), status.split_side).await;
         }
         SplitSide::Right => {
             bind_interrupts!(struct Irqs {
@@ -179,12 +237,14 @@ async fn main(spawner: Spawner) {
             let (uart_tx, uart_rx) = uart.split();
 
             let comm_rx = comm::CommRx::new(uart_rx, channel.sender());
+            let comm_tx = comm::CommTx::new(uart_tx);
+            let vbus = ExtiInput::new(gpio::Input::new(p.PA0, gpio::Pull::Down), p.EXTI0);
+            let role_manager = role::RoleManager::new(vbus, SplitSide::Right, status.usb_connected);
+            spawner.must_spawn(right_role_task(role_manager));
             spawner.must_spawn(right_uart_read_task(comm_rx));
-            if !status.usb_connected {
-                spawner.must_spawn(right_slave_event_task(channel.receiver(), uart_tx))
-            }
+            spawner.must_spawn(right_uart_write_task(split_channel.receiver(), comm_tx));
 
-            main_task(matrix_cfg, adc, channel.sender()).await;
+            main_task(matrix_cfg, adc, channel.sender(), status.split_side).await;
         }
     }
 }
@@ -193,6 +253,7 @@ async fn main_task<ADCPIN: embassy_stm32::adc::AdcPin<peripherals::ADC1>>(
     matrix_cfg: MatrixConfig,
     adc: analog::Adc<'static, ADCPIN>,
     event_sender: event_channel::EventSender<'static>,
+    split_side: SplitSide,
 ) {
     info!("Start main scan task.");
 
@@ -204,78 +265,274 @@ async fn main_task<ADCPIN: embassy_stm32::adc::AdcPin<peripherals::ADC1>>(
     ));
     let rx_mux = RxMux::new(mux8, adc);
     let tx_charger = TxCharger::new(matrix_cfg.drain, matrix_cfg.row_pins, discharge_delay);
+
+    // Compiled thresholds until the half is calibrated.
+    let calibration = storage::with_settings(|s| calibration::load(s, split_side));
+    info!("Calibrated: {:?}", calibration.is_some());
+    let mut calibration = calibration
+        .unwrap_or_else(|| calibration::MatrixCalibration::from_thresholds(&matrix_cfg.thresholds));
+    let debounce = storage::with_settings(|s| s.fetch::<u8>(storage::DEBOUNCE))
+        .ok()
+        .flatten()
+        .unwrap_or(matrix_cfg.nbounce);
+    let transform = matrix_cfg.transform;
     let mut scanner = ECScanner::new(
         tx_charger,
         rx_mux,
-        matrix_cfg.transform,
-        matrix_cfg.nbounce,
-        matrix_cfg.thresholds,
+        transform,
+        debounce,
+        calibration.thresholds(),
     );
 
     scanner.dischage_all();
+    let res = match bootmagic(&mut scanner, split_side).await {
+        Some(Bootmagic::ClearSide) => storage::with_settings(|s| identity::clear(s)),
+        Some(Bootmagic::Calibrate) => {
+            let defaults = matrix_cfg.thresholds;
+            if let Some(c) = calibration::run(&mut scanner, split_side, transform, &defaults).await
+            {
+                scanner.set_thresholds(c.thresholds());
+                calibration = c;
+            }
+            Ok(())
+        }
+        None => Ok(()),
+    };
+    if let Err(e) = res {
+        error!("Failed to write identity: {:?}", Debug2Format(&e));
+    }
 
     loop {
+        let start = Instant::now();
+        let mut events = 0;
         while let Some(e) = scanner.scan() {
             event_sender.send(e).await;
+            events += 1;
+        }
+        let scan_us = start.elapsed().as_micros() as u32;
+
+        monitor::update(|m| {
+            m.values = *scanner.raw_values();
+            m.thresholds = *scanner.thresholds();
+            m.side = Some(split_side);
+            m.debounce = scanner.debounce();
+            m.scans = m.scans.wrapping_add(1);
+            m.events = m.events.wrapping_add(events);
+            m.max_scan_us = m.max_scan_us.max(scan_us);
+        });
+        while let Some(cmd) = monitor::try_receive_command() {
+            apply_matrix_command(cmd, &mut scanner, &mut calibration, split_side);
         }
 
+        #[cfg(feature = "analog-mouse")]
+        hid::set_mouse_depth(mouse_depth(
+            scanner.raw_values(),
+            scanner.thresholds(),
+            transform,
+        ));
+
+        // Scan slowly while the host is sleeping. Still fast enough to wake it up.
+        let delay = match shared_state::get().suspended {
+            true => config::SUSPENDED_SCAN_DELAY,
+            false => config::SCAN_DELAY,
+        };
+        Timer::after(delay).await;
+    }
+}
+
+// Deepest press of mouse movement keys on this half. None if none of them is pressed.
+#[cfg(feature = "analog-mouse")]
+fn mouse_depth(
+    values: &[[config::AdcUnit; RX_SIZE]; TX_SIZE],
+    thresholds: &[[config::AdcUnit; RX_SIZE]; TX_SIZE],
+    transform: fn(u8, u8) -> (u8, u8),
+) -> Option<u8> {
+    let mut depth = None;
+    for (tx, row) in values.iter().enumerate() {
+        for (rx, value) in row.iter().enumerate() {
+            let threshold = thresholds[tx][rx];
+            let pos = transform(tx as u8, rx as u8);
+            if *value <= threshold || !config::MOUSE_ANALOG_KEYS.contains(&pos) {
+                continue;
+            }
+
+            let full = config::MOUSE_ANALOG_FULL.max(threshold + 1);
+            let d = (*value.min(&full) - threshold) as u32 * eck_rs::mouse::DEPTH_FULL as u32
+                / (full - threshold) as u32;
+            depth = depth.max(Some(d as u8));
+        }
+    }
+    depth
+}
+
+// Changes from the console, VIA or the master. Saved to flash. The master sends debounce to the
+// slave.
+fn apply_matrix_command<TX: TxModule, RX: RxModule<AdcUnit = config::AdcUnit>>(
+    cmd: monitor::MatrixCommand,
+    scanner: &mut ECScanner<TX, RX, TX_SIZE, RX_SIZE>,
+    calibration: &mut calibration::MatrixCalibration,
+    split_side: SplitSide,
+) {
+    info!("Matrix command: {:?}", cmd);
+    let res = match cmd {
+        monitor::MatrixCommand::SetThreshold { tx, rx, value } => {
+            calibration.set_threshold(tx, rx, value);
+            scanner.set_thresholds(calibration.thresholds());
+            storage::with_settings(|s| calibration::save(s, split_side, calibration))
+        }
+        monitor::MatrixCommand::SetDebounce(debounce) => {
+            scanner.set_debounce(debounce);
+            let debounce = scanner.debounce();
+            comm::sync_config(ConfigSync::Debounce(debounce));
+            storage::with_settings(|s| s.store(storage::DEBOUNCE, &debounce))
+        }
+    };
+    if let Err(e) = res {
+        error!("Failed to save matrix settings: {:?}", Debug2Format(&e));
+    }
+}
+
+enum Bootmagic {
+    ClearSide,
+    Calibrate,
+}
+
+// Hold a bootmagic key while plugging in.
+// Side of this half is resolved before scanning, since matrix pins differ by side. A half
+// with the wrong side scans nothing, so storing the side is left to the console.
+async fn bootmagic<TX: TxModule, RX: RxModule>(
+    scanner: &mut ECScanner<TX, RX, TX_SIZE, RX_SIZE>,
+    split_side: SplitSide,
+) -> Option<Bootmagic> {
+    let (clear_key, calibrate_key) = match split_side {
+        SplitSide::Left => (
+            config::BOOTMAGIC_LEFT_CLEAR,
+            config::BOOTMAGIC_LEFT_CALIBRATE,
+        ),
+        SplitSide::Right => (
+            config::BOOTMAGIC_RIGHT_CLEAR,
+            config::BOOTMAGIC_RIGHT_CALIBRATE,
+        ),
+    };
+
+    let deadline = Instant::now() + config::BOOTMAGIC_SCAN_TIME;
+    while Instant::now() < deadline {
+        while let Some(e) = scanner.scan() {
+            match e {
+                Event::KeyPress(i, j) if (i, j) == clear_key => return Some(Bootmagic::ClearSide),
+                Event::KeyPress(i, j) if (i, j) == calibrate_key => {
+                    return Some(Bootmagic::Calibrate)
+                }
+                _ => {}
+            }
+        }
         Timer::after(config::SCAN_DELAY).await;
     }
+    None
 }
 
+// Master applies events of both halves to the layout through combos, tap dances and one-shots.
+// Slave forwards its own to master.
 #[embassy_executor::task]
-async fn master_event_handler(
+async fn event_router(
     receiver: event_channel::EventReceiver<'static>,
+    split_sender: event_channel::EventSender<'static>,
     layout: &'static layers::SharedLayout,
 ) {
-    info!("Start master_event_handler");
+    info!("Start event_router");
+    let mut role_changes = role::subscriber();
+    let mut stages = Chain(
+        Chain(
+            Combos::<MAX_COMBO_KEYS>::new(layers::COMBOS, config::COMBO_TIMEOUT.as_millis()),
+            TapDances::new(layers::TAP_DANCES),
+        ),
+        OneShots::new(layers::ONE_SHOTS),
+    );
     loop {
-        let event = receiver.recv().await;
+        let deadline = stages.deadline();
+        let timeout = async {
+            match deadline {
+                Some(ms) => Timer::at(Instant::from_millis(ms)).await,
+                None => core::future::pending().await,
+            }
+        };
+        let event = match select3(receiver.recv(), role_changes.next_message_pure(), timeout).await
+        {
+            Either3::First(event) => event,
+            Either3::Second(_) => {
+                // Keys held in the previous role are released on both halves.
+                keymap::reset(layout);
+                stages.reset();
+                continue;
+            }
+            Either3::Third(_) => {
+                apply_events(layout, &mut stages, None);
+                continue;
+            }
+        };
         debug!("Received Event: {:?}", defmt::Debug2Format(&event));
 
-        let key_event = match event.into_keyberon() {
-            Some(e) => e,
-            None => continue,
-        };
-        layout.lock(|l| {
-            l.borrow_mut().event(key_event);
-        });
+        if !role::is_master() {
+            split_sender.send(event).await;
+            continue;
+        }
+
+        apply_events(layout, &mut stages, Some(event));
     }
 }
 
-async fn slave_event_handler<T: usart::BasicInstance, DMA: usart::TxDma<T>>(
-    receiver: event_channel::EventReceiver<'static>,
-    uart_tx: &mut UartTx<'static, T, DMA>,
-) {
-    loop {
-        let event = receiver.recv().await;
-        debug!("Received Event: {:?}", defmt::Debug2Format(&event));
-
-        // send event to other halve.
-        debug!("Send event to other side");
-        if let Err(err) = comm::send(&event, uart_tx).await {
-            error!("Usart Send Error: {:?}", defmt::Debug2Format(&err));
+// Expired events of the stages go first, then the event.
+fn apply_events(layout: &layers::SharedLayout, stages: &mut impl Stage, event: Option<Event>) {
+    let now = Instant::now().as_millis();
+    layout.lock(|l| {
+        let mut l = l.borrow_mut();
+        // Stages look up the layout, which takes the events after them.
+        let mut events: Vec<Event, MAX_ROUTED_EVENTS> = Vec::new();
+        let mut out = |e: Event| {
+            if events.push(e).is_err() {
+                error!("Too many events at once.");
+            }
         };
-    }
+        let bindings = layers::LayoutBindings(&l);
+        stages.tick(now, &bindings, &mut out);
+        if let Some(event) = event {
+            stages.event(event, now, &bindings, &mut out);
+        }
+        for key_event in events.into_iter().filter_map(Event::into_keyberon) {
+            l.event(key_event);
+        }
+    });
 }
 
 //embassy not allowd generic task. Wrapping generic funtions.
 #[embassy_executor::task]
-async fn left_slave_event_task(
+async fn left_role_task(mut role_manager: role::RoleManager<peripherals::PC6>) {
+    info!("Start left_role_task");
+    role_manager.run().await;
+}
+
+#[embassy_executor::task]
+async fn right_role_task(mut role_manager: role::RoleManager<peripherals::PA0>) {
+    info!("Start right_role_task");
+    role_manager.run().await;
+}
+
+#[embassy_executor::task]
+async fn left_uart_write_task(
     receiver: event_channel::EventReceiver<'static>,
-    mut tx: UartTx<'static, peripherals::USART1, DMA2_CH1>,
+    mut comm_tx: comm::CommTx<'static, peripherals::USART1, DMA2_CH1>,
 ) {
-    info!("Start left_slave_event_task");
-    slave_event_handler(receiver, &mut tx).await;
+    info!("Start left_uart_write_task");
+    comm_tx.run(receiver).await;
 }
 
 #[embassy_executor::task]
-async fn right_slave_event_task(
+async fn right_uart_write_task(
     receiver: event_channel::EventReceiver<'static>,
-    mut tx: UartTx<'static, peripherals::USART3, DMA2_CH1>,
+    mut comm_tx: comm::CommTx<'static, peripherals::USART3, DMA2_CH1>,
 ) {
-    info!("Start right_slave_event_task");
-    slave_event_handler(receiver, &mut tx).await;
+    info!("Start right_uart_write_task");
+    comm_tx.run(receiver).await;
 }
 
 #[embassy_executor::task]
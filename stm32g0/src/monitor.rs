@@ -0,0 +1,121 @@
+use core::cell::{Cell, RefCell};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_time::Instant;
+
+use crate::config::{AdcUnit, MONITOR_TIMEOUT, RX_SIZE, TX_SIZE};
+use crate::SplitSide;
+
+// Matrix state of this half published by the scan task for the console.
+#[derive(Debug, Clone, Copy)]
+pub struct MatrixSnapshot {
+    pub side: Option<SplitSide>,
+    pub values: [[AdcUnit; RX_SIZE]; TX_SIZE],
+    pub thresholds: [[AdcUnit; RX_SIZE]; TX_SIZE],
+    pub debounce: u8,
+    // Full matrix scans and key events since boot.
+    pub scans: u32,
+    pub events: u32,
+    pub max_scan_us: u32,
+}
+
+impl MatrixSnapshot {
+    const fn new() -> Self {
+        Self {
+            side: None,
+            values: [[0; RX_SIZE]; TX_SIZE],
+            thresholds: [[0; RX_SIZE]; TX_SIZE],
+            debounce: 0,
+            scans: 0,
+            events: 0,
+            max_scan_us: 0,
+        }
+    }
+}
+
+// Values of the other half relayed over the split link.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteSnapshot {
+    pub values: [[AdcUnit; RX_SIZE]; TX_SIZE],
+    pub thresholds: [[AdcUnit; RX_SIZE]; TX_SIZE],
+    received_at: Option<Instant>,
+}
+
+// Changes applied and saved by the scan task.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixCommand {
+    SetThreshold {
+        tx: usize,
+        rx: usize,
+        value: AdcUnit,
+    },
+    SetDebounce(u8),
+}
+
+static SNAPSHOT: Mutex<CriticalSectionRawMutex, RefCell<MatrixSnapshot>> =
+    Mutex::new(RefCell::new(MatrixSnapshot::new()));
+static REMOTE: Mutex<CriticalSectionRawMutex, RefCell<RemoteSnapshot>> =
+    Mutex::new(RefCell::new(RemoteSnapshot {
+        values: [[0; RX_SIZE]; TX_SIZE],
+        thresholds: [[0; RX_SIZE]; TX_SIZE],
+        received_at: None,
+    }));
+// Raw values are relayed until then.
+static REQUESTED_UNTIL: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+static COMMANDS: Channel<CriticalSectionRawMutex, MatrixCommand, 4> = Channel::new();
+
+pub fn snapshot() -> MatrixSnapshot {
+    SNAPSHOT.lock(|s| *s.borrow())
+}
+
+pub fn update(f: impl FnOnce(&mut MatrixSnapshot)) {
+    SNAPSHOT.lock(|s| f(&mut s.borrow_mut()));
+}
+
+// Keep the slave sending raw values for a while. Called on every host request.
+pub fn request() {
+    REQUESTED_UNTIL.lock(|r| r.set(Some(Instant::now() + MONITOR_TIMEOUT)));
+}
+
+pub fn is_requested() -> bool {
+    REQUESTED_UNTIL.lock(|r| r.get().is_some_and(|until| Instant::now() < until))
+}
+
+// None if the other half has not sent values lately.
+pub fn remote() -> Option<RemoteSnapshot> {
+    let remote = REMOTE.lock(|r| *r.borrow());
+    match remote.received_at {
+        Some(at) if at.elapsed() < MONITOR_TIMEOUT => Some(remote),
+        _ => None,
+    }
+}
+
+pub fn update_remote(tx: usize, values: &[AdcUnit], thresholds: bool) {
+    REMOTE.lock(|r| {
+        let mut remote = r.borrow_mut();
+        let Some(row) = (match thresholds {
+            true => remote.thresholds.get_mut(tx),
+            false => remote.values.get_mut(tx),
+        }) else {
+            return;
+        };
+        for (dst, src) in row.iter_mut().zip(values) {
+            *dst = *src;
+        }
+        remote.received_at = Some(Instant::now());
+    });
+}
+
+pub async fn send_command(cmd: MatrixCommand) {
+    COMMANDS.send(cmd).await;
+}
+
+// False if the queue is full.
+pub fn try_send_command(cmd: MatrixCommand) -> bool {
+    COMMANDS.try_send(cmd).is_ok()
+}
+
+pub fn try_receive_command() -> Option<MatrixCommand> {
+    COMMANDS.try_receive().ok()
+}
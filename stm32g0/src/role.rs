@@ -0,0 +1,126 @@
+use core::cell::Cell;
+use defmt::*;
+use embassy_futures::select::{select, Either};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::Pin;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+
+use crate::config::VBUS_DEBOUNCE;
+use crate::SplitSide;
+
+// The master half owns USB and the layout. The slave sends key events to it.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Master,
+    Slave,
+}
+
+// Advertised by the other half with heartbeat.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteRole {
+    pub usb: bool,
+    pub master: bool,
+}
+
+#[derive(Clone, Copy)]
+struct LocalRole {
+    role: Role,
+    usb: bool,
+}
+
+const ROLE_SUBSCRIBERS: usize = 4;
+pub type RoleSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, Role, 1, ROLE_SUBSCRIBERS, 1>;
+
+static LOCAL: Mutex<CriticalSectionRawMutex, Cell<LocalRole>> = Mutex::new(Cell::new(LocalRole {
+    role: Role::Slave,
+    usb: false,
+}));
+static ROLE_CHANGES: PubSubChannel<CriticalSectionRawMutex, Role, 1, ROLE_SUBSCRIBERS, 1> =
+    PubSubChannel::new();
+// None if the link is down.
+static REMOTE: Signal<CriticalSectionRawMutex, Option<RemoteRole>> = Signal::new();
+
+pub fn get() -> Role {
+    LOCAL.lock(|l| l.get().role)
+}
+
+pub fn is_master() -> bool {
+    get() == Role::Master
+}
+
+pub fn usb_powered() -> bool {
+    LOCAL.lock(|l| l.get().usb)
+}
+
+pub fn subscriber() -> RoleSubscriber {
+    unwrap!(ROLE_CHANGES.subscriber())
+}
+
+pub fn remote_changed(remote: Option<RemoteRole>) {
+    REMOTE.signal(remote);
+}
+
+fn set(role: Role, usb: bool) {
+    let prev = LOCAL.lock(|l| l.replace(LocalRole { role, usb }));
+    if prev.role != role {
+        info!("Role changed: {:?} -> {:?}", prev.role, role);
+        ROLE_CHANGES.immediate_publisher().publish_immediate(role);
+    }
+}
+
+// Elect master from VBUS of both halves.
+pub struct RoleManager<T: Pin> {
+    vbus: ExtiInput<'static, T>,
+    side: SplitSide,
+    remote: Option<RemoteRole>,
+}
+
+impl<T: Pin> RoleManager<T> {
+    pub fn new(vbus: ExtiInput<'static, T>, side: SplitSide, usb_connected: bool) -> Self {
+        set(
+            match usb_connected {
+                true => Role::Master,
+                false => Role::Slave,
+            },
+            usb_connected,
+        );
+
+        Self {
+            vbus,
+            side,
+            remote: None,
+        }
+    }
+
+    pub async fn run(&mut self) {
+        info!("Start role manager.");
+        loop {
+            self.elect();
+            match select(self.vbus.wait_for_any_edge(), REMOTE.wait()).await {
+                Either::First(_) => Timer::after(VBUS_DEBOUNCE).await,
+                Either::Second(remote) => self.remote = remote,
+            }
+        }
+    }
+
+    fn elect(&self) {
+        let usb = self.vbus.is_high();
+        let role = match (usb, self.remote) {
+            (false, _) => Role::Slave,
+            (true, Some(remote)) if remote.master => {
+                // Both halves claimed master at once. Left one keeps it.
+                match (get(), &self.side) {
+                    (Role::Master, SplitSide::Left) => Role::Master,
+                    _ => Role::Slave,
+                }
+            }
+            (true, _) => Role::Master,
+        };
+
+        set(role, usb);
+    }
+}
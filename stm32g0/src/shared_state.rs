@@ -0,0 +1,57 @@
+use core::cell::Cell;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::signal::Signal;
+
+// State owned by the master and pushed to the slave over the split link.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedState {
+    pub layer: u8,
+    // host LED bitmap. Same as HID keyboard output report.
+    pub leds: u8,
+    pub suspended: bool,
+}
+
+pub const LED_CAPS_LOCK: u8 = 1 << 1;
+
+impl SharedState {
+    const fn new() -> Self {
+        Self {
+            layer: 0,
+            leds: 0,
+            suspended: false,
+        }
+    }
+
+    pub fn caps_lock(&self) -> bool {
+        self.leds & LED_CAPS_LOCK != 0
+    }
+}
+
+static STATE: Mutex<CriticalSectionRawMutex, Cell<SharedState>> =
+    Mutex::new(Cell::new(SharedState::new()));
+
+// Signaled with the new state on every change.
+pub static STATE_CHANGED: Signal<CriticalSectionRawMutex, SharedState> = Signal::new();
+
+pub fn get() -> SharedState {
+    STATE.lock(|s| s.get())
+}
+
+pub fn update(f: impl FnOnce(&mut SharedState)) {
+    let changed = STATE.lock(|s| {
+        let mut state = s.get();
+        f(&mut state);
+        match state != s.get() {
+            true => {
+                s.set(state);
+                Some(state)
+            }
+            false => None,
+        }
+    });
+
+    if let Some(state) = changed {
+        defmt::debug!("Shared state changed: {:?}", state);
+        STATE_CHANGED.signal(state);
+    }
+}
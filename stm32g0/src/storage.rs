@@ -0,0 +1,43 @@
+use core::cell::RefCell;
+use defmt::unwrap;
+use eck_rs::settings::{Key, Settings};
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embedded_storage::nor_flash::ReadNorFlash;
+
+pub type Stm32Flash = Flash<'static, Blocking>;
+pub type Stm32Settings = Settings<Stm32Flash>;
+
+// Settings region at the end of flash. Pages are rotated for wear leveling.
+const SETTINGS_PAGE_SIZE: u32 = 4096;
+const SETTINGS_PAGES: u32 = 4;
+
+// Setting keys. Bump the version when the format of a value changes.
+pub const IDENTITY: Key = Key::new(1, 1);
+pub const KEYMAP: Key = Key::new(2, 1);
+pub const CALIBRATION_LEFT: Key = Key::new(3, 1);
+pub const CALIBRATION_RIGHT: Key = Key::new(4, 1);
+pub const DEBOUNCE: Key = Key::new(5, 1);
+pub const MACROS: Key = Key::new(6, 1);
+
+static SETTINGS: Mutex<ThreadModeRawMutex, RefCell<Option<Stm32Settings>>> =
+    Mutex::new(RefCell::new(None));
+
+pub fn init(flash: Stm32Flash) {
+    let start = flash.capacity() as u32 - SETTINGS_PAGE_SIZE * SETTINGS_PAGES;
+    let settings = unwrap!(Settings::mount(
+        flash,
+        start,
+        SETTINGS_PAGE_SIZE,
+        SETTINGS_PAGES
+    ));
+    SETTINGS.lock(|s| s.replace(Some(settings)));
+}
+
+// Flash operations block the executor. Use for configuration only.
+pub fn with_settings<R>(f: impl FnOnce(&mut Stm32Settings) -> R) -> R {
+    SETTINGS.lock(|settings| {
+        let mut settings = settings.borrow_mut();
+        f(unwrap!(settings.as_mut(), "Settings are not initialized."))
+    })
+}
@@ -0,0 +1,231 @@
+// VIA raw HID protocol. https://www.caniusevia.com/docs/specification
+use defmt::*;
+use eck_rs::via::*;
+use embassy_time::Instant;
+
+use crate::config::{MACRO_BUFFER_SIZE, MACRO_COUNT, RX_SIZE, TX_SIZE};
+use crate::identity::{self, Identity};
+use crate::keymap;
+use crate::layers::{COLS, N_LAYERS, ROWS};
+use crate::macros;
+use crate::monitor::{self, MatrixCommand};
+use crate::storage;
+use crate::SplitSide;
+
+pub const VIA_REPORT_SIZE: usize = REPORT_SIZE;
+
+// Raw HID interface for VIA. Vendor page 0xFF60, usage 0x61.
+#[rustfmt::skip]
+pub const VIA_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x60, 0xFF,  // Usage Page (Vendor Defined 0xFF60)
+    0x09, 0x61,        // Usage (0x61)
+    0xA1, 0x01,        // Collection (Application)
+    0x09, 0x62,        //   Usage (Data In)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
+    0x75, 0x08,        //   Report Size (8)
+    0x81, 0x02,        //   Input (Data, Variable, Absolute)
+    0x09, 0x63,        //   Usage (Data Out)
+    0x15, 0x00,        //   Logical Minimum (0)
+    0x26, 0xFF, 0x00,  //   Logical Maximum (255)
+    0x95, 0x20,        //   Report Count (32)
+    0x75, 0x08,        //   Report Size (8)
+    0x91, 0x02,        //   Output (Data, Variable, Absolute)
+    0xC0,              // End Collection
+];
+
+// Buffer commands carry data after | command | offset(2) | size |
+const BUFFER_DATA_START: usize = 4;
+const FIRMWARE_VERSION_NUMBER: u32 = 1;
+
+// Handle a request in place. The report is sent back as response.
+pub fn handle(report: &mut [u8; VIA_REPORT_SIZE]) {
+    debug!("VIA request: {:?}", report);
+    match report[0] {
+        GET_PROTOCOL_VERSION => report[1..3].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes()),
+        GET_KEYBOARD_VALUE => match report[1] {
+            UPTIME => {
+                let uptime = Instant::now().as_millis() as u32;
+                report[2..6].copy_from_slice(&uptime.to_be_bytes());
+            }
+            LAYOUT_OPTIONS | SWITCH_MATRIX_STATE => report[2..].fill(0),
+            FIRMWARE_VERSION => {
+                report[2..6].copy_from_slice(&FIRMWARE_VERSION_NUMBER.to_be_bytes())
+            }
+            _ => report[0] = UNHANDLED,
+        },
+        // Single layout, nothing to set.
+        SET_KEYBOARD_VALUE => {}
+        DYNAMIC_KEYMAP_GET_KEYCODE => {
+            let (layer, row, col) = (report[1], report[2], report[3]);
+            let keycode = keymap::get_keycode(layer as usize, row as usize, col as usize);
+            report[4..6].copy_from_slice(&keycode.unwrap_or(0).to_be_bytes());
+        }
+        DYNAMIC_KEYMAP_SET_KEYCODE => {
+            let (layer, row, col) = (report[1], report[2], report[3]);
+            let keycode = u16::from_be_bytes([report[4], report[5]]);
+            keymap::set_keycode(layer as usize, row as usize, col as usize, keycode);
+        }
+        CUSTOM_GET_VALUE | CUSTOM_SET_VALUE if report[1] == CUSTOM_CHANNEL => custom_value(report),
+        // Custom values are saved when set.
+        CUSTOM_SAVE => {}
+        DYNAMIC_KEYMAP_RESET => keymap::edit(&keymap::factory()),
+        EEPROM_RESET => {
+            keymap::edit(&keymap::factory());
+            macros::edit(&macros::factory());
+        }
+        DYNAMIC_KEYMAP_MACRO_GET_COUNT => report[1] = MACRO_COUNT,
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER_SIZE => {
+            report[1..3].copy_from_slice(&(MACRO_BUFFER_SIZE as u16).to_be_bytes())
+        }
+        DYNAMIC_KEYMAP_MACRO_GET_BUFFER => {
+            let (offset, size) = buffer_range(report);
+            macros::read_buffer(offset, &mut report[BUFFER_DATA_START..][..size]);
+        }
+        DYNAMIC_KEYMAP_MACRO_SET_BUFFER => {
+            let (offset, size) = buffer_range(report);
+            macros::write_buffer(offset, &report[BUFFER_DATA_START..][..size]);
+        }
+        DYNAMIC_KEYMAP_MACRO_RESET => macros::edit(&macros::factory()),
+        DYNAMIC_KEYMAP_GET_LAYER_COUNT => report[1] = N_LAYERS as u8,
+        DYNAMIC_KEYMAP_GET_BUFFER => {
+            let (offset, size) = buffer_range(report);
+            keymap::read_buffer(offset, &mut report[BUFFER_DATA_START..][..size]);
+        }
+        DYNAMIC_KEYMAP_SET_BUFFER => {
+            let (offset, size) = buffer_range(report);
+            keymap::write_buffer(offset, &report[BUFFER_DATA_START..][..size]);
+        }
+        cmd => {
+            debug!("Unhandled VIA command: {:#04x}", cmd);
+            report[0] = UNHANDLED;
+        }
+    }
+}
+
+fn custom_value(report: &mut [u8; VIA_REPORT_SIZE]) {
+    let set = report[0] == CUSTOM_SET_VALUE;
+    let id = report[2];
+    let data = &mut report[CUSTOM_DATA_START..];
+    let handled = match id {
+        MATRIX_SIZE if !set => {
+            data[..5].copy_from_slice(&[
+                N_LAYERS as u8,
+                ROWS as u8,
+                COLS as u8,
+                TX_SIZE as u8,
+                RX_SIZE as u8,
+            ]);
+            true
+        }
+        THRESHOLD => {
+            let (tx, rx) = (data[0] as usize, data[1] as usize);
+            if tx >= TX_SIZE || rx >= RX_SIZE {
+                false
+            } else if set {
+                let value = u16::from_be_bytes([data[2], data[3]]);
+                monitor::try_send_command(MatrixCommand::SetThreshold { tx, rx, value })
+            } else {
+                let threshold = monitor::snapshot().thresholds[tx][rx];
+                data[2..4].copy_from_slice(&threshold.to_be_bytes());
+                true
+            }
+        }
+        DEBOUNCE if set => {
+            data[0] > 0 && monitor::try_send_command(MatrixCommand::SetDebounce(data[0]))
+        }
+        DEBOUNCE => {
+            data[0] = monitor::snapshot().debounce;
+            true
+        }
+        SIDE if set => set_side(data[0]),
+        SIDE => {
+            data[0] = match storage::with_settings(identity::load) {
+                Some(Identity {
+                    side: SplitSide::Left,
+                }) => SIDE_LEFT,
+                Some(Identity {
+                    side: SplitSide::Right,
+                }) => SIDE_RIGHT,
+                None => SIDE_PIN,
+            };
+            true
+        }
+        ANALOG if !set => analog(data),
+        _ => false,
+    };
+
+    if !handled {
+        debug!("Unhandled VIA custom value: {:#04x}", id);
+        report[0] = UNHANDLED;
+    }
+}
+
+// | side | then | value | threshold | of each rx of a tx line.
+fn analog(data: &mut [u8]) -> bool {
+    let (half, tx) = (data[0], data[1] as usize);
+    if tx >= TX_SIZE {
+        return false;
+    }
+    // Keep the other half sending values while the host polls.
+    monitor::request();
+
+    let local = monitor::snapshot();
+    let side = match (half, local.side) {
+        (ANALOG_LOCAL, side) => side,
+        (ANALOG_REMOTE, Some(SplitSide::Left)) => Some(SplitSide::Right),
+        (ANALOG_REMOTE, Some(SplitSide::Right)) => Some(SplitSide::Left),
+        (ANALOG_REMOTE, None) => None,
+        _ => return false,
+    };
+    let matrix = match half {
+        ANALOG_LOCAL => Some((local.values, local.thresholds)),
+        _ => monitor::remote().map(|r| (r.values, r.thresholds)),
+    };
+    let (Some(side), Some((values, thresholds))) = (side, matrix) else {
+        data[0] = ANALOG_NONE;
+        return true;
+    };
+
+    data[0] = match side {
+        SplitSide::Left => SIDE_LEFT,
+        SplitSide::Right => SIDE_RIGHT,
+    };
+    for (rx, chunk) in data[1..].chunks_exact_mut(4).take(RX_SIZE).enumerate() {
+        chunk[..2].copy_from_slice(&values[tx][rx].to_be_bytes());
+        chunk[2..].copy_from_slice(&thresholds[tx][rx].to_be_bytes());
+    }
+    true
+}
+
+fn set_side(side: u8) -> bool {
+    let res = storage::with_settings(|s| match side {
+        SIDE_LEFT => identity::store(
+            s,
+            Identity {
+                side: SplitSide::Left,
+            },
+        ),
+        SIDE_RIGHT => identity::store(
+            s,
+            Identity {
+                side: SplitSide::Right,
+            },
+        ),
+        _ => identity::clear(s),
+    });
+    match res {
+        Ok(()) => true,
+        Err(e) => {
+            error!("Failed to write identity: {:?}", Debug2Format(&e));
+            false
+        }
+    }
+}
+
+fn buffer_range(report: &[u8; VIA_REPORT_SIZE]) -> (usize, usize) {
+    let offset = u16::from_be_bytes([report[1], report[2]]) as usize;
+    let size = (report[3] as usize).min(VIA_REPORT_SIZE - BUFFER_DATA_START);
+    (offset, size)
+}